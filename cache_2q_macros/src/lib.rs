@@ -0,0 +1,170 @@
+//! Procedural macros for [`cache_2q`](https://docs.rs/cache_2q/)
+//!
+//! These are re-exported by `cache_2q` when its `macros` feature is enabled, and shouldn't be
+//! depended on directly.
+#![deny(missing_docs, missing_debug_implementations, unused_qualifications)]
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Error, Expr, FnArg, ItemFn, Pat, ReturnType};
+
+/// Memoizes a free function in a 2Q cache, keyed by its arguments.
+///
+/// See the documentation of `cache_2q::cached_2q` for details.
+#[proc_macro_attribute]
+pub fn cached_2q(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut size: Option<Expr> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("size") {
+            size = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported cached_2q argument, expected `size`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    let size = match size {
+        Some(size) => size,
+        None => {
+            return Error::new(Span::call_site(), "missing `size = ...` argument")
+                .to_compile_error()
+                .into()
+        }
+    };
+    match expand(size, function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(size: Expr, function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+
+    if let Some(asyncness) = sig.asyncness {
+        return Err(Error::new(
+            asyncness.span(),
+            "cached_2q can't memoize async functions",
+        ));
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "cached_2q can't memoize generic functions",
+        ));
+    }
+
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    for input in &sig.inputs {
+        match input {
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new(
+                    receiver.span(),
+                    "cached_2q can only memoize free functions",
+                ))
+            }
+            FnArg::Typed(typed) => match &*typed.pat {
+                Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                    names.push(pat.ident.clone());
+                    types.push((*typed.ty).clone());
+                }
+                pat => {
+                    return Err(Error::new(
+                        pat.span(),
+                        "cached_2q arguments must be plain identifiers",
+                    ))
+                }
+            },
+        }
+    }
+    let output = match &sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+
+    // the arguments are only cloned into the key outside of the memoized body, so they needn't
+    // be mutable there
+    let mut outer = sig.clone();
+    for input in &mut outer.inputs {
+        if let FnArg::Typed(typed) = input {
+            if let Pat::Ident(pat) = &mut *typed.pat {
+                pat.mutability = None;
+            }
+        }
+    }
+
+    let name = &sig.ident;
+    let inputs = &sig.inputs;
+    let sig_output = &sig.output;
+    let clear_doc = format!("Forgets every result memoized by `{}`.", name);
+    let stats_doc = format!(
+        "Returns the lookup statistics of the results memoized by `{}`.",
+        name
+    );
+    let mod_doc = format!("The cache of results memoized by `{}`.", name);
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis #outer {
+            fn __cached_2q_inner(#inputs) #sig_output #block
+
+            let key = (#(::std::clone::Clone::clone(&#names),)*);
+            let cached = #name::cache()
+                .lock()
+                .unwrap_or_else(::std::sync::PoisonError::into_inner)
+                .get(&key)
+                .cloned();
+            if let ::std::option::Option::Some(result) = cached {
+                return result;
+            }
+            // the lock isn't held while calling the function, so it may recurse
+            let result = __cached_2q_inner(#(#names),*);
+            #name::cache()
+                .lock()
+                .unwrap_or_else(::std::sync::PoisonError::into_inner)
+                .insert(key, ::std::clone::Clone::clone(&result));
+            result
+        }
+
+        #[doc = #mod_doc]
+        #[allow(non_snake_case)]
+        #vis mod #name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            pub(super) fn cache()
+                -> &'static ::std::sync::Mutex<::cache_2q::Cache<(#(#types,)*), #output>>
+            {
+                static CACHE: ::std::sync::OnceLock<
+                    ::std::sync::Mutex<::cache_2q::Cache<(#(#types,)*), #output>>,
+                > = ::std::sync::OnceLock::new();
+                CACHE.get_or_init(|| ::std::sync::Mutex::new(::cache_2q::Cache::new(#size)))
+            }
+
+            #[doc = #clear_doc]
+            pub fn clear() {
+                cache()
+                    .lock()
+                    .unwrap_or_else(::std::sync::PoisonError::into_inner)
+                    .clear();
+            }
+
+            #[doc = #stats_doc]
+            pub fn stats() -> ::cache_2q::Stats {
+                cache()
+                    .lock()
+                    .unwrap_or_else(::std::sync::PoisonError::into_inner)
+                    .stats()
+            }
+        }
+    })
+}
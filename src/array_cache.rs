@@ -0,0 +1,695 @@
+//! A 2Q cache stored inline, which never allocates
+//!
+//! [`ArrayCache`] is available without the `alloc` feature, for targets with no allocator.
+//!
+//! [`ArrayCache`]: struct.ArrayCache.html
+
+use core::borrow::Borrow;
+use core::{array, fmt, mem, slice};
+
+use Stats;
+
+/// A 2Q cache of at most `N` entries, stored inline in arrays so it never allocates.
+///
+/// An `ArrayCache` behaves like a [`Cache`] created with `Cache::new(N)`: a quarter of the
+/// entries, but at least one, are kept in recent, the rest in frequent, and the last `N / 2` keys
+/// evicted from recent are remembered in ghost. Entries can't be pinned or invalidated.
+///
+/// Entries are kept in order in a single array, so inserting an entry, or moving one to the
+/// front of frequent, shifts the entries in front of it. Like lookups, this takes time
+/// proportional to `N`.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::ArrayCache;
+///
+/// let mut cache: ArrayCache<&str, u32, 16> = ArrayCache::new();
+/// cache.insert("apple", 3);
+/// cache.insert("banana", 5);
+/// assert_eq!(cache.get("apple"), Some(&3));
+/// assert_eq!(cache.peek("cherry"), None);
+///
+/// *cache.entry("cherry").or_insert(0) += 1;
+/// assert_eq!(cache.len(), 3);
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+#[derive(Debug, Clone)]
+pub struct ArrayCache<K, V, const N: usize> {
+    /// The entries of recent, followed by the entries of frequent, each from newest to oldest.
+    /// Only the first `len` are `Some`.
+    entries: [Option<(K, V)>; N],
+    recent: usize,
+    len: usize,
+    /// The keys remembered in ghost, from newest to oldest. Only the first `ghost_len` are `Some`.
+    ghost: [Option<K>; N],
+    ghost_len: usize,
+    stats: Stats,
+}
+
+impl<K, V, const N: usize> ArrayCache<K, V, N> {
+    const MAX_RECENT: usize = if N / 4 > 1 { N / 4 } else { 1 };
+    const MAX_FREQUENT: usize = N.saturating_sub(Self::MAX_RECENT);
+    const MAX_GHOST: usize = N / 2;
+
+    /// Creates an empty cache.
+    ///
+    /// # Panics
+    /// panics if `N` is zero
+    pub fn new() -> Self {
+        assert!(N > 0);
+        ArrayCache {
+            entries: array::from_fn(|_| None),
+            recent: 0,
+            len: 0,
+            ghost: array::from_fn(|_| None),
+            ghost_len: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Returns the lookup statistics collected since the cache was created, or since
+    /// [`reset_stats`] was last called.
+    ///
+    /// [`reset_stats`]: struct.ArrayCache.html#method.reset_stats
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Resets the lookup statistics of the cache to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Returns the number of entries currenly in the cache.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Clears the cache, removing all key-value pairs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::ArrayCache;
+    ///
+    /// let mut cache: ArrayCache<u32, &str, 4> = ArrayCache::new();
+    /// cache.insert(1, "a");
+    /// cache.clear();
+    /// assert!(cache.is_empty());
+    /// ```
+    pub fn clear(&mut self) {
+        for slot in &mut self.entries[..self.len] {
+            *slot = None;
+        }
+        for slot in &mut self.ghost[..self.ghost_len] {
+            *slot = None;
+        }
+        self.recent = 0;
+        self.len = 0;
+        self.ghost_len = 0;
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    /// The iterator element type is `(&'a K, &'a V)`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::ArrayCache;
+    ///
+    /// let mut cache: ArrayCache<&str, u32, 8> = ArrayCache::new();
+    /// cache.insert("a", 1);
+    /// cache.insert("b", 2);
+    /// assert_eq!(cache.iter().count(), 2);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.entries[..self.len].iter(),
+        }
+    }
+
+    /// Returns the entry in the specified slot, which must be below `len`.
+    fn slot(&self, index: usize) -> &(K, V) {
+        self.entries[index].as_ref().unwrap()
+    }
+
+    /// Returns the entry in the specified slot mutably, which must be below `len`.
+    fn slot_mut(&mut self, index: usize) -> &mut (K, V) {
+        self.entries[index].as_mut().unwrap()
+    }
+
+    /// Returns the slot of the entry which would be evicted to make room for a new entry in
+    /// frequent, if `frequent` is true, or in recent otherwise, or None if there is already room.
+    fn victim(&self, frequent: bool) -> Option<usize> {
+        let oldest_recent = self.recent.checked_sub(1);
+        let oldest_frequent = if self.len > self.recent {
+            Some(self.len - 1)
+        } else {
+            None
+        };
+        let victim = if frequent && self.len - self.recent >= Self::MAX_FREQUENT {
+            oldest_frequent
+        } else if !frequent && self.recent >= Self::MAX_RECENT {
+            oldest_recent
+        } else {
+            None
+        };
+        victim.or_else(|| if self.len >= N { oldest_frequent.or(oldest_recent) } else { None })
+    }
+
+    /// Removes the entry in the specified slot. The key of an entry evicted from recent is then
+    /// remembered in ghost.
+    fn evict(&mut self, index: usize) {
+        let (key, _) = remove_at(&mut self.entries, self.len, index);
+        self.len -= 1;
+        if index < self.recent {
+            self.recent -= 1;
+            self.stats.recent_evictions += 1;
+            if self.ghost_len > 0 && self.ghost_len >= Self::MAX_GHOST {
+                self.ghost_len -= 1;
+                self.ghost[self.ghost_len] = None;
+                self.stats.ghost_evictions += 1;
+            }
+            insert_at(&mut self.ghost, self.ghost_len, 0, key);
+            self.ghost_len += 1;
+        } else {
+            self.stats.frequent_evictions += 1;
+        }
+    }
+}
+
+impl<K: Eq, V, const N: usize> ArrayCache<K, V, N> {
+    /// Returns true if the cache contains a value for the specified key.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but
+    /// Eq on the borrowed form *must* match those for
+    /// the key type.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.position(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key, without moving it in the
+    /// cache or counting a lookup.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.position(key).map(|i| &self.slot(i).1)
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// Like [`Cache::get`], a key found in frequent is moved to its front.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::ArrayCache;
+    ///
+    /// let mut cache: ArrayCache<u32, &str, 4> = ArrayCache::new();
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.get(&1), Some(&"a"));
+    /// assert_eq!(cache.get(&2), None);
+    /// assert_eq!(cache.stats().misses, 1);
+    /// ```
+    ///
+    /// [`Cache::get`]: struct.Cache.html#method.get
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        match self.lookup(key) {
+            Some(i) => Some(&self.slot(i).1),
+            None => None,
+        }
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    ///
+    /// Like [`Cache::get_mut`], a key found in frequent is moved to its front.
+    ///
+    /// [`Cache::get_mut`]: struct.Cache.html#method.get_mut
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        match self.lookup(key) {
+            Some(i) => Some(&mut self.slot_mut(i).1),
+            None => None,
+        }
+    }
+
+    /// Inserts a key-value pair into the cache.
+    ///
+    /// If the cache did not have this key present, None is returned. If it did, the value is
+    /// updated, and the old value is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::ArrayCache;
+    ///
+    /// let mut cache: ArrayCache<u32, &str, 4> = ArrayCache::new();
+    /// assert_eq!(cache.insert(37, "a"), None);
+    /// assert_eq!(cache.insert(37, "b"), Some("a"));
+    /// ```
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.touch_entry(key) {
+            Entry::Occupied(mut entry) => Some(entry.insert(value)),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Gets the given key's corresponding entry in the cache for in-place manipulation.
+    ///
+    /// Like [`Cache::entry`], the lookup is counted in the cache's [`Stats`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::ArrayCache;
+    ///
+    /// let mut counts: ArrayCache<char, u32, 32> = ArrayCache::new();
+    /// for c in "abracadabra".chars() {
+    ///     *counts.entry(c).or_insert(0) += 1;
+    /// }
+    /// assert_eq!(counts.peek(&'a'), Some(&5));
+    /// ```
+    ///
+    /// [`Cache::entry`]: struct.Cache.html#method.entry
+    /// [`Stats`]: struct.Stats.html
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, N> {
+        let mut entry = self.touch_entry(key);
+        match entry {
+            Entry::Occupied(ref mut entry) if entry.index < entry.cache.recent => {
+                entry.cache.stats.recent_hits += 1;
+            }
+            Entry::Occupied(ref mut entry) => {
+                entry.cache.stats.frequent_hits += 1;
+            }
+            Entry::Vacant(ref mut entry) => {
+                entry.cache.stats.misses += 1;
+            }
+        }
+        entry
+    }
+
+    /// Removes a key from the cache, returning the value associated with the key if the key
+    /// was previously in the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::ArrayCache;
+    ///
+    /// let mut cache: ArrayCache<u32, &str, 4> = ArrayCache::new();
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.remove(&1), Some("a"));
+    /// assert_eq!(cache.remove(&1), None);
+    /// ```
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.position(key).map(|i| self.remove_slot(i).1)
+    }
+
+    /// Returns the slot of the entry for the specified key.
+    fn position<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.entries[..self.len]
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(|entry| entry.0.borrow() == key))
+    }
+
+    /// Looks up the specified key, counting the lookup in the cache's stats, and moving an
+    /// entry found in frequent to its front. Returns the slot of the entry.
+    fn lookup<Q>(&mut self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        match self.position(key) {
+            Some(i) if i < self.recent => {
+                self.stats.recent_hits += 1;
+                Some(i)
+            }
+            Some(i) => {
+                self.stats.frequent_hits += 1;
+                self.entries[self.recent..=i].rotate_right(1);
+                Some(self.recent)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Like [`entry`], but without counting a lookup in the cache's stats.
+    ///
+    /// [`entry`]: struct.ArrayCache.html#method.entry
+    fn touch_entry(&mut self, key: K) -> Entry<'_, K, V, N> {
+        match self.position(&key) {
+            Some(i) => {
+                let index = if i < self.recent {
+                    i
+                } else {
+                    self.entries[self.recent..=i].rotate_right(1);
+                    self.recent
+                };
+                Entry::Occupied(OccupiedEntry { cache: self, index })
+            }
+            None => {
+                let ghost = self.ghost[..self.ghost_len]
+                    .iter()
+                    .position(|old_key| old_key.as_ref() == Some(&key));
+                Entry::Vacant(VacantEntry {
+                    cache: self,
+                    key,
+                    ghost,
+                })
+            }
+        }
+    }
+
+    /// Removes the entry in the specified slot, without remembering its key in ghost.
+    fn remove_slot(&mut self, index: usize) -> (K, V) {
+        let entry = remove_at(&mut self.entries, self.len, index);
+        self.len -= 1;
+        if index < self.recent {
+            self.recent -= 1;
+        }
+        entry
+    }
+}
+
+impl<K, V, const N: usize> Default for ArrayCache<K, V, N> {
+    /// Creates an empty cache.
+    ///
+    /// # Panics
+    /// panics if `N` is zero
+    fn default() -> Self {
+        ArrayCache::new()
+    }
+}
+
+impl<'a, K: 'a, V: 'a, const N: usize> IntoIterator for &'a ArrayCache<K, V, N> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// Inserts `item` at `index` among the first `len` slots, shifting the items after it back.
+fn insert_at<T>(slots: &mut [Option<T>], len: usize, index: usize, item: T) {
+    slots[len] = Some(item);
+    slots[index..=len].rotate_right(1);
+}
+
+/// Removes the item at `index` among the first `len` slots, shifting the items after it forward.
+fn remove_at<T>(slots: &mut [Option<T>], len: usize, index: usize) -> T {
+    let item = slots[index].take().unwrap();
+    slots[index..len].rotate_left(1);
+    item
+}
+
+/// A view into a single entry in an [`ArrayCache`], which may either be vacant or occupied.
+///
+/// This enum is constructed from the entry method on ArrayCache.
+///
+/// [`ArrayCache`]: struct.ArrayCache.html
+pub enum Entry<'a, K: 'a, V: 'a, const N: usize> {
+    /// An occupied entry
+    Occupied(OccupiedEntry<'a, K, V, N>),
+    /// A vacant entry
+    Vacant(VacantEntry<'a, K, V, N>),
+}
+
+impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug, const N: usize> fmt::Debug
+    for Entry<'a, K, V, N>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Entry::Vacant(ref v) => f.debug_tuple("Entry").field(v).finish(),
+            Entry::Occupied(ref o) => f.debug_tuple("Entry").field(o).finish(),
+        }
+    }
+}
+
+impl<'a, K: 'a + Eq, V: 'a, const N: usize> Entry<'a, K, V, N> {
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the default if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+}
+
+/// A view into an occupied entry in an [`ArrayCache`].
+/// It is part of the [`Entry`] enum.
+///
+/// [`ArrayCache`]: struct.ArrayCache.html
+/// [`Entry`]: enum.Entry.html
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, const N: usize> {
+    cache: &'a mut ArrayCache<K, V, N>,
+    index: usize,
+}
+
+impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug, const N: usize> fmt::Debug
+    for OccupiedEntry<'a, K, V, N>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OccupiedEntry")
+            .field("key", self.key())
+            .field("value", self.get())
+            .field(
+                "kind",
+                &if self.index < self.cache.recent {
+                    "recent"
+                } else {
+                    "frequent"
+                },
+            )
+            .finish()
+    }
+}
+
+impl<'a, K: 'a, V: 'a, const N: usize> OccupiedEntry<'a, K, V, N> {
+    /// Gets a reference to the key in the entry.
+    pub fn key(&self) -> &K {
+        &self.cache.slot(self.index).0
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        &self.cache.slot(self.index).1
+    }
+
+    /// Gets a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.cache.slot_mut(self.index).1
+    }
+
+    /// Converts the entry into a mutable reference to the value in the entry
+    /// with a lifetime bound to the cache itself.
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.cache.slot_mut(self.index).1
+    }
+
+    /// Sets the value of the entry, and returns the entry's old value.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+}
+
+impl<'a, K: 'a + Eq, V: 'a, const N: usize> OccupiedEntry<'a, K, V, N> {
+    /// Takes the ownership of the key and value from the cache.
+    pub fn remove_entry(self) -> (K, V) {
+        self.cache.remove_slot(self.index)
+    }
+
+    /// Takes the value out of the entry, and returns it.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+}
+
+/// A view into a vacant entry in an [`ArrayCache`].
+/// It is part of the [`Entry`] enum.
+///
+/// [`ArrayCache`]: struct.ArrayCache.html
+/// [`Entry`]: enum.Entry.html
+pub struct VacantEntry<'a, K: 'a, V: 'a, const N: usize> {
+    cache: &'a mut ArrayCache<K, V, N>,
+    key: K,
+    ghost: Option<usize>,
+}
+
+impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug, const N: usize> fmt::Debug
+    for VacantEntry<'a, K, V, N>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VacantEntry")
+            .field("key", self.key())
+            .field("remembered", &self.ghost.is_some())
+            .finish()
+    }
+}
+
+impl<'a, K: 'a, V: 'a, const N: usize> VacantEntry<'a, K, V, N> {
+    /// Gets a reference to the key that would be used when inserting a value
+    /// through the `VacantEntry`.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Take ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key,
+    /// and returns a mutable reference to it.
+    ///
+    /// The entry is inserted into frequent if its key was remembered in ghost, and into recent
+    /// otherwise, evicting the oldest entries to make room.
+    pub fn insert(self, value: V) -> &'a mut V {
+        let VacantEntry { cache, key, ghost } = self;
+        if let Some(i) = ghost {
+            remove_at(&mut cache.ghost, cache.ghost_len, i);
+            cache.ghost_len -= 1;
+            cache.stats.ghost_hits += 1;
+        }
+        let frequent = ghost.is_some();
+        while let Some(victim) = cache.victim(frequent) {
+            cache.evict(victim);
+        }
+        let index = if frequent {
+            cache.recent
+        } else {
+            cache.recent += 1;
+            0
+        };
+        insert_at(&mut cache.entries, cache.len, index, (key, value));
+        cache.len += 1;
+        &mut cache.slot_mut(index).1
+    }
+}
+
+/// An iterator over the entries of an [`ArrayCache`].
+///
+/// This `struct` is created by the [`iter`] method on [`ArrayCache`]. See its
+/// documentation for more.
+///
+/// [`iter`]: struct.ArrayCache.html#method.iter
+/// [`ArrayCache`]: struct.ArrayCache.html
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: slice::Iter<'a, Option<(K, V)>>,
+}
+
+impl<'a, K: 'a, V: 'a> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Self {
+        Iter {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for Iter<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.clone()).finish()
+    }
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next()
+            .and_then(Option::as_ref)
+            .map(|(key, value)| (key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::ArrayCache;
+    use Cache;
+
+    #[test]
+    fn matches_cache() {
+        let mut array: ArrayCache<u32, u32, 8> = ArrayCache::new();
+        let mut cache = Cache::new(8);
+        let mut state = 17u32;
+        for _ in 0..2000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let key = (state >> 16) % 24;
+            match state % 5 {
+                0 => assert_eq!(array.remove(&key), cache.remove(&key)),
+                1 | 2 => assert_eq!(array.get(&key), cache.get(&key)),
+                _ => assert_eq!(array.insert(key, state), cache.insert(key, state)),
+            }
+            assert_eq!(array.len(), cache.len());
+        }
+        assert_eq!(array.stats(), cache.stats());
+        assert!(array.iter().eq(cache.iter()));
+    }
+
+    #[test]
+    fn single_entry() {
+        let mut cache: ArrayCache<u32, u32, 1> = ArrayCache::new();
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(1, 3);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.peek(&1), Some(&3));
+        assert_eq!(cache.stats().ghost_hits, 1);
+    }
+}
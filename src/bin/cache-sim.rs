@@ -0,0 +1,507 @@
+//! Replays an access trace against caches of several sizes and configurations, and reports how
+//! well each of them did.
+//!
+//! ```text
+//! cache-sim [--format plain|arc|umass|lirs] [--sizes N,N,...] [--policy 2q,tinylfu] [--csv] TRACE
+//! cache-sim --mrc RATE [--format ...] [--sizes MIN..MAX/POINTS] [--csv] TRACE
+//! ```
+//!
+//! `TRACE` may be `-` to read the trace from stdin.
+
+extern crate cache_2q;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use cache_2q::mrc::{MissRatioCurve, Point};
+use cache_2q::{Cache, Entry, Stats, TinyLfuCache};
+
+const USAGE: &str = "\
+usage: cache-sim [options] TRACE
+
+Replays TRACE (or stdin, if TRACE is -) against caches of each size and policy.
+
+options:
+    --format FORMAT   trace format: plain (one key per line, the default), arc, umass or lirs
+    --sizes SIZES     comma separated list of cache sizes (default 100,1000,10000), or
+                      MIN..MAX/POINTS for POINTS sizes spaced logarithmically from MIN to MAX
+    --policy POLICIES comma separated list of policies: 2q, tinylfu (default 2q)
+    --mrc RATE        estimate the 2q miss ratio curve in a single pass, sampling RATE of the
+                      keys (between 0 and 1), instead of replaying the trace for every size;
+                      other policies can't be estimated this way
+    --csv             print results as CSV instead of a table
+";
+
+/// The most blocks a single line of an ARC or UMass trace may access, so that a malformed line
+/// can't make the simulator run out of memory
+const MAX_BLOCKS_PER_LINE: u64 = 1 << 20;
+/// UMass keys are the LBA in their low bits, and the ASU above them
+const UMASS_LBA_BITS: u32 = 48;
+
+/// The supported trace formats
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Format {
+    /// One key per line
+    Plain,
+    /// ARC traces: `start_block num_blocks ignored request_number`
+    Arc,
+    /// UMass/SPC traces: `ASU,LBA,size,opcode,timestamp`
+    Umass,
+    /// LIRS traces: one block number per line, with non-numeric lines ignored
+    Lirs,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "arc" => Ok(Format::Arc),
+            "umass" | "spc" => Ok(Format::Umass),
+            "lirs" => Ok(Format::Lirs),
+            _ => Err(format!("unknown trace format `{}`", s)),
+        }
+    }
+}
+
+/// The cache configurations which can be simulated
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Policy {
+    TwoQ,
+    TinyLfu,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "2q" => Ok(Policy::TwoQ),
+            "tinylfu" => Ok(Policy::TinyLfu),
+            _ => Err(format!("unknown policy `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            Policy::TwoQ => "2q",
+            Policy::TinyLfu => "tinylfu",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    format: Format,
+    sizes: Vec<usize>,
+    policies: Vec<Policy>,
+    mrc: Option<f64>,
+    csv: bool,
+    trace: String,
+}
+
+fn parse_list<T: FromStr>(s: &str) -> Result<Vec<T>, String>
+where
+    T::Err: fmt::Display,
+{
+    s.split(',')
+        .map(|item| item.trim().parse().map_err(|e| format!("invalid value `{}`: {}", item, e)))
+        .collect()
+}
+
+/// Parses either a list of sizes, or a `MIN..MAX/POINTS` logarithmically spaced range of sizes.
+fn parse_sizes(s: &str) -> Result<Vec<usize>, String> {
+    let range = s.find("..").and_then(|dots| {
+        let slash = s.find('/')?;
+        Some((&s[..dots], &s[dots + 2..slash], &s[slash + 1..]))
+    });
+    let (min, max, points) = match range {
+        Some((min, max, points)) => (min, max, points),
+        None => return parse_list(s),
+    };
+    let invalid = |_| format!("invalid size range `{}`", s);
+    let min: usize = min.trim().parse().map_err(invalid)?;
+    let max: usize = max.trim().parse().map_err(invalid)?;
+    let points: usize = points.trim().parse().map_err(invalid)?;
+    if min == 0 || max < min || points < 2 {
+        return Err(format!("invalid size range `{}`", s));
+    }
+
+    let step = (max as f64 / min as f64).ln() / (points - 1) as f64;
+    let mut sizes: Vec<usize> = (0..points)
+        .map(|i| (min as f64 * (step * i as f64).exp()).round() as usize)
+        .collect();
+    sizes.dedup();
+    Ok(sizes)
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut format = Format::Plain;
+    let mut sizes = vec![100, 1000, 10000];
+    let mut policies = vec![Policy::TwoQ];
+    let mut mrc = None;
+    let mut csv = false;
+    let mut trace = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} requires a value", name));
+        match arg.as_str() {
+            "--format" => format = value("--format")?.parse()?,
+            "--sizes" => sizes = parse_sizes(&value("--sizes")?)?,
+            "--policy" => policies = parse_list(&value("--policy")?)?,
+            "--mrc" => {
+                let rate = value("--mrc")?;
+                match rate.parse() {
+                    Ok(rate) if rate > 0.0 && rate <= 1.0 => mrc = Some(rate),
+                    _ => return Err(format!("invalid sample rate `{}`", rate)),
+                }
+            }
+            "--csv" => csv = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if trace.is_none() && (arg == "-" || !arg.starts_with('-')) => trace = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    if sizes.contains(&0) {
+        return Err("cache sizes must be greater than zero".to_string());
+    }
+    if mrc.is_some() && policies != [Policy::TwoQ] {
+        return Err("--mrc only estimates the 2q policy".to_string());
+    }
+
+    Ok(Options {
+        format,
+        sizes,
+        policies,
+        mrc,
+        csv,
+        trace: trace.ok_or_else(|| "missing trace".to_string())?,
+    })
+}
+
+/// Maps the keys in a trace to dense integers, so replaying doesn't need to compare strings.
+#[derive(Debug, Default)]
+struct Interner {
+    ids: HashMap<String, u64>,
+}
+
+impl Interner {
+    fn intern(&mut self, key: &str) -> u64 {
+        let next = self.ids.len() as u64;
+        *self.ids.entry(key.to_string()).or_insert(next)
+    }
+}
+
+/// Parses a single line of a trace, appending the keys it accesses to `keys`.
+fn parse_line(
+    format: Format,
+    line: &str,
+    interner: &mut Interner,
+    keys: &mut Vec<u64>,
+) -> Result<(), String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+    match format {
+        Format::Plain => keys.push(interner.intern(line)),
+        Format::Lirs => {
+            if let Ok(block) = line.parse() {
+                keys.push(block);
+            }
+        }
+        Format::Arc => {
+            let mut fields = line.split_whitespace().map(u64::from_str);
+            match (fields.next(), fields.next()) {
+                (Some(Ok(_)), Some(Ok(count))) if count > MAX_BLOCKS_PER_LINE => {
+                    return Err(format!("ARC trace line `{}` accesses too many blocks", line));
+                }
+                (Some(Ok(start)), Some(Ok(count))) => match start.checked_add(count) {
+                    Some(end) => keys.extend(start..end),
+                    None => return Err(format!("ARC trace line `{}` is out of range", line)),
+                },
+                _ => return Err(format!("invalid ARC trace line `{}`", line)),
+            }
+        }
+        Format::Umass => {
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            if fields.len() < 3 {
+                return Err(format!("invalid UMass trace line `{}`", line));
+            }
+            let parse = |field: &str| {
+                field
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid UMass trace line `{}`", line))
+            };
+            let (asu, lba, size) = (parse(fields[0])?, parse(fields[1])?, parse(fields[2])?);
+            // Sizes are in bytes, addresses are in 512 byte blocks
+            let blocks = cmp::max(1, size.div_ceil(512));
+            if blocks > MAX_BLOCKS_PER_LINE {
+                return Err(format!("UMass trace line `{}` accesses too many blocks", line));
+            }
+            let end = match lba.checked_add(blocks) {
+                Some(end) if asu >> (64 - UMASS_LBA_BITS) == 0 && end <= 1 << UMASS_LBA_BITS => end,
+                _ => return Err(format!("UMass trace line `{}` is out of range", line)),
+            };
+            keys.extend((lba..end).map(|block| (asu << UMASS_LBA_BITS) | block));
+        }
+    }
+    Ok(())
+}
+
+fn read_trace<R: BufRead>(format: Format, reader: R) -> Result<Vec<u64>, String> {
+    let mut interner = Interner::default();
+    let mut keys = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("error reading trace: {}", e))?;
+        parse_line(format, &line, &mut interner, &mut keys)
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
+    Ok(keys)
+}
+
+/// The outcome of replaying a trace against a single cache
+#[derive(Debug, Clone, PartialEq)]
+struct Report {
+    policy: Policy,
+    size: usize,
+    stats: Stats,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn ghost_hit_ratio(&self) -> f64 {
+        match self.stats.lookups() {
+            0 => 0.0,
+            lookups => self.stats.ghost_hits as f64 / lookups as f64,
+        }
+    }
+
+    fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.stats.lookups() as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+fn replay(policy: Policy, size: usize, keys: &[u64]) -> Report {
+    let start = Instant::now();
+    let stats = match policy {
+        Policy::TwoQ => {
+            let mut cache = Cache::new(size);
+            for &key in keys {
+                if let Entry::Vacant(entry) = cache.entry(key) {
+                    entry.insert(());
+                }
+            }
+            cache.stats()
+        }
+        Policy::TinyLfu => {
+            let mut cache = TinyLfuCache::new(size);
+            for &key in keys {
+                if cache.get(&key).is_none() {
+                    cache.insert(key, ());
+                }
+            }
+            cache.cache().stats()
+        }
+    };
+    Report {
+        policy,
+        size,
+        stats,
+        elapsed: start.elapsed(),
+    }
+}
+
+fn write_reports<W: Write>(mut out: W, reports: &[Report], csv: bool) -> io::Result<()> {
+    if csv {
+        writeln!(out, "policy,size,accesses,hit_ratio,ghost_hit_ratio,accesses_per_sec")?;
+        for report in reports {
+            writeln!(
+                out,
+                "{},{},{},{:.6},{:.6},{:.0}",
+                report.policy,
+                report.size,
+                report.stats.lookups(),
+                report.stats.hit_ratio(),
+                report.ghost_hit_ratio(),
+                report.throughput()
+            )?;
+        }
+    } else {
+        writeln!(
+            out,
+            "{:<8} {:>10} {:>12} {:>10} {:>10} {:>14}",
+            "policy", "size", "accesses", "hit %", "ghost %", "accesses/s"
+        )?;
+        for report in reports {
+            writeln!(
+                out,
+                "{:<8} {:>10} {:>12} {:>10.2} {:>10.2} {:>14.0}",
+                report.policy,
+                report.size,
+                report.stats.lookups(),
+                report.stats.hit_ratio() * 100.0,
+                report.ghost_hit_ratio() * 100.0,
+                report.throughput()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_points<W: Write>(mut out: W, points: &[Point], csv: bool) -> io::Result<()> {
+    if csv {
+        writeln!(out, "size,scaled_size,sampled_accesses,miss_ratio")?;
+        for point in points {
+            writeln!(
+                out,
+                "{},{},{},{:.6}",
+                point.size,
+                point.scaled_size,
+                point.accesses,
+                point.miss_ratio()
+            )?;
+        }
+    } else {
+        writeln!(out, "{:>10} {:>12} {:>12} {:>10}", "size", "scaled size", "sampled", "miss %")?;
+        for point in points {
+            writeln!(
+                out,
+                "{:>10} {:>12} {:>12} {:>10.2}",
+                point.size,
+                point.scaled_size,
+                point.accesses,
+                point.miss_ratio() * 100.0
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let keys = if options.trace == "-" {
+        let stdin = io::stdin();
+        let lock = stdin.lock();
+        read_trace(options.format, lock)?
+    } else {
+        let file = File::open(&options.trace)
+            .map_err(|e| format!("unable to open {}: {}", options.trace, e))?;
+        read_trace(options.format, BufReader::new(file))?
+    };
+
+    let stdout = io::stdout();
+    if let Some(rate) = options.mrc {
+        let mut curve = MissRatioCurve::new(&options.sizes, rate);
+        for &key in &keys {
+            curve.access(key);
+        }
+        return write_points(stdout.lock(), &curve.points(), options.csv).map_err(|e| e.to_string());
+    }
+
+    let mut reports = Vec::new();
+    for &policy in &options.policies {
+        for &size in &options.sizes {
+            reports.push(replay(policy, size, &keys));
+        }
+    }
+
+    write_reports(stdout.lock(), &reports, options.csv).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let result = parse_args(env::args().skip(1)).and_then(|options| run(&options));
+    if let Err(message) = result {
+        if !message.is_empty() {
+            eprintln!("error: {}\n", message);
+        }
+        eprint!("{}", USAGE);
+        process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, parse_sizes, read_trace, replay, Format, Policy};
+
+    fn args(s: &str) -> impl Iterator<Item = String> + '_ {
+        s.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn parse_formats() {
+        let plain = read_trace(Format::Plain, &b"a\nb\n\na\n"[..]).unwrap();
+        assert_eq!(plain, vec![0, 1, 0]);
+
+        let arc = read_trace(Format::Arc, &b"10 3 0 1\n4 1 0 2\n"[..]).unwrap();
+        assert_eq!(arc, vec![10, 11, 12, 4]);
+
+        let umass = read_trace(Format::Umass, &b"0,20,1024,r,0.1\n1,5,100,w,0.2\n"[..]).unwrap();
+        assert_eq!(umass, vec![20, 21, (1 << 48) | 5]);
+
+        let lirs = read_trace(Format::Lirs, &b"1\n2\n*\n1\n"[..]).unwrap();
+        assert_eq!(lirs, vec![1, 2, 1]);
+
+        assert!(read_trace(Format::Arc, &b"x y\n"[..]).is_err());
+        assert_eq!(
+            read_trace(Format::Arc, &b"1 1\n18446744073709551615 1\n"[..]),
+            Err("line 2: ARC trace line `18446744073709551615 1` is out of range".to_string())
+        );
+        assert_eq!(
+            read_trace(Format::Arc, &b"0 4000000000 0 0\n"[..]),
+            Err("line 1: ARC trace line `0 4000000000 0 0` accesses too many blocks".to_string())
+        );
+        assert_eq!(
+            read_trace(Format::Umass, &b"65536,0,512,r,0.1\n"[..]),
+            Err("line 1: UMass trace line `65536,0,512,r,0.1` is out of range".to_string())
+        );
+        assert!(read_trace(Format::Umass, &b"0,281474976710655,1024,r,0.1\n"[..]).is_err());
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = parse_args(args("--sizes 10,20 --policy 2q,tinylfu --csv trace.txt")).unwrap();
+        assert_eq!(options.sizes, vec![10, 20]);
+        assert_eq!(options.policies, vec![Policy::TwoQ, Policy::TinyLfu]);
+        assert!(options.csv);
+        assert_eq!(options.trace, "trace.txt");
+
+        assert!(parse_args(args("--sizes 0 trace.txt")).is_err());
+        assert!(parse_args(args("--csv")).is_err());
+        assert!(parse_args(args("--mrc 0.1 trace.txt")).is_ok());
+        assert!(parse_args(args("--mrc 0.1 --policy tinylfu trace.txt")).is_err());
+    }
+
+    #[test]
+    fn parse_size_ranges() {
+        assert_eq!(parse_sizes("10,20,30").unwrap(), vec![10, 20, 30]);
+        assert_eq!(parse_sizes("10..1000/3").unwrap(), vec![10, 100, 1000]);
+        assert_eq!(parse_sizes("1..2/4").unwrap(), vec![1, 2]);
+        assert!(parse_sizes("100..10/3").is_err());
+        assert!(parse_sizes("10..100/1").is_err());
+    }
+
+    #[test]
+    fn replay_counts_hits() {
+        let keys = [1, 2, 1, 2, 3, 1];
+        let report = replay(Policy::TwoQ, 8, &keys);
+        assert_eq!(report.stats.lookups(), 6);
+        assert_eq!(report.stats.hits(), 2);
+        // recent only holds 2 entries, so 1 was evicted to the ghost list by 3
+        assert_eq!(report.stats.ghost_hits, 1);
+    }
+}
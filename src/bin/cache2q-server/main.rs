@@ -0,0 +1,300 @@
+//! Serves a 2Q cache of byte strings over the memcached text protocol, or the Redis protocol,
+//! so programs in any language can share it.
+//!
+//! ```text
+//! cache2q-server [--protocol memcache|resp] [--listen ADDR | --unix PATH] [--size N]
+//!     [--max-bytes BYTES]
+//! ```
+
+extern crate cache_2q;
+
+mod memcache;
+mod resp;
+mod store;
+
+use std::env;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use store::Store;
+
+const USAGE: &str = "\
+usage: cache2q-server [options]
+
+Serves a 2Q cache over the memcached text protocol, or the Redis protocol.
+
+options:
+    --protocol PROTO  `memcache` (the default), or `resp` for the Redis protocol
+    --listen ADDR     TCP address to listen on (default 127.0.0.1:11211, or 127.0.0.1:6379
+                      for the Redis protocol)
+    --unix PATH       Unix socket to listen on, instead of TCP
+    --size N          maximum number of items (default 1000000)
+    --max-bytes BYTES maximum memory used by the cache, optionally suffixed with K, M or G
+                      (default 64M)
+";
+
+/// Where to listen for connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Listen {
+    Tcp(String),
+    Unix(String),
+}
+
+/// The protocol spoken to clients
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Protocol {
+    Memcache,
+    Resp,
+}
+
+#[derive(Debug)]
+struct Options {
+    protocol: Protocol,
+    listen: Listen,
+    size: usize,
+    max_bytes: usize,
+}
+
+/// Parses a number of bytes, optionally suffixed with K, M or G.
+fn parse_bytes(s: &str) -> Result<usize, String> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size `{}`", s))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut protocol = Protocol::Memcache;
+    let mut listen = None;
+    let mut size = 1_000_000;
+    let mut max_bytes = 64 << 20;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} requires a value", name));
+        match arg.as_str() {
+            "--protocol" => {
+                protocol = match value("--protocol")?.as_str() {
+                    "memcache" => Protocol::Memcache,
+                    "resp" => Protocol::Resp,
+                    other => return Err(format!("unknown protocol `{}`", other)),
+                }
+            }
+            "--listen" => listen = Some(Listen::Tcp(value("--listen")?)),
+            "--unix" => listen = Some(Listen::Unix(value("--unix")?)),
+            "--size" => {
+                let s = value("--size")?;
+                size = s.parse().map_err(|_| format!("invalid size `{}`", s))?;
+            }
+            "--max-bytes" => max_bytes = parse_bytes(&value("--max-bytes")?)?,
+            "-h" | "--help" => return Err(String::new()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    if size == 0 {
+        return Err("the cache size must be greater than zero".to_string());
+    }
+
+    let listen = listen.unwrap_or_else(|| {
+        Listen::Tcp(match protocol {
+            Protocol::Memcache => "127.0.0.1:11211".to_string(),
+            Protocol::Resp => "127.0.0.1:6379".to_string(),
+        })
+    });
+
+    Ok(Options {
+        protocol,
+        listen,
+        size,
+        max_bytes,
+    })
+}
+
+/// Serves a connection on its own thread.
+fn spawn_connection<S>(store: Arc<Store>, protocol: Protocol, reader: S, writer: S)
+where
+    S: Read + Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let _connection = store.connect();
+        let (reader, writer) = (BufReader::new(reader), BufWriter::new(writer));
+        // an error only ends this connection
+        let _ = match protocol {
+            Protocol::Memcache => memcache::serve(&store, reader, writer),
+            Protocol::Resp => resp::serve(&store, reader, writer),
+        };
+    });
+}
+
+/// Accepts connections on a TCP listener forever.
+fn serve_tcp(listener: TcpListener, store: Arc<Store>, protocol: Protocol) {
+    for stream in listener.incoming() {
+        let connection = stream.and_then(|stream| {
+            stream.set_nodelay(true)?;
+            Ok((stream.try_clone()?, stream))
+        });
+        match connection {
+            Ok((reader, writer)) => spawn_connection(store.clone(), protocol, reader, writer),
+            Err(e) => eprintln!("error: unable to accept connection: {}", e),
+        }
+    }
+}
+
+/// Accepts connections on a Unix socket forever.
+#[cfg(unix)]
+fn serve_unix(listener: UnixListener, store: Arc<Store>, protocol: Protocol) {
+    for stream in listener.incoming() {
+        match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+            Ok((reader, writer)) => spawn_connection(store.clone(), protocol, reader, writer),
+            Err(e) => eprintln!("error: unable to accept connection: {}", e),
+        }
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let store = Arc::new(Store::new(options.size, options.max_bytes));
+    match options.listen {
+        Listen::Tcp(ref addr) => {
+            let listener =
+                TcpListener::bind(addr).map_err(|e| format!("unable to listen on {}: {}", addr, e))?;
+            serve_tcp(listener, store, options.protocol);
+        }
+        #[cfg(unix)]
+        Listen::Unix(ref path) => {
+            let listener = UnixListener::bind(path)
+                .map_err(|e| format!("unable to listen on {}: {}", path, e))?;
+            serve_unix(listener, store, options.protocol);
+        }
+        #[cfg(not(unix))]
+        Listen::Unix(_) => return Err("Unix sockets are not supported on this platform".to_string()),
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = parse_args(env::args().skip(1)).and_then(|options| run(&options));
+    if let Err(message) = result {
+        if !message.is_empty() {
+            eprintln!("error: {}\n", message);
+        }
+        eprint!("{}", USAGE);
+        process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{parse_args, parse_bytes, serve_tcp, Listen, Protocol};
+    use store::Store;
+
+    fn args(s: &str) -> impl Iterator<Item = String> + '_ {
+        s.split_whitespace().map(String::from)
+    }
+
+    /// Sends `request`, and reads back lines until one equal to `last`.
+    fn exchange<S: Read + Write>(stream: &mut BufReader<S>, request: &str, last: &str) -> String {
+        stream.get_mut().write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        loop {
+            let mut line = String::new();
+            assert!(stream.read_line(&mut line).unwrap() > 0, "connection closed");
+            response.push_str(&line);
+            if line == last {
+                return response;
+            }
+        }
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = parse_args(args("--unix /tmp/sock --size 10 --max-bytes 2K")).unwrap();
+        assert_eq!(options.protocol, Protocol::Memcache);
+        assert_eq!(options.listen, Listen::Unix("/tmp/sock".to_string()));
+        assert_eq!(options.size, 10);
+        assert_eq!(options.max_bytes, 2048);
+
+        assert_eq!(parse_bytes("64M"), Ok(64 << 20));
+        assert!(parse_bytes("M").is_err());
+        assert!(parse_args(args("--size 0")).is_err());
+
+        let options = parse_args(args("--protocol resp")).unwrap();
+        assert_eq!(options.protocol, Protocol::Resp);
+        assert_eq!(options.listen, Listen::Tcp("127.0.0.1:6379".to_string()));
+        assert!(parse_args(args("--protocol http")).is_err());
+    }
+
+    #[test]
+    fn serves_tcp_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::new(Store::new(64, 1 << 20));
+        thread::spawn(move || serve_tcp(listener, store, Protocol::Memcache));
+
+        let mut first = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut second = BufReader::new(TcpStream::connect(addr).unwrap());
+        assert_eq!(exchange(&mut first, "set k 0 0 5\r\nhello\r\n", "STORED\r\n"), "STORED\r\n");
+        assert_eq!(
+            exchange(&mut second, "get k\r\n", "END\r\n"),
+            "VALUE k 0 5\r\nhello\r\nEND\r\n"
+        );
+        let stats = exchange(&mut second, "stats\r\n", "END\r\n");
+        assert!(stats.contains("STAT curr_connections 2\r\n"));
+        assert!(stats.contains("STAT get_hits 1\r\n"));
+    }
+
+    #[test]
+    fn serves_resp_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::new(Store::new(64, 1 << 20));
+        thread::spawn(move || serve_tcp(listener, store, Protocol::Resp));
+
+        let mut client = BufReader::new(TcpStream::connect(addr).unwrap());
+        assert_eq!(
+            exchange(&mut client, "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\nhi\r\n", "+OK\r\n"),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            exchange(&mut client, "*2\r\n$3\r\nGET\r\n$1\r\nk\r\n", "hi\r\n"),
+            "$2\r\nhi\r\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_unix_clients() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use std::{env, fs, process};
+
+        use super::serve_unix;
+
+        let path = env::temp_dir().join(format!("cache2q-server-test-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let store = Arc::new(Store::new(64, 1 << 20));
+        thread::spawn(move || serve_unix(listener, store, Protocol::Memcache));
+
+        let mut client = BufReader::new(UnixStream::connect(&path).unwrap());
+        assert_eq!(
+            exchange(&mut client, "set u 0 0 1\r\nx\r\nget u\r\n", "END\r\n"),
+            "STORED\r\nVALUE u 0 1\r\nx\r\nEND\r\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
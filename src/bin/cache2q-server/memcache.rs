@@ -0,0 +1,391 @@
+//! The memcached text protocol
+//!
+//! Supports `get`, `gets`, `set`, `add`, `replace`, `delete`, `incr`, `decr`, `flush_all`,
+//! `stats`, `version` and `quit`, as described in memcached's `doc/protocol.txt`.
+
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use store::{Item, Store, TooLarge};
+
+/// The largest value which can be stored, like memcached's default item size limit
+const MAX_ITEM_SIZE: usize = 1 << 20;
+/// The longest key memcached allows
+const MAX_KEY_LENGTH: usize = 250;
+/// The longest command line accepted, excluding data blocks
+const MAX_LINE_LENGTH: usize = 2048;
+/// Expiration times up to this many seconds are relative, longer ones are unix times
+const MAX_RELATIVE_EXPTIME: u64 = 60 * 60 * 24 * 30;
+
+/// Whether to keep serving a connection after a command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Quit,
+}
+
+/// Serves commands read from `reader` until it is closed, or the client quits.
+pub fn serve<R: BufRead, W: Write>(store: &Store, mut reader: R, mut writer: W) -> io::Result<()> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        (&mut reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") {
+            writer.write_all(b"CLIENT_ERROR line too long\r\n")?;
+            return writer.flush();
+        }
+        let words: Vec<&[u8]> = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|word| !word.is_empty())
+            .collect();
+        let flow = command(store, &words, &mut reader, &mut writer)?;
+        writer.flush()?;
+        if flow == Flow::Quit {
+            return Ok(());
+        }
+    }
+}
+
+fn command<R: BufRead, W: Write>(
+    store: &Store,
+    words: &[&[u8]],
+    reader: R,
+    mut out: W,
+) -> io::Result<Flow> {
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => {
+            out.write_all(b"ERROR\r\n")?;
+            return Ok(Flow::Continue);
+        }
+    };
+    match name {
+        b"get" => get(store, args, false, out)?,
+        b"gets" => get(store, args, true, out)?,
+        b"set" | b"add" | b"replace" => return storage(store, name, args, reader, out),
+        b"delete" => delete(store, args, out)?,
+        b"incr" => arithmetic(store, args, true, out)?,
+        b"decr" => arithmetic(store, args, false, out)?,
+        b"flush_all" => flush_all(store, args, out)?,
+        b"stats" if args.is_empty() => {
+            for (name, value) in store.stats() {
+                write!(out, "STAT {} {}\r\n", name, value)?;
+            }
+            out.write_all(b"END\r\n")?;
+        }
+        b"version" => write!(out, "VERSION {}\r\n", env!("CARGO_PKG_VERSION"))?,
+        b"quit" => return Ok(Flow::Quit),
+        _ => out.write_all(b"ERROR\r\n")?,
+    }
+    Ok(Flow::Continue)
+}
+
+/// `get <key>*` and `gets <key>*`
+///
+/// Each item is copied out of the store, so a client which is slow to read doesn't hold the
+/// lock shared by every connection.
+fn get<W: Write>(store: &Store, keys: &[&[u8]], with_cas: bool, mut out: W) -> io::Result<()> {
+    if keys.is_empty() {
+        return out.write_all(b"ERROR\r\n");
+    }
+    let now = Instant::now();
+    for &key in keys {
+        let item = {
+            let mut state = store.lock();
+            let item = state.get(key, now).cloned();
+            state.counters.cmd_get += 1;
+            if item.is_some() {
+                state.counters.get_hits += 1;
+            } else {
+                state.counters.get_misses += 1;
+            }
+            item
+        };
+        if let Some(item) = item {
+            out.write_all(b"VALUE ")?;
+            out.write_all(key)?;
+            write!(out, " {} {}", item.flags, item.data.len())?;
+            if with_cas {
+                write!(out, " {}", item.cas)?;
+            }
+            out.write_all(b"\r\n")?;
+            out.write_all(&item.data)?;
+            out.write_all(b"\r\n")?;
+        }
+    }
+    out.write_all(b"END\r\n")
+}
+
+/// `set`, `add` or `replace <key> <flags> <exptime> <bytes> [noreply]`, followed by a data block
+///
+/// The connection is closed after a data block too large to store, rather than reading it.
+fn storage<R: BufRead, W: Write>(
+    store: &Store,
+    name: &[u8],
+    args: &[&[u8]],
+    mut reader: R,
+    mut out: W,
+) -> io::Result<Flow> {
+    let (args, noreply) = split_noreply(args);
+    let (key, flags, exptime, bytes) = match *args {
+        [key, flags, exptime, bytes] => match (parse(flags), parse(exptime), parse(bytes)) {
+            (Some(flags), Some(exptime), Some(bytes)) => (key, flags, exptime, bytes),
+            _ => {
+                out.write_all(b"CLIENT_ERROR bad command line format\r\n")?;
+                return Ok(Flow::Continue);
+            }
+        },
+        _ => {
+            out.write_all(b"CLIENT_ERROR bad command line format\r\n")?;
+            return Ok(Flow::Continue);
+        }
+    };
+    let bytes: usize = bytes;
+    if bytes > MAX_ITEM_SIZE {
+        out.write_all(b"SERVER_ERROR object too large for cache\r\n")?;
+        return Ok(Flow::Quit);
+    }
+    let mut data = vec![0; bytes + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        out.write_all(b"CLIENT_ERROR bad data chunk\r\n")?;
+        return Ok(Flow::Continue);
+    }
+    data.truncate(bytes);
+    if !is_valid_key(key) {
+        out.write_all(b"CLIENT_ERROR bad command line format\r\n")?;
+        return Ok(Flow::Continue);
+    }
+
+    let now = Instant::now();
+    let item = Item {
+        data,
+        flags,
+        cas: 0,
+        expires: expires(exptime, now),
+    };
+    let mut state = store.lock();
+    state.counters.cmd_set += 1;
+    let exists = state.peek(key, now).is_some();
+    let message: &[u8] = match name {
+        b"add" if exists => b"NOT_STORED",
+        b"replace" if !exists => b"NOT_STORED",
+        _ => match state.set(key, item) {
+            Ok(_) => b"STORED",
+            Err(TooLarge) => b"SERVER_ERROR out of memory storing object",
+        },
+    };
+    reply(out, noreply, message)?;
+    Ok(Flow::Continue)
+}
+
+/// `delete <key> [noreply]`
+fn delete<W: Write>(store: &Store, args: &[&[u8]], out: W) -> io::Result<()> {
+    let (args, noreply) = split_noreply(args);
+    let key = match *args {
+        [key] => key,
+        _ => return reply(out, false, b"CLIENT_ERROR bad command line format"),
+    };
+    let mut state = store.lock();
+    let message: &[u8] = if state.remove(key, Instant::now()).is_some() {
+        state.counters.delete_hits += 1;
+        b"DELETED"
+    } else {
+        state.counters.delete_misses += 1;
+        b"NOT_FOUND"
+    };
+    reply(out, noreply, message)
+}
+
+/// `incr` or `decr <key> <value> [noreply]`
+///
+/// Incrementing wraps around at 2^64, and decrementing stops at 0.
+fn arithmetic<W: Write>(store: &Store, args: &[&[u8]], incr: bool, out: W) -> io::Result<()> {
+    let (args, noreply) = split_noreply(args);
+    let (key, delta) = match *args {
+        [key, delta] => match parse::<u64>(delta) {
+            Some(delta) => (key, delta),
+            None => return reply(out, false, b"CLIENT_ERROR invalid numeric delta argument"),
+        },
+        _ => return reply(out, false, b"CLIENT_ERROR bad command line format"),
+    };
+    let now = Instant::now();
+    let mut state = store.lock();
+    let current = state
+        .peek(key, now)
+        .map(|item| (parse::<u64>(&item.data), item.flags, item.expires));
+    let counters = &mut state.counters;
+    let (hits, misses) = if incr {
+        (&mut counters.incr_hits, &mut counters.incr_misses)
+    } else {
+        (&mut counters.decr_hits, &mut counters.decr_misses)
+    };
+    let (value, flags, expires) = match current {
+        None => {
+            *misses += 1;
+            return reply(out, noreply, b"NOT_FOUND");
+        }
+        Some((None, _, _)) => {
+            return reply(
+                out,
+                noreply,
+                b"CLIENT_ERROR cannot increment or decrement non-numeric value",
+            )
+        }
+        Some((Some(value), flags, expires)) => (value, flags, expires),
+    };
+    *hits += 1;
+    let value = if incr {
+        value.wrapping_add(delta)
+    } else {
+        value.saturating_sub(delta)
+    };
+    let item = Item {
+        flags,
+        expires,
+        ..Item::new(value.to_string().into_bytes())
+    };
+    match state.set(key, item) {
+        Ok(_) => reply(out, noreply, value.to_string().as_bytes()),
+        Err(TooLarge) => reply(out, noreply, b"SERVER_ERROR out of memory storing object"),
+    }
+}
+
+/// `flush_all [0] [noreply]`
+fn flush_all<W: Write>(store: &Store, args: &[&[u8]], out: W) -> io::Result<()> {
+    let (args, noreply) = split_noreply(args);
+    match *args {
+        [] | [b"0"] => {}
+        [_] => return reply(out, false, b"CLIENT_ERROR delayed flush_all is not supported"),
+        _ => return reply(out, false, b"CLIENT_ERROR bad command line format"),
+    }
+    let mut state = store.lock();
+    state.counters.cmd_flush += 1;
+    state.flush();
+    reply(out, noreply, b"OK")
+}
+
+/// Writes a single line reply, unless the client asked for no reply.
+fn reply<W: Write>(mut out: W, noreply: bool, message: &[u8]) -> io::Result<()> {
+    if noreply {
+        return Ok(());
+    }
+    out.write_all(message)?;
+    out.write_all(b"\r\n")
+}
+
+/// Splits a trailing `noreply` from the arguments of a command.
+fn split_noreply<'a, 'b>(args: &'a [&'b [u8]]) -> (&'a [&'b [u8]], bool) {
+    match args.split_last() {
+        Some((&b"noreply", rest)) => (rest, true),
+        _ => (args, false),
+    }
+}
+
+fn parse<T: str::FromStr>(word: &[u8]) -> Option<T> {
+    str::from_utf8(word).ok()?.parse().ok()
+}
+
+fn is_valid_key(key: &[u8]) -> bool {
+    key.len() <= MAX_KEY_LENGTH && !key.iter().any(u8::is_ascii_control)
+}
+
+/// Converts a memcached expiration time: 0 never expires, a number of seconds up to 30 days is
+/// relative to now, and anything longer is a unix time. Negative times have already expired.
+fn expires(exptime: i64, now: Instant) -> Option<Instant> {
+    let seconds = match exptime {
+        0 => return None,
+        _ if exptime < 0 => 0,
+        _ if exptime as u64 <= MAX_RELATIVE_EXPTIME => exptime as u64,
+        _ => {
+            let unix_now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            (exptime as u64).saturating_sub(unix_now)
+        }
+    };
+    Some(now + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve;
+    use store::Store;
+
+    /// Serves `input` on a single connection, and returns everything written back.
+    fn session(store: &Store, input: &str) -> String {
+        let mut output = Vec::new();
+        serve(store, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn storage_commands() {
+        let store = Store::new(64, 1 << 20);
+        assert_eq!(
+            session(&store, "set a 5 0 3\r\nabc\r\nget a b\r\n"),
+            "STORED\r\nVALUE a 5 3\r\nabc\r\nEND\r\n"
+        );
+        assert_eq!(
+            session(&store, "add a 0 0 1\r\nx\r\nadd b 0 0 1\r\ny\r\nreplace c 0 0 1\r\nz\r\n"),
+            "NOT_STORED\r\nSTORED\r\nNOT_STORED\r\n"
+        );
+        assert_eq!(
+            session(&store, "gets b\r\nset b 0 0 1 noreply\r\nz\r\ngets b\r\n"),
+            "VALUE b 0 1 2\r\ny\r\nEND\r\nVALUE b 0 1 3\r\nz\r\nEND\r\n"
+        );
+        assert_eq!(session(&store, "set c 0 -1 1\r\nx\r\nget c\r\n"), "STORED\r\nEND\r\n");
+        assert_eq!(
+            session(&store, "set d 0 0 2\r\nabc\r\nset e 0 0 2000000\r\n"),
+            "CLIENT_ERROR bad data chunk\r\nERROR\r\nSERVER_ERROR object too large for cache\r\n"
+        );
+        // the data block isn't read, and the connection is closed, even with noreply
+        assert_eq!(
+            session(&store, "set e 0 0 18446744073709551615 noreply\r\nabc\r\nget a\r\n"),
+            "SERVER_ERROR object too large for cache\r\n"
+        );
+        assert_eq!(
+            session(&store, "delete a\r\ndelete a\r\nflush_all\r\nget b\r\nquit\r\nget b\r\n"),
+            "DELETED\r\nNOT_FOUND\r\nOK\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn arithmetic_commands() {
+        let store = Store::new(64, 1 << 20);
+        assert_eq!(
+            session(
+                &store,
+                "set n 0 0 2\r\n10\r\nincr n 5\r\ndecr n 100\r\nincr m 1\r\nincr n x\r\n"
+            ),
+            "STORED\r\n15\r\n0\r\nNOT_FOUND\r\nCLIENT_ERROR invalid numeric delta argument\r\n"
+        );
+        assert_eq!(
+            session(&store, "set s 0 0 1\r\na\r\nincr s 1\r\n"),
+            "STORED\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+        assert_eq!(
+            session(&store, "set n 0 0 20\r\n18446744073709551615\r\nincr n 2\r\n"),
+            "STORED\r\n1\r\n"
+        );
+    }
+
+    #[test]
+    fn stats_report_ghost_hits() {
+        // recent holds a single entry, so setting b pushes a into ghost, and setting a again
+        // is a ghost hit
+        let store = Store::new(4, 1 << 20);
+        session(&store, "set a 0 0 1\r\n1\r\nset b 0 0 1\r\n2\r\nset a 0 0 1\r\n3\r\nget a\r\n");
+        let stats = session(&store, "stats\r\n");
+        assert!(stats.contains("STAT ghost_hits 1\r\n"));
+        assert!(stats.contains("STAT frequent_hits 1\r\n"));
+        assert!(stats.contains("STAT curr_items 2\r\n"));
+        assert!(stats.ends_with("END\r\n"));
+    }
+}
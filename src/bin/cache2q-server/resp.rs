@@ -0,0 +1,541 @@
+//! The Redis serialization protocol, RESP2 and RESP3
+//!
+//! Supports `GET`, `SET` with `EX`, `PX`, `NX` and `XX`, `DEL`, `EXISTS`, `MGET`, `MSET`, `TTL`,
+//! `PTTL`, `FLUSHALL`, `INFO`, `PING`, `ECHO`, `SELECT 0` and `QUIT`, and the `HELLO` and `CLIENT`
+//! commands Redis client libraries send when they connect. Connections start out speaking
+//! RESP2, and switch to RESP3 with `HELLO 3`.
+
+use std::cmp;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+use std::time::{Duration, Instant};
+
+use store::{Item, Store, TooLarge};
+
+/// The longest inline command, or header line, accepted
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+/// The largest bulk string accepted as an argument, if the store may use more memory
+const MAX_BULK_LENGTH: usize = 64 << 20;
+/// The most arguments accepted in a command
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+/// The most memory allocated for the arguments of a command, or for a bulk string, before any
+/// of it is read: buffers grow as data arrives, so a client can't make the server allocate
+/// more than it sends
+const PREALLOCATED: usize = 4096;
+
+/// A command read from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    Command(Vec<Vec<u8>>),
+    /// A request which doesn't follow the protocol, after which the connection is closed
+    Invalid(&'static str),
+    Closed,
+}
+
+/// Whether to keep serving a connection after a command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Quit,
+}
+
+/// Serves commands read from `reader` until it is closed, or the client quits.
+pub fn serve<R: BufRead, W: Write>(store: &Store, mut reader: R, writer: W) -> io::Result<()> {
+    let mut session = Session {
+        out: writer,
+        protocol: 2,
+    };
+    // an argument larger than the store can't be stored anyway
+    let max_bulk = cmp::min(MAX_BULK_LENGTH, store.max_bytes());
+    loop {
+        let flow = match read_request(&mut reader, max_bulk)? {
+            Request::Command(ref args) if args.is_empty() => Flow::Continue,
+            Request::Command(args) => command(store, &args, &mut session)?,
+            Request::Invalid(message) => {
+                session.error(&format!("ERR Protocol error: {}", message))?;
+                Flow::Quit
+            }
+            Request::Closed => return Ok(()),
+        };
+        session.out.flush()?;
+        if flow == Flow::Quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a command, either as an array of bulk strings of at most `max_bulk` bytes, or inline as
+/// words separated by spaces.
+fn read_request<R: BufRead>(reader: &mut R, max_bulk: usize) -> io::Result<Request> {
+    let line = match read_line(reader)? {
+        Some(Ok(line)) => line,
+        Some(Err(message)) => return Ok(Request::Invalid(message)),
+        None => return Ok(Request::Closed),
+    };
+    if !line.starts_with(b"*") {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Request::Command(args));
+    }
+
+    let count = match parse::<usize>(&line[1..]) {
+        Some(count) if count <= MAX_ARGUMENTS => count,
+        _ => return Ok(Request::Invalid("invalid multibulk length")),
+    };
+    let mut args = Vec::with_capacity(cmp::min(count, PREALLOCATED / 24));
+    for _ in 0..count {
+        let header = match read_line(reader)? {
+            Some(Ok(header)) => header,
+            Some(Err(message)) => return Ok(Request::Invalid(message)),
+            None => return Ok(Request::Closed),
+        };
+        let len = match header.split_first() {
+            Some((b'$', len)) => parse::<usize>(len),
+            _ => return Ok(Request::Invalid("expected '$'")),
+        };
+        let len = match len {
+            Some(len) if len <= max_bulk => len,
+            _ => return Ok(Request::Invalid("invalid bulk length")),
+        };
+        let mut arg = Vec::with_capacity(cmp::min(len + 2, PREALLOCATED));
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Ok(Request::Closed);
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Ok(Request::Invalid("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Request::Command(args))
+}
+
+/// Reads a line without its line ending, or `None` if the reader is closed.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Result<Vec<u8>, &'static str>>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Ok(Some(Err("too big request")));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(Ok(line)))
+}
+
+fn command<W: Write>(store: &Store, args: &[Vec<u8>], session: &mut Session<W>) -> io::Result<Flow> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let args: Vec<&[u8]> = args[1..].iter().map(Vec::as_slice).collect();
+    let arity_ok = match name.as_str() {
+        "get" | "ttl" | "pttl" | "echo" | "select" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "mget" | "client" => !args.is_empty(),
+        "mset" => !args.is_empty() && args.len().is_multiple_of(2),
+        "ping" => args.len() <= 1,
+        "flushall" => args.len() <= 1,
+        "quit" => args.is_empty(),
+        _ => true,
+    };
+    if !arity_ok {
+        session.error(&format!("ERR wrong number of arguments for '{}' command", name))?;
+        return Ok(Flow::Continue);
+    }
+
+    match name.as_str() {
+        "get" => get(store, args[0], session)?,
+        "set" => set(store, &args, session)?,
+        "del" => {
+            let now = Instant::now();
+            let mut state = store.lock();
+            let mut deleted = 0;
+            for key in args {
+                if state.remove(key, now).is_some() {
+                    state.counters.delete_hits += 1;
+                    deleted += 1;
+                } else {
+                    state.counters.delete_misses += 1;
+                }
+            }
+            session.integer(deleted)?;
+        }
+        "exists" => {
+            let now = Instant::now();
+            let mut state = store.lock();
+            let count = args
+                .iter()
+                .filter(|key| state.peek(key, now).is_some())
+                .count();
+            session.integer(count as i64)?;
+        }
+        "mget" => {
+            session.array(args.len())?;
+            for key in args {
+                get(store, key, session)?;
+            }
+        }
+        "mset" => {
+            let mut state = store.lock();
+            for pair in args.chunks(2) {
+                state.counters.cmd_set += 1;
+                if state.set(pair[0], Item::new(pair[1].to_vec())) == Err(TooLarge) {
+                    return session.out_of_memory().map(|()| Flow::Continue);
+                }
+            }
+            session.simple("OK")?;
+        }
+        "ttl" | "pttl" => {
+            let now = Instant::now();
+            let ttl = match store.lock().peek(args[0], now) {
+                None => -2,
+                Some(&Item { expires: None, .. }) => -1,
+                Some(&Item {
+                    expires: Some(expires),
+                    ..
+                }) => {
+                    let millis = expires.saturating_duration_since(now).as_millis() as i64;
+                    if name == "ttl" {
+                        (millis + 500) / 1000
+                    } else {
+                        millis
+                    }
+                }
+            };
+            session.integer(ttl)?;
+        }
+        "flushall" => match args.first().map(|mode| mode.to_ascii_lowercase()) {
+            None => flush_all(store, session)?,
+            Some(ref mode) if mode == b"sync" || mode == b"async" => flush_all(store, session)?,
+            Some(_) => session.error("ERR syntax error")?,
+        },
+        "info" => {
+            // every section is the same, so the section names are ignored
+            let mut info = String::from("# Stats\r\n");
+            for (name, value) in store.stats() {
+                info.push_str(&format!("{}:{}\r\n", name, value));
+            }
+            session.bulk(Some(info.as_bytes()))?;
+        }
+        "ping" => match args.first() {
+            Some(message) => session.bulk(Some(message))?,
+            None => session.simple("PONG")?,
+        },
+        "echo" => session.bulk(Some(args[0]))?,
+        "select" => match args[0] {
+            b"0" => session.simple("OK")?,
+            _ => session.error("ERR DB index is out of range")?,
+        },
+        "hello" => hello(&args, session)?,
+        "client" => match args[0].to_ascii_lowercase().as_slice() {
+            b"setname" | b"setinfo" => session.simple("OK")?,
+            _ => session.error("ERR unsupported CLIENT subcommand")?,
+        },
+        "quit" => {
+            session.simple("OK")?;
+            return Ok(Flow::Quit);
+        }
+        _ => session.error(&format!("ERR unknown command '{}'", name))?,
+    }
+    Ok(Flow::Continue)
+}
+
+/// `GET key`, also used for each key of `MGET`
+///
+/// The value is copied out of the store, so a client which is slow to read doesn't hold the lock
+/// shared by every connection.
+fn get<W: Write>(store: &Store, key: &[u8], session: &mut Session<W>) -> io::Result<()> {
+    let data = {
+        let mut state = store.lock();
+        state.counters.cmd_get += 1;
+        let data = state.get(key, Instant::now()).map(|item| item.data.clone());
+        if data.is_some() {
+            state.counters.get_hits += 1;
+        } else {
+            state.counters.get_misses += 1;
+        }
+        data
+    };
+    session.bulk(data.as_deref())
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+fn set<W: Write>(store: &Store, args: &[&[u8]], session: &mut Session<W>) -> io::Result<()> {
+    let now = Instant::now();
+    let mut expires = None;
+    let mut condition = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_lowercase();
+        match option.as_slice() {
+            b"ex" | b"px" if expires.is_none() => {
+                let ttl = match options.next().and_then(|ttl| parse::<u64>(ttl)) {
+                    Some(ttl) if ttl > 0 && option == b"ex" => Duration::from_secs(ttl),
+                    Some(ttl) if ttl > 0 => Duration::from_millis(ttl),
+                    _ => return session.error("ERR invalid expire time in 'set' command"),
+                };
+                match now.checked_add(ttl) {
+                    Some(time) => expires = Some(time),
+                    None => return session.error("ERR invalid expire time in 'set' command"),
+                }
+            }
+            b"nx" | b"xx" if condition.is_none() => condition = Some(option),
+            _ => return session.error("ERR syntax error"),
+        }
+    }
+
+    let mut state = store.lock();
+    state.counters.cmd_set += 1;
+    if let Some(condition) = condition {
+        let exists = state.peek(args[0], now).is_some();
+        if exists != (condition == b"xx") {
+            return session.bulk(None);
+        }
+    }
+    let item = Item {
+        expires,
+        ..Item::new(args[1].to_vec())
+    };
+    match state.set(args[0], item) {
+        Ok(_) => session.simple("OK"),
+        Err(TooLarge) => session.out_of_memory(),
+    }
+}
+
+/// `FLUSHALL [SYNC | ASYNC]`, which always flushes synchronously
+fn flush_all<W: Write>(store: &Store, session: &mut Session<W>) -> io::Result<()> {
+    let mut state = store.lock();
+    state.counters.cmd_flush += 1;
+    state.flush();
+    session.simple("OK")
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+///
+/// There is no authentication, so any credentials are accepted.
+fn hello<W: Write>(args: &[&[u8]], session: &mut Session<W>) -> io::Result<()> {
+    if let Some(version) = args.first() {
+        match parse::<u8>(version) {
+            Some(version @ 2) | Some(version @ 3) => session.protocol = version,
+            _ => return session.error("NOPROTO unsupported protocol version"),
+        }
+    }
+    let protocol = session.protocol;
+    session.map(6)?;
+    session.bulk(Some(b"server"))?;
+    session.bulk(Some(b"cache2q-server"))?;
+    session.bulk(Some(b"version"))?;
+    session.bulk(Some(env!("CARGO_PKG_VERSION").as_bytes()))?;
+    session.bulk(Some(b"proto"))?;
+    session.integer(i64::from(protocol))?;
+    session.bulk(Some(b"mode"))?;
+    session.bulk(Some(b"standalone"))?;
+    session.bulk(Some(b"role"))?;
+    session.bulk(Some(b"master"))?;
+    session.bulk(Some(b"modules"))?;
+    session.array(0)
+}
+
+/// A connection, which writes replies in the protocol version chosen by the client.
+#[derive(Debug)]
+struct Session<W> {
+    out: W,
+    protocol: u8,
+}
+
+impl<W: Write> Session<W> {
+    fn simple(&mut self, message: &str) -> io::Result<()> {
+        write!(self.out, "+{}\r\n", message)
+    }
+
+    fn error(&mut self, message: &str) -> io::Result<()> {
+        write!(self.out, "-{}\r\n", message)
+    }
+
+    fn out_of_memory(&mut self) -> io::Result<()> {
+        self.error("OOM value is larger than the cache")
+    }
+
+    fn integer(&mut self, value: i64) -> io::Result<()> {
+        write!(self.out, ":{}\r\n", value)
+    }
+
+    /// Writes a bulk string, or null.
+    fn bulk(&mut self, value: Option<&[u8]>) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                write!(self.out, "${}\r\n", value.len())?;
+                self.out.write_all(value)?;
+                self.out.write_all(b"\r\n")
+            }
+            None if self.protocol >= 3 => self.out.write_all(b"_\r\n"),
+            None => self.out.write_all(b"$-1\r\n"),
+        }
+    }
+
+    fn array(&mut self, len: usize) -> io::Result<()> {
+        write!(self.out, "*{}\r\n", len)
+    }
+
+    /// Starts a map of `len` pairs, which RESP2 writes as a flat array.
+    fn map(&mut self, len: usize) -> io::Result<()> {
+        if self.protocol >= 3 {
+            write!(self.out, "%{}\r\n", len)
+        } else {
+            self.array(len * 2)
+        }
+    }
+}
+
+fn parse<T: str::FromStr>(word: &[u8]) -> Option<T> {
+    str::from_utf8(word).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::serve;
+    use store::{Item, Store};
+
+    /// Serves `input` on a single connection, and returns everything written back.
+    fn session(store: &Store, input: &str) -> String {
+        let mut output = Vec::new();
+        serve(store, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    /// Encodes a command as an array of bulk strings.
+    fn command(args: &[&str]) -> String {
+        let mut encoded = format!("*{}\r\n", args.len());
+        for arg in args {
+            encoded.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        encoded
+    }
+
+    #[test]
+    fn string_commands() {
+        let store = Store::new(64, 1 << 20);
+        let input = [
+            command(&["SET", "a", "1"]),
+            command(&["set", "b", "two words"]),
+            command(&["GET", "b"]),
+            command(&["MGET", "a", "missing", "b"]),
+            command(&["MSET", "c", "3", "d", "4"]),
+            command(&["EXISTS", "a", "c", "missing", "a"]),
+            command(&["DEL", "a", "missing"]),
+            command(&["SET", "d", "5", "NX"]),
+            command(&["SET", "e", "5", "XX"]),
+            command(&["GET", "d"]),
+            command(&["FLUSHALL"]),
+            command(&["EXISTS", "b"]),
+        ]
+        .concat();
+        assert_eq!(
+            session(&store, &input),
+            "+OK\r\n+OK\r\n$9\r\ntwo words\r\n*3\r\n$1\r\n1\r\n$-1\r\n$9\r\ntwo words\r\n+OK\r\n\
+             :3\r\n:1\r\n$-1\r\n$-1\r\n$1\r\n4\r\n+OK\r\n:0\r\n"
+        );
+    }
+
+    #[test]
+    fn expiration() {
+        let store = Store::new(64, 1 << 20);
+        let input = [
+            command(&["SET", "a", "1", "EX", "100"]),
+            command(&["SET", "b", "1", "PX", "1500"]),
+            command(&["SET", "c", "1"]),
+            command(&["TTL", "a"]),
+            command(&["PTTL", "b"]),
+            command(&["TTL", "c"]),
+            command(&["TTL", "missing"]),
+            command(&["SET", "d", "1", "EX", "0"]),
+            command(&["SET", "d", "1", "EX"]),
+        ]
+        .concat();
+        let output = session(&store, &input);
+        let lines: Vec<&str> = output.split("\r\n").collect();
+        assert_eq!(&lines[..4], ["+OK", "+OK", "+OK", ":100"]);
+        let pttl: u64 = lines[4][1..].parse().unwrap();
+        assert!(pttl > 1000 && pttl <= 1500);
+        assert_eq!(
+            &lines[5..],
+            [
+                ":-1",
+                ":-2",
+                "-ERR invalid expire time in 'set' command",
+                "-ERR invalid expire time in 'set' command",
+                ""
+            ]
+        );
+
+        let mut state = store.lock();
+        let expired = Item {
+            expires: Some(Instant::now() - Duration::from_millis(1)),
+            ..Item::new(b"x".to_vec())
+        };
+        state.set(b"gone", expired).unwrap();
+        drop(state);
+        assert_eq!(session(&store, &command(&["TTL", "gone"])), ":-2\r\n");
+    }
+
+    #[test]
+    fn protocol_versions() {
+        let store = Store::new(64, 1 << 20);
+        let output = session(
+            &store,
+            &[
+                command(&["GET", "a"]),
+                command(&["HELLO", "3"]),
+                command(&["GET", "a"]),
+                command(&["HELLO", "4"]),
+            ]
+            .concat(),
+        );
+        assert!(output.starts_with("$-1\r\n%6\r\n$6\r\nserver\r\n$14\r\ncache2q-server\r\n"));
+        assert!(output.contains("$5\r\nproto\r\n:3\r\n"));
+        assert!(output.ends_with("*0\r\n_\r\n-NOPROTO unsupported protocol version\r\n"));
+    }
+
+    #[test]
+    fn inline_and_invalid_commands() {
+        let store = Store::new(64, 1 << 20);
+        assert_eq!(
+            session(&store, "PING\r\nset k v\r\nget k\r\nget\r\nnope\r\nQUIT\r\nPING\r\n"),
+            "+PONG\r\n+OK\r\n$1\r\nv\r\n-ERR wrong number of arguments for 'get' command\r\n\
+             -ERR unknown command 'nope'\r\n+OK\r\n"
+        );
+        assert_eq!(
+            session(&store, "*1\r\n+PING\r\nPING\r\n"),
+            "-ERR Protocol error: expected '$'\r\n"
+        );
+        let info = session(&store, "INFO\r\n");
+        assert!(info.contains("get_hits:1\r\n"));
+        assert!(info.contains("ghost_hits:0\r\n"));
+    }
+
+    #[test]
+    fn lengths_are_checked_before_allocating() {
+        let store = Store::new(64, 1 << 20);
+        assert_eq!(
+            session(&store, "*1\r\n$67108864\r\n"),
+            "-ERR Protocol error: invalid bulk length\r\n"
+        );
+        assert_eq!(
+            session(&store, "*1\r\n$2097152\r\nabc"),
+            "-ERR Protocol error: invalid bulk length\r\n"
+        );
+        // connections closed before sending what they announced are closed quietly
+        assert_eq!(session(&store, "*1048576\r\n$4\r\nPING\r\n"), "");
+        assert_eq!(session(&store, "*2\r\n$3\r\nGET\r\n$1000\r\nabc"), "");
+    }
+}
@@ -0,0 +1,267 @@
+//! The cache shared by every connection
+
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use cache_2q::{HeapSize, MemoryBoundedCache};
+
+/// A value stored in the cache, with the metadata kept alongside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub data: Vec<u8>,
+    /// Opaque flags stored by memcached clients
+    pub flags: u32,
+    /// A unique version of the item, which changes whenever it is stored
+    pub cas: u64,
+    /// When the item expires, if ever
+    pub expires: Option<Instant>,
+}
+
+impl Item {
+    /// Creates an item which never expires, and has no flags.
+    pub fn new(data: Vec<u8>) -> Self {
+        Item {
+            data,
+            flags: 0,
+            cas: 0,
+            expires: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl HeapSize for Item {
+    fn heap_size(&self) -> usize {
+        self.data.heap_size()
+    }
+}
+
+/// Counters of the commands served, reported by `stats`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Counters {
+    pub cmd_get: u64,
+    pub cmd_set: u64,
+    pub cmd_flush: u64,
+    pub get_hits: u64,
+    pub get_misses: u64,
+    pub get_expired: u64,
+    pub delete_hits: u64,
+    pub delete_misses: u64,
+    pub incr_hits: u64,
+    pub incr_misses: u64,
+    pub decr_hits: u64,
+    pub decr_misses: u64,
+}
+
+/// The error returned when an item doesn't fit in the cache on its own.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TooLarge;
+
+/// The contents of a [`Store`], which are locked by every command.
+#[derive(Debug)]
+pub struct State {
+    cache: MemoryBoundedCache<Vec<u8>, Item>,
+    next_cas: u64,
+    pub counters: Counters,
+}
+
+impl State {
+    /// Looks up a key, counting the lookup in the cache's stats. An expired item is removed.
+    pub fn get(&mut self, key: &[u8], now: Instant) -> Option<&Item> {
+        self.expire(key, now);
+        self.cache.get(key)
+    }
+
+    /// Looks up a key without counting the lookup or moving it in the cache. An expired item is
+    /// removed.
+    pub fn peek(&mut self, key: &[u8], now: Instant) -> Option<&Item> {
+        self.expire(key, now);
+        self.cache.peek(key)
+    }
+
+    /// Stores an item, giving it a new cas version, and returns that version.
+    ///
+    /// An item larger than the memory the cache may use is rejected without touching the cache,
+    /// rather than evicting every other item before being evicted itself.
+    pub fn set(&mut self, key: &[u8], mut item: Item) -> Result<u64, TooLarge> {
+        if key.len().saturating_add(item.heap_size()) > self.cache.max_bytes() {
+            return Err(TooLarge);
+        }
+        self.next_cas += 1;
+        item.cas = self.next_cas;
+        self.cache.insert(key.to_vec(), item);
+        if self.cache.contains_key(key) {
+            Ok(self.next_cas)
+        } else {
+            Err(TooLarge)
+        }
+    }
+
+    /// Removes a key, returning its item if it had one which hasn't expired.
+    pub fn remove(&mut self, key: &[u8], now: Instant) -> Option<Item> {
+        self.expire(key, now);
+        self.cache.remove(key)
+    }
+
+    /// Removes every item.
+    pub fn flush(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Returns the number of items in the cache, including expired items which haven't been
+    /// removed yet.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Removes the item for `key` if it has expired.
+    fn expire(&mut self, key: &[u8], now: Instant) {
+        if self.cache.peek(key).is_some_and(|item| item.is_expired(now)) {
+            self.cache.remove(key);
+            self.counters.get_expired += 1;
+        }
+    }
+}
+
+/// A 2Q cache of byte strings, weighted by the memory they use, shared by every connection.
+#[derive(Debug)]
+pub struct Store {
+    state: Mutex<State>,
+    size: usize,
+    started: Instant,
+    curr_connections: AtomicUsize,
+    total_connections: AtomicUsize,
+}
+
+impl Store {
+    /// Creates a store holding at most `size` items, in at most `max_bytes` bytes of memory.
+    pub fn new(size: usize, max_bytes: usize) -> Self {
+        Store {
+            state: Mutex::new(State {
+                cache: MemoryBoundedCache::new(size, max_bytes),
+                next_cas: 0,
+                counters: Counters::default(),
+            }),
+            size,
+            started: Instant::now(),
+            curr_connections: AtomicUsize::new(0),
+            total_connections: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of bytes of memory the cache may use.
+    pub fn max_bytes(&self) -> usize {
+        self.lock().cache.max_bytes()
+    }
+
+    /// Locks the contents of the store. A panic in another connection doesn't leave the cache
+    /// inconsistent, so a poisoned lock is ignored.
+    pub fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Counts a new connection, until the returned guard is dropped.
+    pub fn connect(&self) -> Connection<'_> {
+        self.curr_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        Connection { store: self }
+    }
+
+    /// Returns the statistics of the store as name and value pairs, in the order `stats` reports
+    /// them.
+    pub fn stats(&self) -> Vec<(&'static str, String)> {
+        let state = self.lock();
+        let counters = state.counters;
+        let cache = state.cache.stats();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        vec![
+            ("pid", process::id().to_string()),
+            ("uptime", self.started.elapsed().as_secs().to_string()),
+            ("time", time.to_string()),
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("pointer_size", usize::BITS.to_string()),
+            ("curr_connections", self.curr_connections.load(Ordering::Relaxed).to_string()),
+            ("total_connections", self.total_connections.load(Ordering::Relaxed).to_string()),
+            ("cmd_get", counters.cmd_get.to_string()),
+            ("cmd_set", counters.cmd_set.to_string()),
+            ("cmd_flush", counters.cmd_flush.to_string()),
+            ("get_hits", counters.get_hits.to_string()),
+            ("get_misses", counters.get_misses.to_string()),
+            ("get_expired", counters.get_expired.to_string()),
+            ("delete_hits", counters.delete_hits.to_string()),
+            ("delete_misses", counters.delete_misses.to_string()),
+            ("incr_hits", counters.incr_hits.to_string()),
+            ("incr_misses", counters.incr_misses.to_string()),
+            ("decr_hits", counters.decr_hits.to_string()),
+            ("decr_misses", counters.decr_misses.to_string()),
+            ("curr_items", state.len().to_string()),
+            ("bytes", state.cache.memory_usage().to_string()),
+            ("limit_maxbytes", state.cache.max_bytes().to_string()),
+            ("limit_maxitems", self.size.to_string()),
+            ("recent_hits", cache.recent_hits.to_string()),
+            ("frequent_hits", cache.frequent_hits.to_string()),
+            ("ghost_hits", cache.ghost_hits.to_string()),
+        ]
+    }
+}
+
+/// A connection counted by [`Store::connect`].
+#[derive(Debug)]
+pub struct Connection<'a> {
+    store: &'a Store,
+}
+
+impl<'a> Drop for Connection<'a> {
+    fn drop(&mut self) {
+        self.store.curr_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Item, Store, TooLarge};
+
+    #[test]
+    fn expired_items_are_removed() {
+        let store = Store::new(16, 1 << 20);
+        let mut state = store.lock();
+        let now = Instant::now();
+        let item = Item {
+            expires: Some(now + Duration::from_secs(10)),
+            ..Item::new(b"v".to_vec())
+        };
+        assert_eq!(state.set(b"k", item), Ok(1));
+        assert!(state.get(b"k", now).is_some());
+        assert!(state.get(b"k", now + Duration::from_secs(10)).is_none());
+        assert_eq!(state.len(), 0);
+        assert_eq!(state.counters.get_expired, 1);
+
+        assert_eq!(state.set(b"big", Item::new(vec![0; 2 << 20])), Err(TooLarge));
+    }
+
+    #[test]
+    fn rejected_items_leave_the_cache_alone() {
+        let store = Store::new(16, 64 << 10);
+        let mut state = store.lock();
+        let now = Instant::now();
+        // recent holds 4 of the 16 items
+        for key in 0..4u8 {
+            assert!(state.set(&[key], Item::new(vec![key; 1024])).is_ok());
+        }
+        assert_eq!(state.set(b"big", Item::new(vec![0; 64 << 10])), Err(TooLarge));
+        assert_eq!(state.set(&[0], Item::new(vec![0; 64 << 10])), Err(TooLarge));
+        assert_eq!(state.len(), 4);
+        for key in 0..4u8 {
+            assert_eq!(state.peek(&[key], now).map(|item| item.data[0]), Some(key));
+        }
+    }
+}
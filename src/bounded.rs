@@ -0,0 +1,264 @@
+//! A cache bounded by the memory it uses
+
+use alloc::collections::VecDeque;
+use core::borrow::Borrow;
+
+use {Cache, Entry, HeapSize, Segment, Stats, VacantKind};
+
+/// A 2Q cache which evicts entries to keep the memory it uses under a budget, as well as its
+/// number of entries under its size.
+///
+/// The memory used is accounted for as entries are inserted and evicted, and includes the cache
+/// itself, the capacity of its lists, and the heap memory owned by its keys, values and the keys
+/// remembered in ghost, as reported by [`HeapSize`]. When an insertion takes the cache over its
+/// budget, entries are evicted as they would be from a full cache, and then ghost keys are
+/// forgotten, oldest first, until it is back under budget.
+///
+/// Because values are only measured when they are inserted, there is no way to mutate a value in
+/// place.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::MemoryBoundedCache;
+///
+/// let mut cache = MemoryBoundedCache::new(1024, 64 * 1024);
+/// for i in 0..100 {
+///     cache.insert(i, vec![0u8; 4096]);
+///     assert!(cache.memory_usage() <= 64 * 1024);
+/// }
+/// assert!(cache.len() < 16);
+/// ```
+///
+/// [`HeapSize`]: trait.HeapSize.html
+#[derive(Debug, Clone)]
+pub struct MemoryBoundedCache<K, V> {
+    cache: Cache<K, V>,
+    ghost_sizes: VecDeque<usize>,
+    heap_bytes: usize,
+    max_bytes: usize,
+}
+
+impl<K: Eq + HeapSize, V: HeapSize> MemoryBoundedCache<K, V> {
+    /// Creates an empty cache with the specified size, which keeps its memory usage at or under
+    /// `max_bytes`.
+    ///
+    /// The lists of the cache grow as entries are inserted, and their capacity counts against the
+    /// budget, so growing them can evict entries. Once nothing is left to evict, their spare
+    /// capacity is released.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize, max_bytes: usize) -> Self {
+        let cache = Cache::new(size);
+        MemoryBoundedCache {
+            ghost_sizes: VecDeque::new(),
+            cache,
+            heap_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Returns a reference to the underlying cache.
+    pub fn cache(&self) -> &Cache<K, V> {
+        &self.cache
+    }
+
+    /// Returns the lookup statistics of the cache.
+    pub fn stats(&self) -> Stats {
+        self.cache.stats()
+    }
+
+    /// Returns the number of bytes of memory the cache may use.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Changes the number of bytes of memory the cache may use, evicting entries if it is now
+    /// over budget.
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.shrink_to_budget();
+    }
+
+    /// Returns the number of bytes of memory used by the cache.
+    ///
+    /// Unlike [`Cache::memory_usage`], this takes constant time.
+    ///
+    /// [`Cache::memory_usage`]: struct.Cache.html#method.memory_usage
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.cache.list_capacity_bytes()
+            + self.ghost_sizes.capacity() * size_of::<usize>()
+            + self.heap_bytes
+    }
+
+    /// Returns true if the cache contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.contains_key(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key, without moving it in the
+    /// cache.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.peek(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.get(key)
+    }
+
+    /// Inserts a key-value pair into the cache, then evicts entries until the cache is under
+    /// budget. This may evict the new entry itself, if it doesn't fit in the budget on its own.
+    ///
+    /// If the cache had this key present, the value is updated, and the old value is returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let value_bytes = value.heap_size();
+        let max_ghost = self.cache.max_ghost;
+        let old = match self.cache.touch_entry(key) {
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(value);
+                self.heap_bytes = self.heap_bytes + value_bytes - old.heap_size();
+                Some(old)
+            }
+            Entry::Vacant(entry) => {
+                let (heap_bytes, ghost_sizes) = (&mut self.heap_bytes, &mut self.ghost_sizes);
+                if let VacantKind::Ghost(i) = entry.kind {
+                    *heap_bytes -= ghost_sizes.remove(i).unwrap();
+                }
+                *heap_bytes += entry.key.heap_size() + value_bytes;
+                entry.insert_entry_with(value, |segment, key, value| {
+                    evicted(heap_bytes, ghost_sizes, max_ghost, segment, key, value)
+                });
+                None
+            }
+        };
+        self.shrink_to_budget();
+        old
+    }
+
+    /// Removes a key from the cache, returning the value associated with the key if the key
+    /// was previously in the cache.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let key_bytes = self.cache
+            .iter()
+            .find(|&(k, _)| k.borrow() == key)
+            .map(|(k, _)| k.heap_size())?;
+        let value = self.cache.remove(key)?;
+        self.heap_bytes -= key_bytes + value.heap_size();
+        Some(value)
+    }
+
+    /// Returns the number of entries currenly in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Clears the cache, removing all key-value pairs.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.ghost_sizes.clear();
+        self.heap_bytes = 0;
+    }
+
+    /// Evicts entries, then forgets ghost keys, until the cache is under budget.
+    fn shrink_to_budget(&mut self) {
+        let max_ghost = self.cache.max_ghost;
+        while self.memory_usage() > self.max_bytes {
+            if let Some(victim) = self.cache.oldest() {
+                let (heap_bytes, ghost_sizes) = (&mut self.heap_bytes, &mut self.ghost_sizes);
+                self.cache.evict(victim, &mut |segment, key: &K, value| {
+                    evicted(heap_bytes, ghost_sizes, max_ghost, segment, key, value)
+                });
+            } else if let Some(bytes) = self.ghost_sizes.pop_back() {
+                self.cache.ghost.pop_back();
+                self.cache.stats.ghost_evictions += 1;
+                self.heap_bytes -= bytes;
+            } else {
+                self.cache.shrink_to_fit();
+                self.ghost_sizes.shrink_to_fit();
+                break;
+            }
+        }
+    }
+}
+
+/// Accounts for an entry evicted from the cache, whose key is remembered in ghost if it was
+/// evicted from recent, the same way the cache itself does.
+fn evicted<K: HeapSize, V: HeapSize>(
+    heap_bytes: &mut usize,
+    ghost_sizes: &mut VecDeque<usize>,
+    max_ghost: usize,
+    segment: Segment,
+    key: &K,
+    value: V,
+) {
+    let key_bytes = key.heap_size();
+    *heap_bytes -= key_bytes + value.heap_size();
+    if segment == Segment::Recent {
+        if ghost_sizes.len() + 1 > max_ghost {
+            *heap_bytes -= ghost_sizes.pop_back().unwrap_or(0);
+        }
+        ghost_sizes.push_front(key_bytes);
+        *heap_bytes += key_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBoundedCache;
+
+    /// Checks the tracked memory usage against a recount of the whole cache
+    fn check(cache: &MemoryBoundedCache<String, String>) {
+        let tracking = size_of_val(cache) - size_of_val(&cache.cache)
+            + cache.ghost_sizes.capacity() * size_of::<usize>();
+        assert_eq!(cache.memory_usage(), cache.cache.memory_usage() + tracking);
+        assert_eq!(cache.ghost_sizes.len(), cache.cache.ghost.len());
+        assert!(cache.memory_usage() <= cache.max_bytes());
+    }
+
+    #[test]
+    fn tracks_memory_usage() {
+        let mut cache = MemoryBoundedCache::new(16, 4096);
+        for i in 0..200 {
+            let key = "k".repeat(i % 23);
+            cache.insert(key.clone(), "v".repeat(i * 7 % 300));
+            check(&cache);
+            if i % 5 == 0 {
+                cache.get(&key);
+            }
+            if i % 11 == 0 {
+                cache.remove(&"k".repeat(i % 7));
+                check(&cache);
+            }
+        }
+        // the cache itself doesn't fit, so everything is evicted and released
+        cache.set_max_bytes(0);
+        assert_eq!(cache.memory_usage(), size_of_val(&cache));
+        assert!(cache.is_empty());
+        assert!(cache.cache.ghost.is_empty());
+        assert_eq!(cache.heap_bytes, 0);
+    }
+}
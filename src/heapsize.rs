@@ -0,0 +1,282 @@
+//! Accounting of the heap memory owned by values
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "std")]
+use std::ffi::OsString;
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+
+use Cache;
+
+/// Types which can report how many bytes of heap memory they own.
+///
+/// The reported size excludes the size of the value itself, which is accounted for by whatever
+/// contains it, and memory shared with other values, like the contents of an `Rc` or `Arc`.
+///
+/// It is implemented for primitives, strings, boxes, tuples, arrays and the standard
+/// collections. The sizes of `HashMap`, `HashSet`, `BTreeMap` and `BTreeSet` are estimates,
+/// since their layout isn't specified: hash tables are assumed to use one control byte per
+/// bucket, and B-trees are counted by their entries, without the spare room in their nodes.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::HeapSize;
+///
+/// assert_eq!(5u32.heap_size(), 0);
+/// assert_eq!(String::with_capacity(10).heap_size(), 10);
+/// assert_eq!(vec![String::from("abc")].heap_size(), 24 + 3);
+/// ```
+pub trait HeapSize {
+    /// Returns the number of bytes of heap memory owned by this value.
+    fn heap_size(&self) -> usize;
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                fn heap_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+no_heap!(
+    (), bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+impl<T: ?Sized> HeapSize for &T {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(feature = "std")]
+impl HeapSize for OsString {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(feature = "std")]
+impl HeapSize for PathBuf {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl HeapSize for Box<str> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<[T]> {
+    fn heap_size(&self) -> usize {
+        size_of_val::<[T]>(self) + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for VecDeque<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: ?Sized> HeapSize for Rc<T> {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl<T: ?Sized> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+/// Estimates the bytes allocated by a hash table with room for `capacity` entries of `T`, which
+/// has a control byte per bucket, and keeps an eighth of its buckets empty once it has 8.
+#[cfg(feature = "std")]
+fn hash_table_size<T>(capacity: usize) -> usize {
+    let buckets = match capacity {
+        0 => return 0,
+        1..=7 => capacity + 1,
+        _ => capacity / 7 * 8,
+    };
+    buckets * (size_of::<T>() + 1)
+}
+
+#[cfg(feature = "std")]
+impl<K: HeapSize, V: HeapSize, S> HeapSize for HashMap<K, V, S> {
+    fn heap_size(&self) -> usize {
+        hash_table_size::<(K, V)>(self.capacity())
+            + self
+                .iter()
+                .map(|(key, value)| key.heap_size() + value.heap_size())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: HeapSize, S> HeapSize for HashSet<T, S> {
+    fn heap_size(&self) -> usize {
+        hash_table_size::<T>(self.capacity()) + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<K: HeapSize, V: HeapSize> HeapSize for BTreeMap<K, V> {
+    fn heap_size(&self) -> usize {
+        self.len() * size_of::<(K, V)>()
+            + self
+                .iter()
+                .map(|(key, value)| key.heap_size() + value.heap_size())
+                .sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for BTreeSet<T> {
+    fn heap_size(&self) -> usize {
+        self.len() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<T: HeapSize, E: HeapSize> HeapSize for Result<T, E> {
+    fn heap_size(&self) -> usize {
+        match *self {
+            Ok(ref value) => value.heap_size(),
+            Err(ref error) => error.heap_size(),
+        }
+    }
+}
+
+impl<T: HeapSize, const N: usize> HeapSize for [T; N] {
+    fn heap_size(&self) -> usize {
+        self.iter().map(HeapSize::heap_size).sum()
+    }
+}
+
+macro_rules! tuple_heap {
+    ($(($($name:ident),+)),*) => {
+        $(
+            impl<$($name: HeapSize),+> HeapSize for ($($name,)+) {
+                #[allow(non_snake_case)]
+                fn heap_size(&self) -> usize {
+                    let ($(ref $name,)+) = *self;
+                    0 $(+ $name.heap_size())+
+                }
+            }
+        )*
+    };
+}
+
+tuple_heap!((A), (A, B), (A, B, C), (A, B, C, D), (A, B, C, D, E), (A, B, C, D, E, F));
+
+impl<K: HeapSize, V: HeapSize> HeapSize for Cache<K, V> {
+    fn heap_size(&self) -> usize {
+        let entries = self.recent.iter().chain(self.frequent.iter());
+        self.list_capacity_bytes()
+            + entries
+                .map(|entry| entry.key.heap_size() + entry.value.heap_size())
+                .sum::<usize>()
+            + self.ghost.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<K: HeapSize, V: HeapSize> Cache<K, V> {
+    /// Returns the number of bytes of memory used by the cache, including the cache itself, the
+    /// spare capacity of its lists, and the heap memory owned by its keys, values and the keys
+    /// remembered in ghost.
+    ///
+    /// This takes time proportional to the number of entries and ghost keys, see
+    /// [`MemoryBoundedCache`] to keep track of it as the cache changes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache: Cache<u32, String> = Cache::new(8);
+    /// cache.reserve(8);
+    /// let empty = cache.memory_usage();
+    /// cache.insert(1, "a".repeat(100));
+    /// assert_eq!(cache.memory_usage(), empty + 100);
+    /// ```
+    ///
+    /// [`MemoryBoundedCache`]: struct.MemoryBoundedCache.html
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>() + self.heap_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    #[cfg(feature = "std")]
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use super::HeapSize;
+
+    #[test]
+    fn nested() {
+        let value = (Some(vec![1u64, 2, 3]), Box::new(String::from("ab")), [0u8; 4]);
+        assert_eq!(value.heap_size(), 3 * 8 + size_of::<String>() + 2);
+        let slice: Box<[String]> = vec![String::from("abc")].into_boxed_slice();
+        assert_eq!(slice.heap_size(), size_of::<String>() + 3);
+    }
+
+    #[test]
+    fn collections() {
+        let shared = Rc::new(String::from("abc"));
+        assert_eq!(shared.heap_size(), 0);
+
+        let tree: BTreeMap<u32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        assert_eq!(tree.heap_size(), 10 * size_of::<(u32, String)>() + 10);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn hash_tables() {
+        let mut map = HashMap::with_capacity(100);
+        map.insert(1u64, String::from("abc"));
+        let table = map.heap_size() - 3;
+        assert!(table >= map.capacity() * (size_of::<(u64, String)>() + 1));
+        assert!(table <= 2 * map.capacity() * (size_of::<(u64, String)>() + 1));
+        assert_eq!(HashMap::<u64, u64>::new().heap_size(), 0);
+    }
+}
@@ -0,0 +1,746 @@
+//! Caching of HTTP responses, independent of any HTTP library
+
+use std::time::{Duration, Instant};
+
+use {Cache, Entry, Stats};
+
+/// The most variants of a response, selected by `Vary`, stored for one URI
+const MAX_VARIANTS: usize = 8;
+
+/// An HTTP request, as seen by an [`HttpCache`].
+///
+/// [`HttpCache`]: struct.HttpCache.html
+pub trait HttpRequest {
+    /// Returns the method of the request, such as `GET`.
+    fn method(&self) -> &str;
+
+    /// Returns the URI of the request, which identifies the responses cached for it.
+    fn uri(&self) -> &str;
+
+    /// Returns the value of the header named `name`, which is matched case-insensitively. The
+    /// values of a repeated header should be joined by commas.
+    fn header(&self, name: &str) -> Option<&str>;
+}
+
+/// An HTTP response, as seen by an [`HttpCache`].
+///
+/// [`HttpCache`]: struct.HttpCache.html
+pub trait HttpResponse {
+    /// Returns the status code of the response, such as 200.
+    fn status(&self) -> u16;
+
+    /// Returns the value of the header named `name`, which is matched case-insensitively. The
+    /// values of a repeated header should be joined by commas.
+    fn header(&self, name: &str) -> Option<&str>;
+}
+
+/// Where the response returned by [`HttpCache::fetch`] came from.
+///
+/// [`HttpCache::fetch`]: struct.HttpCache.html#method.fetch
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CacheStatus {
+    /// A fresh response was served from the cache, without contacting the origin
+    Hit,
+    /// A stale response was revalidated with the origin, which answered `304 Not Modified`, and
+    /// was served from the cache
+    Revalidated,
+    /// The response came from the origin, and was stored if it is cacheable
+    Miss,
+    /// The request can't be served from the cache, so it was sent to the origin, and the
+    /// response wasn't stored
+    Bypass,
+}
+
+/// The validators of a stored response, which make a revalidation request conditional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Validators {
+    /// The `ETag` of the stored response
+    pub etag: Option<String>,
+    /// The `Last-Modified` date of the stored response
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Returns the headers to add to a request to make it conditional: `If-None-Match` with the
+    /// entity tag, and `If-Modified-Since` with the modification date.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Validators;
+    ///
+    /// let validators = Validators {
+    ///     etag: Some("\"v1\"".to_string()),
+    ///     last_modified: None,
+    /// };
+    /// let headers: Vec<_> = validators.headers().collect();
+    /// assert_eq!(headers, [("If-None-Match", "\"v1\"")]);
+    /// ```
+    pub fn headers(&self) -> impl Iterator<Item = (&'static str, &str)> + '_ {
+        let etag = self
+            .etag
+            .as_ref()
+            .map(|etag| ("If-None-Match", etag.as_str()));
+        let last_modified = self
+            .last_modified
+            .as_ref()
+            .map(|date| ("If-Modified-Since", date.as_str()));
+        etag.into_iter().chain(last_modified)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// The directives of a `Cache-Control` header which the cache understands.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    /// Parses a `Cache-Control` header. Unknown directives are ignored, and an invalid number of
+    /// seconds is taken as 0, so the response is stale.
+    fn parse(header: Option<&str>) -> Self {
+        let mut directives = CacheControl::default();
+        for directive in header.unwrap_or("").split(',') {
+            let (name, seconds) = match directive.find('=') {
+                Some(i) => {
+                    let value = directive[i + 1..].trim().trim_matches('"');
+                    (&directive[..i], Some(value.parse().unwrap_or(0)))
+                }
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => directives.s_maxage = seconds,
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Returns how long a response is fresh for, as seen by a shared cache.
+    fn lifetime(&self) -> Duration {
+        if self.no_cache {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs(self.s_maxage.or(self.max_age).unwrap_or(0))
+    }
+}
+
+/// A response stored in the cache, and what is needed to tell whether it can be served.
+#[derive(Debug, Clone)]
+struct Stored<R> {
+    response: R,
+    /// The request headers named by the response's `Vary`, and their values in the request
+    vary: Vec<(String, Option<String>)>,
+    validators: Validators,
+    stored_at: Instant,
+    /// The age of the response when it was stored, from its `Age` header
+    initial_age: Duration,
+    /// How long the response is fresh for
+    lifetime: Duration,
+}
+
+impl<R: HttpResponse> Stored<R> {
+    /// Prepares a response to the request to be stored, or returns `None` if it can't be.
+    fn new<Q: HttpRequest>(request: &Q, response: R, now: Instant) -> Option<Self> {
+        let directives = CacheControl::parse(response.header("cache-control"));
+        if directives.no_store || directives.private {
+            return None;
+        }
+        // a shared cache may only store responses to authorized requests explicitly allowed
+        if request.header("authorization").is_some()
+            && !(directives.public || directives.must_revalidate || directives.s_maxage.is_some())
+        {
+            return None;
+        }
+        let validators = Validators {
+            etag: response.header("etag").map(String::from),
+            last_modified: response.header("last-modified").map(String::from),
+        };
+        // partial and not modified responses only make sense for the request they answer
+        let explicit = (directives.max_age.is_some() || directives.s_maxage.is_some())
+            && !matches!(response.status(), 206 | 304);
+        let cacheable_status = match response.status() {
+            200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501 => {
+                !validators.is_empty()
+            }
+            _ => false,
+        };
+        if !explicit && !cacheable_status {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        for name in response.header("vary").unwrap_or("").split(',') {
+            let name = name.trim().to_ascii_lowercase();
+            if name == "*" {
+                return None;
+            }
+            if !name.is_empty() {
+                let value = request.header(&name).map(|value| value.trim().to_string());
+                vary.push((name, value));
+            }
+        }
+
+        Some(Stored {
+            initial_age: age(&response),
+            response,
+            vary,
+            validators,
+            stored_at: now,
+            lifetime: directives.lifetime(),
+        })
+    }
+
+    /// Returns true if this response was selected by the same request headers as `request` has.
+    fn matches<Q: HttpRequest>(&self, request: &Q) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.header(name).map(str::trim) == value.as_deref())
+    }
+
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.initial_age + now.saturating_duration_since(self.stored_at) < self.lifetime
+    }
+
+    /// Updates the stored response with a `304 Not Modified` response from revalidating it.
+    fn refresh(&mut self, not_modified: &R, now: Instant) {
+        self.stored_at = now;
+        self.initial_age = age(not_modified);
+        if let Some(header) = not_modified.header("cache-control") {
+            self.lifetime = CacheControl::parse(Some(header)).lifetime();
+        }
+        if let Some(etag) = not_modified.header("etag") {
+            self.validators.etag = Some(etag.to_string());
+        }
+        if let Some(date) = not_modified.header("last-modified") {
+            self.validators.last_modified = Some(date.to_string());
+        }
+    }
+}
+
+/// Returns the age of a response from its `Age` header, or zero.
+fn age<R: HttpResponse>(response: &R) -> Duration {
+    let seconds = response
+        .header("age")
+        .and_then(|age| age.trim().parse().ok());
+    Duration::from_secs(seconds.unwrap_or(0))
+}
+
+/// A shared cache of HTTP responses, kept in a 2Q [`Cache`] keyed by URI.
+///
+/// Requests and responses are accessed through the [`HttpRequest`] and [`HttpResponse`]
+/// traits, so any HTTP library can be used. [`fetch`] serves `GET` requests from the cache
+/// while their responses are fresh, and sends them to the origin otherwise, adding the
+/// [`Validators`] of a stale response so the origin can answer `304 Not Modified`.
+///
+/// Responses are stored by URI, and then by the values of the request headers named by their
+/// `Vary` header. Freshness comes from the `s-maxage` and `max-age` directives of
+/// `Cache-Control`, less the `Age` of the response. Responses without them are stored only if
+/// they have an `ETag` or `Last-Modified` date, and are revalidated every time they are used.
+/// `Expires` isn't supported, and no freshness is guessed heuristically.
+///
+/// Responses with `no-store` or `private`, or to requests with `Authorization` unless they
+/// allow it, aren't stored. Requests with `no-store` are sent straight to the origin, and
+/// requests with `no-cache` or `max-age=0` always revalidate. A successful request with a method
+/// which isn't safe, such as `POST`, invalidates the responses stored for its URI.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::{CacheStatus, HttpCache, HttpRequest, HttpResponse};
+///
+/// #[derive(Debug, Clone)]
+/// struct Response {
+///     status: u16,
+///     cache_control: &'static str,
+///     body: String,
+/// }
+///
+/// impl HttpResponse for Response {
+///     fn status(&self) -> u16 {
+///         self.status
+///     }
+///     fn header(&self, name: &str) -> Option<&str> {
+///         if name.eq_ignore_ascii_case("cache-control") {
+///             Some(self.cache_control)
+///         } else {
+///             None
+///         }
+///     }
+/// }
+///
+/// struct Get(&'static str);
+///
+/// impl HttpRequest for Get {
+///     fn method(&self) -> &str {
+///         "GET"
+///     }
+///     fn uri(&self) -> &str {
+///         self.0
+///     }
+///     fn header(&self, _name: &str) -> Option<&str> {
+///         None
+///     }
+/// }
+///
+/// let mut cache = HttpCache::new(64);
+/// let origin = |_validators: Option<&_>| -> Result<Response, ()> {
+///     Ok(Response {
+///         status: 200,
+///         cache_control: "max-age=60",
+///         body: "hello".to_string(),
+///     })
+/// };
+/// let (status, response) = cache.fetch(&Get("/hello"), origin).unwrap();
+/// assert_eq!((status, response.body.as_str()), (CacheStatus::Miss, "hello"));
+/// let (status, _) = cache.fetch(&Get("/hello"), origin).unwrap();
+/// assert_eq!(status, CacheStatus::Hit);
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+/// [`HttpRequest`]: trait.HttpRequest.html
+/// [`HttpResponse`]: trait.HttpResponse.html
+/// [`Validators`]: struct.Validators.html
+/// [`fetch`]: struct.HttpCache.html#method.fetch
+#[derive(Debug)]
+pub struct HttpCache<R> {
+    cache: Cache<String, Vec<Stored<R>>>,
+}
+
+impl<R: HttpResponse + Clone> HttpCache<R> {
+    /// Creates an empty cache, storing the responses of up to `size` URIs.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize) -> Self {
+        HttpCache {
+            cache: Cache::new(size),
+        }
+    }
+
+    /// Returns a response to `request`, from the cache if it has a fresh one, and otherwise by
+    /// calling `send` to send the request to the origin.
+    ///
+    /// When a stored response is stale, `send` is given its validators, which should be added
+    /// to the request as headers. If the origin answers `304 Not Modified`, the stored response
+    /// is refreshed and returned instead.
+    ///
+    /// # Errors
+    /// Returns the error from `send`, leaving the cache unchanged.
+    pub fn fetch<Q, E, F>(&mut self, request: &Q, send: F) -> Result<(CacheStatus, R), E>
+    where
+        Q: HttpRequest,
+        F: FnOnce(Option<&Validators>) -> Result<R, E>,
+    {
+        self.fetch_at(request, Instant::now(), send)
+    }
+
+    /// Returns the number of URIs with stored responses.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns true if no responses are stored.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Removes every response stored for `uri`, returning true if there were any.
+    pub fn invalidate(&mut self, uri: &str) -> bool {
+        self.cache.remove(uri).is_some()
+    }
+
+    /// Removes every stored response.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Returns the lookup statistics of the underlying cache, which counts a lookup for every
+    /// `GET` request which may be served from the cache.
+    pub fn stats(&self) -> Stats {
+        self.cache.stats()
+    }
+
+    fn fetch_at<Q, E, F>(
+        &mut self,
+        request: &Q,
+        now: Instant,
+        send: F,
+    ) -> Result<(CacheStatus, R), E>
+    where
+        Q: HttpRequest,
+        F: FnOnce(Option<&Validators>) -> Result<R, E>,
+    {
+        let method = request.method();
+        if method != "GET" {
+            let response = send(None)?;
+            let safe = matches!(method, "HEAD" | "OPTIONS" | "TRACE");
+            if !safe && response.status() < 400 {
+                self.cache.remove(request.uri());
+            }
+            return Ok((CacheStatus::Bypass, response));
+        }
+        let directives = CacheControl::parse(request.header("cache-control"));
+        if directives.no_store {
+            return send(None).map(|response| (CacheStatus::Bypass, response));
+        }
+        let revalidate = directives.no_cache || directives.max_age == Some(0);
+
+        let stored = self
+            .cache
+            .get_mut(request.uri())
+            .and_then(|variants| variants.iter_mut().find(|stored| stored.matches(request)));
+        let validators = match stored {
+            Some(ref stored) if !revalidate && stored.is_fresh(now) => {
+                return Ok((CacheStatus::Hit, stored.response.clone()));
+            }
+            Some(ref stored) if !stored.validators.is_empty() => Some(stored.validators.clone()),
+            _ => None,
+        };
+        let response = send(validators.as_ref())?;
+        if let (Some(stored), Some(_), 304) = (stored, validators, response.status()) {
+            stored.refresh(&response, now);
+            return Ok((CacheStatus::Revalidated, stored.response.clone()));
+        }
+        self.store(request, &response, now);
+        Ok((CacheStatus::Miss, response))
+    }
+
+    /// Stores a response from the origin, replacing the response stored for the same request.
+    fn store<Q: HttpRequest>(&mut self, request: &Q, response: &R, now: Instant) {
+        let stored = Stored::new(request, response.clone(), now);
+        match self.cache.peek_entry(request.uri().to_string()) {
+            Entry::Occupied(mut entry) => {
+                let variants = entry.get_mut();
+                variants.retain(|variant| !variant.matches(request));
+                if let Some(stored) = stored {
+                    variants.truncate(MAX_VARIANTS - 1);
+                    variants.insert(0, stored);
+                }
+                if variants.is_empty() {
+                    entry.remove();
+                }
+            }
+            Entry::Vacant(entry) => {
+                if let Some(stored) = stored {
+                    entry.insert(vec![stored]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{CacheControl, CacheStatus, HttpCache, HttpRequest, HttpResponse, Validators};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Message {
+        method: &'static str,
+        uri: &'static str,
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+    }
+
+    impl Message {
+        fn request(
+            method: &'static str,
+            uri: &'static str,
+            headers: &[(&'static str, &str)],
+        ) -> Self {
+            Message {
+                method,
+                uri,
+                status: 0,
+                headers: headers
+                    .iter()
+                    .map(|&(name, value)| (name, value.to_string()))
+                    .collect(),
+                body: String::new(),
+            }
+        }
+
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|header| header.0.eq_ignore_ascii_case(name))
+                .map(|header| header.1.as_str())
+        }
+    }
+
+    impl HttpRequest for Message {
+        fn method(&self) -> &str {
+            self.method
+        }
+        fn uri(&self) -> &str {
+            self.uri
+        }
+        fn header(&self, name: &str) -> Option<&str> {
+            Message::header(self, name)
+        }
+    }
+
+    impl HttpResponse for Message {
+        fn status(&self) -> u16 {
+            self.status
+        }
+        fn header(&self, name: &str) -> Option<&str> {
+            Message::header(self, name)
+        }
+    }
+
+    /// A stand-in origin server, whose responses carry the headers it is configured with, and
+    /// whose body changes with its version.
+    #[derive(Debug, Default)]
+    struct Origin {
+        version: u32,
+        headers: Vec<(&'static str, String)>,
+        requests: Vec<Message>,
+    }
+
+    impl Origin {
+        fn with_headers(headers: &[(&'static str, &str)]) -> Self {
+            Origin {
+                headers: headers
+                    .iter()
+                    .map(|&(name, value)| (name, value.to_string()))
+                    .collect(),
+                ..Origin::default()
+            }
+        }
+
+        /// Serves a request, made conditional by `validators`.
+        fn serve(
+            &mut self,
+            request: &Message,
+            validators: Option<&Validators>,
+        ) -> Result<Message, ()> {
+            let mut request = request.clone();
+            if let Some(validators) = validators {
+                request.headers.extend(
+                    validators
+                        .headers()
+                        .map(|(name, value)| (name, value.to_string())),
+                );
+            }
+            let etag = format!("\"v{}\"", self.version);
+            let mut response = Message {
+                status: 200,
+                headers: self.headers.clone(),
+                body: format!(
+                    "{} {} v{}",
+                    request.uri,
+                    request.header("accept").unwrap_or("*"),
+                    self.version
+                ),
+                ..request.clone()
+            };
+            response.headers.push(("ETag", etag.clone()));
+            if request.header("if-none-match") == Some(&etag) {
+                response.status = 304;
+                response.body.clear();
+            }
+            self.requests.push(request);
+            Ok(response)
+        }
+    }
+
+    fn get(uri: &'static str) -> Message {
+        Message::request("GET", uri, &[])
+    }
+
+    #[test]
+    fn cache_control_directives() {
+        let directives = CacheControl::parse(Some("Public, max-age=\"60\", s-maxage=x, no-cache"));
+        assert!(directives.public && directives.no_cache && !directives.no_store);
+        assert_eq!(directives.max_age, Some(60));
+        assert_eq!(directives.s_maxage, Some(0));
+        assert_eq!(directives.lifetime(), Duration::from_secs(0));
+        assert_eq!(
+            CacheControl::parse(Some("max-age=5, s-maxage=10")).lifetime(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(CacheControl::parse(None), CacheControl::default());
+    }
+
+    #[test]
+    fn fresh_responses_are_served_until_stale() {
+        let mut origin = Origin::with_headers(&[("Cache-Control", "max-age=60"), ("Age", "10")]);
+        let mut cache = HttpCache::new(16);
+        let now = Instant::now();
+        let fetch = |cache: &mut HttpCache<Message>, origin: &mut Origin, secs| {
+            let request = get("/a");
+            cache
+                .fetch_at(&request, now + Duration::from_secs(secs), |v| {
+                    origin.serve(&request, v)
+                })
+                .unwrap()
+        };
+
+        assert_eq!(fetch(&mut cache, &mut origin, 0).0, CacheStatus::Miss);
+        let (status, response) = fetch(&mut cache, &mut origin, 49);
+        assert_eq!(
+            (status, response.body.as_str()),
+            (CacheStatus::Hit, "/a * v0")
+        );
+        assert_eq!(origin.requests.len(), 1);
+
+        // stale after 50 more seconds, and revalidated with the entity tag
+        assert_eq!(
+            fetch(&mut cache, &mut origin, 50).0,
+            CacheStatus::Revalidated
+        );
+        assert_eq!(origin.requests[1].header("if-none-match"), Some("\"v0\""));
+        assert_eq!(fetch(&mut cache, &mut origin, 99).0, CacheStatus::Hit);
+
+        origin.version = 1;
+        let (status, response) = fetch(&mut cache, &mut origin, 100);
+        assert_eq!(
+            (status, response.body.as_str()),
+            (CacheStatus::Miss, "/a * v1")
+        );
+        assert_eq!(fetch(&mut cache, &mut origin, 101).1.body, "/a * v1");
+        assert_eq!(cache.stats().hits(), 5);
+    }
+
+    #[test]
+    fn responses_vary_by_request_headers() {
+        let mut origin =
+            Origin::with_headers(&[("Cache-Control", "max-age=60"), ("Vary", "Accept")]);
+        let mut cache = HttpCache::new(16);
+        let json = Message::request("GET", "/v", &[("Accept", "application/json")]);
+        let html = Message::request("GET", "/v", &[("accept", " text/html ")]);
+        for _ in 0..2 {
+            for request in &[&json, &html, &get("/v")] {
+                cache.fetch(*request, |v| origin.serve(request, v)).unwrap();
+            }
+        }
+        assert_eq!(origin.requests.len(), 3);
+        assert_eq!(cache.len(), 1);
+        let (status, response) = cache.fetch(&html, |_| Err(())).unwrap();
+        assert_eq!(
+            (status, response.body.as_str()),
+            (CacheStatus::Hit, "/v  text/html  v0")
+        );
+
+        let mut origin = Origin::with_headers(&[("Cache-Control", "max-age=60"), ("Vary", "*")]);
+        cache
+            .fetch(&get("/star"), |v| origin.serve(&get("/star"), v))
+            .unwrap();
+        assert_eq!(
+            cache
+                .fetch(&get("/star"), |v| origin.serve(&get("/star"), v))
+                .unwrap()
+                .0,
+            CacheStatus::Miss
+        );
+    }
+
+    #[test]
+    fn uncacheable_responses_are_not_stored() {
+        let mut cache = HttpCache::new(16);
+        for &cache_control in &["no-store, max-age=60", "private, max-age=60"] {
+            let mut origin = Origin::with_headers(&[("Cache-Control", cache_control)]);
+            let request = get("/private");
+            for _ in 0..2 {
+                let (status, _) = cache
+                    .fetch(&request, |v| origin.serve(&request, v))
+                    .unwrap();
+                assert_eq!(status, CacheStatus::Miss);
+            }
+            assert!(origin
+                .requests
+                .iter()
+                .all(|request| request.header("if-none-match").is_none()));
+        }
+        assert!(cache.is_empty());
+
+        // responses to authorized requests are only stored when explicitly allowed
+        let authorized = Message::request("GET", "/auth", &[("Authorization", "Bearer x")]);
+        let mut origin = Origin::with_headers(&[("Cache-Control", "max-age=60")]);
+        cache
+            .fetch(&authorized, |v| origin.serve(&authorized, v))
+            .unwrap();
+        assert!(cache.is_empty());
+        let mut origin = Origin::with_headers(&[("Cache-Control", "public, max-age=60")]);
+        cache
+            .fetch(&authorized, |v| origin.serve(&authorized, v))
+            .unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn requests_control_the_cache() {
+        let mut origin = Origin::with_headers(&[("Cache-Control", "max-age=60")]);
+        let mut cache = HttpCache::new(16);
+        let request = get("/r");
+        cache
+            .fetch(&request, |v| origin.serve(&request, v))
+            .unwrap();
+
+        let no_cache = Message::request("GET", "/r", &[("Cache-Control", "no-cache")]);
+        let (status, _) = cache
+            .fetch(&no_cache, |v| origin.serve(&no_cache, v))
+            .unwrap();
+        assert_eq!(status, CacheStatus::Revalidated);
+        let no_store = Message::request("GET", "/r", &[("Cache-Control", "no-store")]);
+        let (status, _) = cache
+            .fetch(&no_store, |v| origin.serve(&no_store, v))
+            .unwrap();
+        assert_eq!(status, CacheStatus::Bypass);
+        assert_eq!(origin.requests.len(), 3);
+
+        // a failed request leaves the cache unchanged
+        assert_eq!(
+            cache.fetch(&no_cache, |_| Err("unreachable")),
+            Err("unreachable")
+        );
+        assert_eq!(
+            cache.fetch(&request, |_| Err("unreachable")).unwrap().0,
+            CacheStatus::Hit
+        );
+
+        let post = Message::request("POST", "/r", &[]);
+        let (status, _) = cache.fetch(&post, |v| origin.serve(&post, v)).unwrap();
+        assert_eq!(status, CacheStatus::Bypass);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn responses_without_freshness_are_revalidated() {
+        let mut origin = Origin::default();
+        let mut cache = HttpCache::new(16);
+        let request = get("/etag");
+        let (status, _) = cache
+            .fetch(&request, |v| origin.serve(&request, v))
+            .unwrap();
+        assert_eq!(status, CacheStatus::Miss);
+        let (status, response) = cache
+            .fetch(&request, |v| origin.serve(&request, v))
+            .unwrap();
+        assert_eq!((status, response.status), (CacheStatus::Revalidated, 200));
+        assert_eq!(response.body, "/etag * v0");
+        assert_eq!(origin.requests[1].header("If-None-Match"), Some("\"v0\""));
+        assert!(cache.invalidate("/etag"));
+        assert!(!cache.invalidate("/etag"));
+    }
+}
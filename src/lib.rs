@@ -2,6 +2,24 @@
 //!
 //! This cache based on the paper entitled
 //! **[2Q: A Low Overhead High-Performance Buffer Management Replacement Algorithm](http://www.vldb.org/conf/1994/P439.PDF)**.
+//!
+//! # Features
+//!
+//! The `std` feature is enabled by default. Without it the crate is `no_std`, and only needs
+//! `alloc`: the [`CachedReader`], [`HttpCache`] and [`TieredCache`] wrappers and the `HeapSize`
+//! impls for `OsString` and `PathBuf` require `std`. [`TinyLfuCache`], [`CountMinSketch`] and the
+//! [`mrc`] module only need it for their default hashers, and take a hash builder otherwise.
+//!
+//! Without the `alloc` feature, which `std` enables, only [`ArrayCache`] is available, and the
+//! crate doesn't allocate at all.
+//!
+//! The `tower` feature adds [`CacheLayer`] and [`CacheService`], which cache the responses of
+//! a [tower](https://docs.rs/tower) service.
+//!
+//! The `tracing` feature makes caches emit [tracing](https://docs.rs/tracing) events for misses,
+//! ghost hits, promotions, evictions and loads, configured by [`Cache::set_trace_level`] and
+//! [`Cache::trace_keys`].
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![deny(
     missing_docs,
     missing_debug_implementations, missing_copy_implementations,
@@ -11,33 +29,171 @@
     unused_import_braces, unused_qualifications
  )]
 
-use std::collections::VecDeque;
-use std::collections::vec_deque;
-use std::borrow::Borrow;
-use std::cmp;
-use std::mem;
-use std::iter;
-use std::fmt;
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate core;
+
+#[cfg(feature = "alloc")]
+use alloc::collections::{vec_deque, TryReserveError, VecDeque};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use core::borrow::Borrow;
+#[cfg(feature = "alloc")]
+use core::{array, cmp, iter, mem};
+use core::fmt;
+use core::error::Error;
+
+#[cfg(feature = "macros")]
+extern crate cache_2q_macros;
+#[cfg(feature = "tower")]
+extern crate tower_layer;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+pub mod array_cache;
+#[cfg(feature = "alloc")]
+mod bounded;
+#[cfg(feature = "alloc")]
+mod heapsize;
+#[cfg(feature = "std")]
+mod http;
+#[cfg(feature = "alloc")]
+mod memoize;
+#[cfg(feature = "testing")]
+pub mod model;
+#[cfg(feature = "alloc")]
+pub mod mrc;
+#[cfg(feature = "alloc")]
+mod negative;
+#[cfg(feature = "alloc")]
+mod openmetrics;
+#[cfg(feature = "alloc")]
+mod profile;
+#[cfg(feature = "std")]
+mod reader;
+#[cfg(feature = "tower")]
+mod service;
+#[cfg(feature = "alloc")]
+mod tagged;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "std")]
+mod tiered;
+#[cfg(feature = "alloc")]
+mod tinylfu;
+#[cfg(feature = "alloc")]
+mod writeback;
+
+pub use array_cache::ArrayCache;
+#[cfg(feature = "alloc")]
+pub use bounded::MemoryBoundedCache;
+#[cfg(feature = "alloc")]
+pub use heapsize::HeapSize;
+#[cfg(feature = "std")]
+pub use http::{CacheStatus, HttpCache, HttpRequest, HttpResponse, Validators};
+#[cfg(feature = "alloc")]
+pub use memoize::Memoize;
+#[cfg(feature = "alloc")]
+pub use negative::{Lookup, NegativeCache};
+#[cfg(feature = "alloc")]
+pub use openmetrics::OpenMetrics;
+#[cfg(feature = "alloc")]
+pub use profile::Profile;
+#[cfg(feature = "std")]
+pub use reader::CachedReader;
+#[cfg(feature = "tower")]
+pub use service::{CacheLayer, CacheService, ResponseFuture};
+#[cfg(feature = "alloc")]
+pub use tagged::TaggedCache;
+#[cfg(feature = "std")]
+pub use tiered::{Codec, DiskTier, TieredCache};
+#[cfg(feature = "alloc")]
+pub use tinylfu::{CountMinSketch, TinyLfuCache};
+#[cfg(feature = "alloc")]
+pub use writeback::{BackingStore, WriteBackCache};
+
+/// Memoizes a free function in a 2Q [`Cache`], keyed by its arguments.
+///
+/// The results are kept in a cache of `size` entries shared by every thread, so the arguments
+/// and the return type must be `Clone + Eq + Send`, and `Clone + Send` respectively. The cache
+/// isn't locked while the function runs, so it may call itself recursively.
+///
+/// A module with the same name as the function is generated next to it, with a `clear()`
+/// function which forgets every memoized result, and a `stats()` function which returns the
+/// [`Stats`] of the cache.
+///
+/// Requires the `macros` feature.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::cached_2q;
+///
+/// #[cached_2q(size = 128)]
+/// fn fibonacci(n: u64) -> u64 {
+///     if n < 2 {
+///         n
+///     } else {
+///         fibonacci(n - 1) + fibonacci(n - 2)
+///     }
+/// }
+///
+/// assert_eq!(fibonacci(90), 2880067194370816120);
+/// assert_eq!(fibonacci::stats().misses, 91);
+/// fibonacci::clear();
+/// assert_eq!(fibonacci(1), 1);
+/// assert_eq!(fibonacci::stats().misses, 92);
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+/// [`Stats`]: struct.Stats.html
+#[cfg(feature = "macros")]
+pub use cache_2q_macros::cached_2q;
+
+/// Emits a tracing event from a cache, with the key it concerns, if the `tracing` feature is
+/// enabled.
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($cache:expr, $event:ident, $key:expr) => {
+        $cache.tracer.emit(trace::Event::$event, $key)
+    };
+}
+
+#[cfg(all(feature = "alloc", not(feature = "tracing")))]
+macro_rules! trace {
+    ($cache:expr, $event:ident, $key:expr) => {
+        let _ = $key;
+    };
+}
 
+#[cfg(feature = "alloc")]
 /// The type of items in the recent and frequent lists.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct CacheEntry<K, V> {
     key: K,
     value: V,
+    pinned: bool,
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K, V> Into<(&'a K, &'a V)> for &'a CacheEntry<K, V> {
     fn into(self) -> (&'a K, &'a V) {
         (&self.key, &self.value)
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K, V> Into<(&'a K, &'a mut V)> for &'a mut CacheEntry<K, V> {
     fn into(self) -> (&'a K, &'a mut V) {
         (&self.key, &mut self.value)
     }
 }
 
+#[cfg(feature = "alloc")]
 /// A 2Q Cache which maps keys to values
 ///
 /// 2Q is an enhancement over an LRU cache by tracking both recent and frequently accessed entries
@@ -121,7 +277,7 @@ impl<'a, K, V> Into<(&'a K, &'a mut V)> for &'a mut CacheEntry<K, V> {
 /// let stat = player_stats.entry("attack").or_insert(100);
 /// *stat += random_stat_buff();
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct Cache<K, V> {
     frequent: VecDeque<CacheEntry<K, V>>,
     recent: VecDeque<CacheEntry<K, V>>,
@@ -129,8 +285,169 @@ pub struct Cache<K, V> {
     max_frequent: usize,
     max_recent: usize,
     max_ghost: usize,
+    pinned_recent: usize,
+    pinned_frequent: usize,
+    stale_recent: usize,
+    stale_frequent: usize,
+    generation: u64,
+    stats: Stats,
+    profile: Option<Profile>,
+    #[cfg(feature = "tracing")]
+    tracer: trace::Tracer<K>,
+}
+
+/// An error returned when an entry could not be pinned in a [`Cache`].
+///
+/// [`Cache`]: struct.Cache.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PinError {
+    /// The key was not in the cache
+    NotFound,
+    /// Pinning the entry would leave no room in the cache for unpinned entries
+    Full,
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            PinError::NotFound => "key not found in cache",
+            PinError::Full => "pinning would leave no room in the cache for unpinned entries",
+        })
+    }
+}
+
+impl Error for PinError {}
+
+/// An error returned by [`Cache::get_many_mut`] when the same key was given more than once.
+///
+/// [`Cache::get_many_mut`]: struct.Cache.html#method.get_many_mut
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DuplicateKeyError {
+    /// The index of the first occurrence of the duplicated key
+    pub first: usize,
+    /// The index of the second occurrence of the duplicated key
+    pub second: usize,
 }
 
+impl fmt::Display for DuplicateKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "keys {} and {} are the same", self.first, self.second)
+    }
+}
+
+impl Error for DuplicateKeyError {}
+
+/// A broken invariant of a [`Cache`], found by [`Cache::check_invariants`].
+///
+/// [`Cache`]: struct.Cache.html
+/// [`Cache::check_invariants`]: struct.Cache.html#method.check_invariants
+#[cfg(feature = "testing")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum InvariantError {
+    /// A key has more than one entry in recent and frequent
+    DuplicateKey,
+    /// A key is remembered in ghost more than once
+    DuplicateGhostKey,
+    /// A key remembered in ghost also has an entry in recent or frequent
+    GhostKeyCached,
+    /// The cache holds more entries than its size
+    TooManyEntries,
+    /// Recent holds more unpinned entries than its size
+    RecentTooLong,
+    /// Frequent holds more unpinned entries than its size
+    FrequentTooLong,
+    /// Ghost remembers more keys than its size
+    GhostTooLong,
+    /// The number of pinned entries doesn't match the entries marked as pinned
+    PinnedCount,
+    /// The number of invalidated entries is more than the entries of the cache
+    StaleCount,
+}
+
+#[cfg(feature = "testing")]
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InvariantError::DuplicateKey => "a key has more than one entry",
+            InvariantError::DuplicateGhostKey => "a key is remembered in ghost more than once",
+            InvariantError::GhostKeyCached => "a key remembered in ghost also has an entry",
+            InvariantError::TooManyEntries => "the cache holds more entries than its size",
+            InvariantError::RecentTooLong => "recent holds more unpinned entries than its size",
+            InvariantError::FrequentTooLong => {
+                "frequent holds more unpinned entries than its size"
+            }
+            InvariantError::GhostTooLong => "ghost remembers more keys than its size",
+            InvariantError::PinnedCount => "the count of pinned entries is wrong",
+            InvariantError::StaleCount => "the count of invalidated entries is wrong",
+        })
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Error for InvariantError {}
+
+/// Counters of the lookups made against a [`Cache`].
+///
+/// Lookups are counted by [`get`], [`get_mut`] and [`entry`]. Methods which don't update the
+/// cache, like [`peek`] and [`peek_entry`], are not counted.
+///
+/// [`Cache`]: struct.Cache.html
+/// [`get`]: struct.Cache.html#method.get
+/// [`get_mut`]: struct.Cache.html#method.get_mut
+/// [`entry`]: struct.Cache.html#method.entry
+/// [`peek`]: struct.Cache.html#method.peek
+/// [`peek_entry`]: struct.Cache.html#method.peek_entry
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Stats {
+    /// The number of lookups which found their key in the recent list
+    pub recent_hits: u64,
+    /// The number of lookups which found their key in the frequent list
+    pub frequent_hits: u64,
+    /// The number of lookups which did not find their key
+    pub misses: u64,
+    /// The number of entries inserted into the frequent list because their key was remembered
+    /// in the ghost list
+    pub ghost_hits: u64,
+    /// The number of entries evicted from the recent list, whose keys were then remembered in
+    /// the ghost list
+    pub recent_evictions: u64,
+    /// The number of entries evicted from the frequent list
+    pub frequent_evictions: u64,
+    /// The number of keys forgotten by the ghost list to make room for others
+    pub ghost_evictions: u64,
+}
+
+impl Stats {
+    /// Returns the total number of lookups which found their key.
+    pub fn hits(&self) -> u64 {
+        self.recent_hits + self.frequent_hits
+    }
+
+    /// Returns the total number of lookups.
+    pub fn lookups(&self) -> u64 {
+        self.hits() + self.misses
+    }
+
+    /// Returns the fraction of lookups which found their key, or 0 if there were no lookups.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Stats;
+    ///
+    /// let stats = Stats { recent_hits: 1, misses: 1, ..Stats::default() };
+    /// assert_eq!(stats.hit_ratio(), 0.5);
+    /// assert_eq!(Stats::default().hit_ratio(), 0.0);
+    /// ```
+    pub fn hit_ratio(&self) -> f64 {
+        match self.lookups() {
+            0 => 0.0,
+            lookups => self.hits() as f64 / lookups as f64,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
 impl<K: Eq, V> Cache<K, V> {
     /// Creates an empty cache, with the specified size
     ///
@@ -138,6 +455,9 @@ impl<K: Eq, V> Cache<K, V> {
     /// `size` defines the maximum number of entries, but there can be
     /// an additional `size / 2` instances of `K`
     ///
+    /// Nothing is allocated until entries are inserted: the lists of the cache grow as needed, see
+    /// [`reserve`] to allocate them ahead of time.
+    ///
     /// # Examples
     ///
     /// ```
@@ -153,21 +473,149 @@ impl<K: Eq, V> Cache<K, V> {
     /// (like [VacantEntry::insert], which returns a reference to the newly inserted item)
     ///
     /// [VacantEntry::insert]: struct.VacantEntry.html#method.insert
+    /// [`reserve`]: struct.Cache.html#method.reserve
     pub fn new(size: usize) -> Cache<K, V> {
         assert!(size > 0);
         let max_recent = cmp::max(1, size / 4);
         let max_frequent = size - max_recent;
         let max_ghost = size / 2;
         Cache {
-            frequent: VecDeque::with_capacity(max_frequent),
-            recent: VecDeque::with_capacity(max_recent),
-            ghost: VecDeque::with_capacity(max_ghost),
+            frequent: VecDeque::new(),
+            recent: VecDeque::new(),
+            ghost: VecDeque::new(),
             max_frequent: max_frequent,
             max_recent: max_recent,
             max_ghost: max_ghost,
+            pinned_recent: 0,
+            pinned_frequent: 0,
+            stale_recent: 0,
+            stale_frequent: 0,
+            generation: 0,
+            stats: Stats::default(),
+            profile: None,
+            #[cfg(feature = "tracing")]
+            tracer: trace::Tracer::new(),
         }
     }
 
+    /// Returns the lookup statistics collected since the cache was created, or since
+    /// [`reset_stats`] was last called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert(1, "a");
+    /// cache.get(&1);
+    /// cache.get(&2);
+    /// let stats = cache.stats();
+    /// assert_eq!(stats.hits(), 1);
+    /// assert_eq!(stats.misses, 1);
+    /// ```
+    ///
+    /// [`reset_stats`]: struct.Cache.html#method.reset_stats
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Resets all lookup statistics to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Starts recording a [`Profile`] of the positions at which lookups find their keys in
+    /// recent and frequent, and at which lookups which miss find theirs remembered in ghost,
+    /// discarding the profile recorded so far, if any.
+    ///
+    /// Lookups are counted by the same methods as in [`Stats`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.start_profiling();
+    /// cache.insert(1, "a");
+    /// cache.insert(2, "b");
+    /// assert_eq!(cache.get(&1), Some(&"a"));
+    /// assert_eq!(cache.profile().unwrap().recent_hits(), [0, 1]);
+    /// ```
+    ///
+    /// [`Profile`]: struct.Profile.html
+    /// [`Stats`]: struct.Stats.html
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Profile::new(self.max_recent));
+    }
+
+    /// Returns the profile recorded since [`start_profiling`] was called, if it was.
+    ///
+    /// [`start_profiling`]: struct.Cache.html#method.start_profiling
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Stops recording a profile, and returns the profile recorded, if any.
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    /// Sets the level of the tracing events emitted by the cache, or stops it emitting them if
+    /// `level` is `None`. Events are emitted at the `TRACE` level by default.
+    ///
+    /// The cache emits an event for each miss, each key remembered in ghost which is inserted
+    /// into frequent, each entry found in frequent and moved to its front, each entry evicted
+    /// from recent into ghost, each key dropped from ghost, and each value loaded by a wrapper
+    /// such as [`Memoize`]. Every event has an `event` field naming it. Requires the `tracing`
+    /// feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate cache_2q;
+    /// extern crate tracing;
+    ///
+    /// use cache_2q::Cache;
+    /// use tracing::Level;
+    ///
+    /// # fn main() {
+    ///
+    /// let mut cache: Cache<u32, u32> = Cache::new(8);
+    /// cache.set_trace_level(Some(Level::DEBUG));
+    /// # }
+    /// ```
+    ///
+    /// [`Memoize`]: struct.Memoize.html
+    #[cfg(feature = "tracing")]
+    pub fn set_trace_level(&mut self, level: Option<tracing::Level>) {
+        self.tracer.set_level(level);
+    }
+
+    /// Adds a `key` field to the tracing events emitted by the cache, with the `Debug`
+    /// representation of the key an event concerns. Misses of lookups by a borrowed form of the
+    /// key, such as [`get`], don't have a key to show. Requires the `tracing` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache: Cache<&str, u32> = Cache::new(8);
+    /// cache.trace_keys();
+    /// cache.entry("a").or_insert(1);
+    /// ```
+    ///
+    /// [`get`]: struct.Cache.html#method.get
+    #[cfg(feature = "tracing")]
+    pub fn trace_keys(&mut self)
+    where
+        K: fmt::Debug,
+    {
+        self.tracer.trace_keys();
+    }
+
     /// Returns true if the cache contains a value for the specified key.
     ///
     /// The key may be any borrowed form of the cache's key type, but
@@ -188,8 +636,8 @@ impl<K: Eq, V> Cache<K, V> {
         K: Borrow<Q>,
         Q: Eq,
     {
-        self.recent.iter().any(|entry| entry.key.borrow() == key) ||
-            self.frequent.iter().any(|entry| entry.key.borrow() == key)
+        self.live_recent().any(|entry| entry.key.borrow() == key) ||
+            self.live_frequent().any(|entry| entry.key.borrow() == key)
     }
 
     /// Returns a reference to the value corresponding to the key.
@@ -220,11 +668,11 @@ impl<K: Eq, V> Cache<K, V> {
         Q: Eq,
     {
         if let Some(&CacheEntry { ref value, .. }) =
-            self.recent.iter().find(|entry| entry.key.borrow() == key)
+            self.live_recent().find(|entry| entry.key.borrow() == key)
         {
             Some(value)
         } else if let Some(&CacheEntry { ref value, .. }) =
-            self.frequent.iter().find(|entry| entry.key.borrow() == key)
+            self.live_frequent().find(|entry| entry.key.borrow() == key)
         {
             Some(value)
         } else {
@@ -253,16 +701,9 @@ impl<K: Eq, V> Cache<K, V> {
         K: Borrow<Q>,
         Q: Eq,
     {
-        if let Some(&CacheEntry { ref value, .. }) =
-            self.recent.iter().find(|entry| entry.key.borrow() == key)
-        {
-            Some(value)
-        } else if let Some(i) = self.frequent.iter().position(|entry| entry.key.borrow() == key) {
-            let old = self.frequent.remove(i).unwrap();
-            self.frequent.push_front(old);
-            Some(&self.frequent[0].value)
-        } else {
-            None
+        match self.lookup(key)? {
+            OccupiedKind::Recent(i) => Some(&self.recent[i].value),
+            OccupiedKind::Frequent(i) => Some(&self.frequent[i].value),
         }
     }
 
@@ -289,18 +730,158 @@ impl<K: Eq, V> Cache<K, V> {
         K: Borrow<Q>,
         Q: Eq,
     {
-        if let Some(&mut CacheEntry { ref mut value, .. }) = self.recent
+        match self.lookup(key)? {
+            OccupiedKind::Recent(i) => Some(&mut self.recent[i].value),
+            OccupiedKind::Frequent(i) => Some(&mut self.frequent[i].value),
+        }
+    }
+
+    /// Finds the entry for `key`, counting the lookup in the cache's [`Stats`], and moves it to
+    /// the front of frequent if it is found there.
+    ///
+    /// [`Stats`]: struct.Stats.html
+    fn lookup<Q>(&mut self, key: &Q) -> Option<OccupiedKind>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let found = match self.live_recent().position(|entry| entry.key.borrow() == key) {
+            Some(i) => Some(OccupiedKind::Recent(i)),
+            None => self.live_frequent()
+                .position(|entry| entry.key.borrow() == key)
+                .map(OccupiedKind::Frequent),
+        };
+        self.count_lookup(found);
+        match found {
+            Some(OccupiedKind::Frequent(i)) => {
+                self.promote(i);
+                Some(OccupiedKind::Frequent(0))
+            }
+            None => {
+                if let Some(ref mut profile) = self.profile {
+                    if let Some(i) = self.ghost.iter().position(|ghost| ghost.borrow() == key) {
+                        profile.ghost_miss(i);
+                    }
+                }
+                trace!(self, Miss, None::<&K>);
+                None
+            }
+            recent => recent,
+        }
+    }
+
+    /// Counts a lookup which found its key where `found` says, before it was moved, in the
+    /// cache's [`Stats`] and [`Profile`].
+    ///
+    /// [`Stats`]: struct.Stats.html
+    /// [`Profile`]: struct.Profile.html
+    fn count_lookup(&mut self, found: Option<OccupiedKind>) {
+        match found {
+            Some(OccupiedKind::Recent(_)) => self.stats.recent_hits += 1,
+            Some(OccupiedKind::Frequent(_)) => self.stats.frequent_hits += 1,
+            None => self.stats.misses += 1,
+        }
+        if let Some(ref mut profile) = self.profile {
+            profile.lookup(found);
+        }
+    }
+
+    /// Moves the entry at position `i` of frequent to its front.
+    fn promote(&mut self, i: usize) {
+        let old = self.frequent.remove(i).unwrap();
+        self.frequent.push_front(old);
+        trace!(self, Promotion, Some(&self.frequent[0].key));
+    }
+
+    /// Looks up each of `keys`, returning the values corresponding to them in the same order.
+    ///
+    /// Each key is counted as a lookup in the cache's [`Stats`], and moved within the cache as
+    /// if by [`get`], in order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert_many(vec![(1, "a"), (2, "b")]);
+    /// assert_eq!(cache.get_many(&[2, 3, 1]), vec![Some(&"b"), None, Some(&"a")]);
+    /// assert_eq!(cache.stats().misses, 1);
+    /// ```
+    ///
+    /// [`Stats`]: struct.Stats.html
+    /// [`get`]: struct.Cache.html#method.get
+    pub fn get_many<'k, Q, I>(&mut self, keys: I) -> Vec<Option<&V>>
+    where
+        I: IntoIterator<Item = &'k Q>,
+        K: Borrow<Q>,
+        Q: 'k + ?Sized + Eq,
+    {
+        let keys: Vec<&Q> = keys.into_iter().collect();
+        for key in &keys {
+            self.lookup(*key);
+        }
+        let cache = &*self;
+        keys.into_iter().map(|key| cache.peek(key)).collect()
+    }
+
+    /// Looks up each of `keys`, returning mutable references to the values corresponding to them
+    /// in the same order.
+    ///
+    /// Each key is counted as a lookup in the cache's [`Stats`], and moved within the cache as
+    /// if by [`get_mut`], in order.
+    ///
+    /// # Errors
+    /// Returns a [`DuplicateKeyError`], without looking up any key, if the same key is given more
+    /// than once, since that would allow mutating a value through two references.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert_many(vec![("alice", 100), ("bob", 20)]);
+    /// if let Ok([Some(from), Some(to)]) = cache.get_many_mut(["alice", "bob"]) {
+    ///     *from -= 30;
+    ///     *to += 30;
+    /// }
+    /// assert_eq!(cache.peek("alice"), Some(&70));
+    /// assert_eq!(cache.peek("bob"), Some(&50));
+    /// assert!(cache.get_many_mut(["alice", "alice"]).is_err());
+    /// ```
+    ///
+    /// [`Stats`]: struct.Stats.html
+    /// [`get_mut`]: struct.Cache.html#method.get_mut
+    /// [`DuplicateKeyError`]: struct.DuplicateKeyError.html
+    pub fn get_many_mut<Q, const N: usize>(
+        &mut self,
+        keys: [&Q; N],
+    ) -> Result<[Option<&mut V>; N], DuplicateKeyError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        for second in 1..N {
+            if let Some(first) = keys[..second].iter().position(|&key| key == keys[second]) {
+                return Err(DuplicateKeyError { first, second });
+            }
+        }
+        for &key in &keys {
+            self.lookup(key);
+        }
+        let (live_recent, live_frequent) = (self.live_recent().len(), self.live_frequent().len());
+        let mut values: [Option<&mut V>; N] = array::from_fn(|_| None);
+        let entries = self.recent
             .iter_mut()
-            .find(|entry| entry.key.borrow() == key)
-        {
-            Some(value)
-        } else if let Some(i) = self.frequent.iter().position(|entry| entry.key.borrow() == key) {
-            let old = self.frequent.remove(i).unwrap();
-            self.frequent.push_front(old);
-            Some(&mut self.frequent[0].value)
-        } else {
-            None
+            .take(live_recent)
+            .chain(self.frequent.iter_mut().take(live_frequent));
+        for entry in entries {
+            if let Some(i) = keys.iter().position(|&key| entry.key.borrow() == key) {
+                values[i] = Some(&mut entry.value);
+            }
         }
+        Ok(values)
     }
 
     /// Inserts a key-value pair into the cache.
@@ -324,7 +905,7 @@ impl<K: Eq, V> Cache<K, V> {
     /// assert_eq!(*cache.get(&37).unwrap(), "c");
     /// ```
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        match self.entry(key) {
+        match self.touch_entry(key) {
             Entry::Occupied(mut entry) => Some(entry.insert(value)),
             Entry::Vacant(entry) => {
                 entry.insert(value);
@@ -333,6 +914,86 @@ impl<K: Eq, V> Cache<K, V> {
         }
     }
 
+    /// Inserts each of the key-value pairs into the cache in order, as if by [`insert`], and
+    /// returns the old values replaced in the same order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert(2, "x");
+    /// assert_eq!(cache.insert_many(vec![(1, "a"), (2, "b")]), vec![None, Some("x")]);
+    /// ```
+    ///
+    /// [`insert`]: struct.Cache.html#method.insert
+    pub fn insert_many<I>(&mut self, pairs: I) -> Vec<Option<V>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        pairs.into_iter().map(|(key, value)| self.insert(key, value)).collect()
+    }
+
+    /// Loads entries directly into frequent, for restoring a set of entries known to be
+    /// accessed frequently, such as one saved before a restart.
+    ///
+    /// The entries should be given from the most to the least frequently accessed. Each is added
+    /// behind the entries already in frequent until frequent is full, and the remaining entries
+    /// are dropped. An entry for a key which is already in the cache replaces its value in place
+    /// instead. Returns the number of entries which were loaded.
+    ///
+    /// No lookups are counted in the cache's [`Stats`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// // frequent holds 6 of the 8 entries
+    /// assert_eq!(cache.warm((0..10).map(|i| (i, i * 10))), 6);
+    /// assert_eq!(cache.peek(&5), Some(&50));
+    /// assert_eq!(cache.peek(&6), None);
+    ///
+    /// // unlike inserted entries, warmed entries survive a scan
+    /// for i in 100..200 {
+    ///     cache.insert(i, i);
+    /// }
+    /// assert_eq!(cache.peek(&0), Some(&0));
+    /// ```
+    ///
+    /// [`Stats`]: struct.Stats.html
+    pub fn warm<I>(&mut self, entries: I) -> usize
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.reclaim_stale();
+        let mut loaded = 0;
+        for (key, value) in entries {
+            match self.peek_entry(key) {
+                Entry::Occupied(mut entry) => {
+                    entry.insert(value);
+                }
+                Entry::Vacant(VacantEntry { cache, key, kind }) => {
+                    if cache.victim(Segment::Frequent).is_some() {
+                        break;
+                    }
+                    if let VacantKind::Ghost(i) = kind {
+                        cache.ghost.remove(i);
+                    }
+                    cache.frequent.push_back(CacheEntry {
+                        key,
+                        value,
+                        pinned: false,
+                    });
+                }
+            }
+            loaded += 1;
+        }
+        loaded
+    }
+
     /// Gets the given key's corresponding entry in the cache for in-place manipulation.
     ///
     /// # Examples
@@ -348,6 +1009,31 @@ impl<K: Eq, V> Cache<K, V> {
     /// }
     /// ```
     pub fn entry(&mut self, key: K) -> Entry<K, V> {
+        let mut entry = self.peek_entry(key);
+        match entry {
+            Entry::Occupied(OccupiedEntry { ref mut cache, ref mut kind }) => {
+                cache.count_lookup(Some(*kind));
+                if let OccupiedKind::Frequent(ref mut i) = *kind {
+                    cache.promote(*i);
+                    *i = 0;
+                }
+            }
+            Entry::Vacant(VacantEntry { ref mut cache, ref key, kind }) => {
+                cache.count_lookup(None);
+                if let (Some(ref mut profile), VacantKind::Ghost(i)) = (&mut cache.profile, kind) {
+                    profile.ghost_miss(i);
+                }
+                trace!(cache, Miss, Some(key));
+            }
+        }
+        entry
+    }
+
+    /// Like [`entry`], but without counting a lookup in the cache's [`Stats`].
+    ///
+    /// [`entry`]: struct.Cache.html#method.entry
+    /// [`Stats`]: struct.Stats.html
+    fn touch_entry(&mut self, key: K) -> Entry<'_, K, V> {
         let mut entry = self.peek_entry(key);
         if let Entry::Occupied(OccupiedEntry {
             ref mut cache,
@@ -355,8 +1041,7 @@ impl<K: Eq, V> Cache<K, V> {
             ..
         }) = entry
         {
-            let old_entry = cache.frequent.remove(*i).unwrap();
-            cache.frequent.push_front(old_entry);
+            cache.promote(*i);
             *i = 0;
         }
         entry
@@ -375,7 +1060,7 @@ impl<K: Eq, V> Cache<K, V> {
     /// assert_eq!(a.len(), 1);
     /// ```
     pub fn len(&self) -> usize {
-        self.recent.len() + self.frequent.len()
+        self.recent.len() + self.frequent.len() - self.stale_recent - self.stale_frequent
     }
 
     /// Returns true if the cache contains no elements.
@@ -383,67 +1068,502 @@ impl<K: Eq, V> Cache<K, V> {
     /// # Examples
     ///
     /// ```
-    /// use cache_2q::Cache;
+    /// use cache_2q::Cache;
+    ///
+    /// let mut a = Cache::new(8);
+    /// assert!(a.is_empty());
+    /// a.insert(1, "a");
+    /// assert!(!a.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes a key from the cache, returning the value associated with the key if the key
+    /// was previously in the cache.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but
+    /// Eq on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.remove(&1), Some("a"));
+    /// assert_eq!(cache.remove(&1), None);
+    /// ```
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq,
+    {
+        if let Some(i) = self.live_recent()
+            .position(|entry| entry.key.borrow() == key)
+        {
+            let entry = self.recent.remove(i).unwrap();
+            if entry.pinned {
+                self.pinned_recent -= 1;
+            }
+            Some(entry.value)
+        } else if let Some(i) = self.live_frequent().position(|entry| entry.key.borrow() == key) {
+            let entry = self.frequent.remove(i).unwrap();
+            if entry.pinned {
+                self.pinned_frequent -= 1;
+            }
+            Some(entry.value)
+        } else {
+            None
+        }
+    }
+
+    /// Removes each of `keys` from the cache, returning the values which were associated with
+    /// them in the same order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert_many(vec![(1, "a"), (2, "b")]);
+    /// assert_eq!(cache.remove_many(&[2, 3]), vec![Some("b"), None]);
+    /// assert_eq!(cache.len(), 1);
+    /// ```
+    pub fn remove_many<'k, Q, I>(&mut self, keys: I) -> Vec<Option<V>>
+    where
+        I: IntoIterator<Item = &'k Q>,
+        K: Borrow<Q>,
+        Q: 'k + ?Sized + Eq,
+    {
+        keys.into_iter().map(|key| self.remove(key)).collect()
+    }
+
+    /// Clears the cache, removing all key-value pairs. Keeps the allocated memory for reuse, see
+    /// [`shrink_to_fit`] to release it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut a = Cache::new(32);
+    /// a.insert(1, "a");
+    /// a.clear();
+    /// assert!(a.is_empty());
+    /// ```
+    ///
+    /// [`shrink_to_fit`]: struct.Cache.html#method.shrink_to_fit
+    pub fn clear(&mut self) {
+        self.recent.clear();
+        self.ghost.clear();
+        self.frequent.clear();
+        self.pinned_recent = 0;
+        self.pinned_frequent = 0;
+        self.stale_recent = 0;
+        self.stale_frequent = 0;
+    }
+
+    /// Reserves capacity for at least `additional` more entries in each of recent and frequent,
+    /// and as many more keys in ghost, without going over the size of each list.
+    ///
+    /// The lists of the cache grow as entries are inserted, so this only avoids reallocating them
+    /// later.
+    ///
+    /// # Panics
+    /// panics if the new capacity overflows `usize`, see [`try_reserve`] to handle allocation
+    /// failure instead
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache: Cache<u32, u32> = Cache::new(1_000_000);
+    /// cache.reserve(100);
+    /// let reserved = cache.memory_usage();
+    /// cache.extend((0..100).map(|i| (i, i)));
+    /// assert_eq!(cache.memory_usage(), reserved);
+    /// ```
+    ///
+    /// [`try_reserve`]: struct.Cache.html#method.try_reserve
+    pub fn reserve(&mut self, additional: usize) {
+        let (recent, frequent, ghost) = self.reservation(additional);
+        self.recent.reserve(recent);
+        self.frequent.reserve(frequent);
+        self.ghost.reserve(ghost);
+    }
+
+    /// Like [`reserve`], but returns an error instead of panicking or aborting if the capacity
+    /// overflows or the allocation fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache: Cache<u64, u64> = Cache::new(usize::MAX);
+    /// assert!(cache.try_reserve(usize::MAX).is_err());
+    /// assert!(cache.try_reserve(64).is_ok());
+    /// ```
+    ///
+    /// [`reserve`]: struct.Cache.html#method.reserve
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let (recent, frequent, ghost) = self.reservation(additional);
+        self.recent.try_reserve(recent)?;
+        self.frequent.try_reserve(frequent)?;
+        self.ghost.try_reserve(ghost)
+    }
+
+    /// Shrinks the capacity of the lists of the cache as much as possible, releasing the memory
+    /// left unused after entries were removed. Invalidated entries are dropped first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache: Cache<u32, u32> = Cache::new(1024);
+    /// let empty = cache.memory_usage();
+    /// cache.extend((0..1000).map(|i| (i, i)));
+    /// cache.clear();
+    /// assert!(cache.memory_usage() > empty);
+    /// cache.shrink_to_fit();
+    /// assert_eq!(cache.memory_usage(), empty);
+    /// ```
+    pub fn shrink_to_fit(&mut self) {
+        self.reclaim_stale();
+        self.recent.shrink_to_fit();
+        self.frequent.shrink_to_fit();
+        self.ghost.shrink_to_fit();
+    }
+
+    /// Returns how many more entries [`reserve`] makes room for in recent, frequent and ghost.
+    ///
+    /// [`reserve`]: struct.Cache.html#method.reserve
+    fn reservation(&self, additional: usize) -> (usize, usize, usize) {
+        (
+            cmp::min(additional, self.max_recent.saturating_sub(self.recent.len())),
+            cmp::min(additional, self.max_frequent.saturating_sub(self.frequent.len())),
+            cmp::min(additional, self.max_ghost.saturating_sub(self.ghost.len())),
+        )
+    }
+
+    /// Invalidates every entry in the cache, including pinned entries, in constant time.
+    ///
+    /// Invalidated entries are treated as missing, and are only dropped as the space they take is
+    /// reclaimed to make room for new entries. Every call increments the cache's [`generation`].
+    ///
+    /// If `keep_ghost` is true, the keys remembered in ghost are kept, so keys which were evicted
+    /// before the invalidation are still inserted into frequent when they are next inserted.
+    /// Otherwise, ghost is cleared, which takes time proportional to its length if `K` needs to
+    /// be dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// cache.insert(1, "a");
+    /// cache.insert_pinned(2, "b").unwrap();
+    /// cache.invalidate_all(true);
+    /// assert!(cache.is_empty());
+    /// assert_eq!(cache.get(&1), None);
+    /// assert_eq!(cache.pinned_len(), 0);
+    /// assert_eq!(cache.generation(), 1);
+    ///
+    /// cache.insert(1, "c");
+    /// assert_eq!(cache.get(&1), Some(&"c"));
+    /// assert_eq!(cache.len(), 1);
+    /// ```
+    ///
+    /// [`generation`]: struct.Cache.html#method.generation
+    pub fn invalidate_all(&mut self, keep_ghost: bool) {
+        self.generation += 1;
+        self.stale_recent = self.recent.len();
+        self.stale_frequent = self.frequent.len();
+        self.pinned_recent = 0;
+        self.pinned_frequent = 0;
+        if !keep_ghost {
+            self.ghost.clear();
+        }
+    }
+
+    /// Returns the number of times [`invalidate_all`] has been called on the cache.
+    ///
+    /// [`invalidate_all`]: struct.Cache.html#method.invalidate_all
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Removes every entry for which `f` returns true, including pinned entries, and returns how
+    /// many were removed.
+    ///
+    /// The keys of removed entries aren't remembered in ghost, see [`purge_ghost_where`] to also
+    /// forget keys which were already evicted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(32);
+    /// for i in 0..8 {
+    ///     cache.insert(i, i * 10);
+    /// }
+    /// assert_eq!(cache.invalidate_where(|&k, _| k % 2 == 0), 4);
+    /// assert_eq!(cache.invalidate_where(|_, &v| v > 40), 2);
+    /// assert_eq!(cache.len(), 2);
+    /// ```
+    ///
+    /// [`purge_ghost_where`]: struct.Cache.html#method.purge_ghost_where
+    pub fn invalidate_where<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.reclaim_stale();
+        let len = self.len();
+        let pinned_recent = &mut self.pinned_recent;
+        self.recent.retain(|entry| {
+            let invalidate = f(&entry.key, &entry.value);
+            if invalidate && entry.pinned {
+                *pinned_recent -= 1;
+            }
+            !invalidate
+        });
+        let pinned_frequent = &mut self.pinned_frequent;
+        self.frequent.retain(|entry| {
+            let invalidate = f(&entry.key, &entry.value);
+            if invalidate && entry.pinned {
+                *pinned_frequent -= 1;
+            }
+            !invalidate
+        });
+        len - self.len()
+    }
+
+    /// Forgets every key in ghost for which `f` returns true, and returns how many were
+    /// forgotten.
+    ///
+    /// A forgotten key which is inserted again is placed in recent, rather than frequent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(4);
+    /// cache.insert(1, "a");
+    /// cache.insert(2, "b");
+    /// assert_eq!(cache.purge_ghost_where(|&k| k == 1), 1);
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.peek(&2), None);
+    /// ```
+    pub fn purge_ghost_where<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&K) -> bool,
+    {
+        let len = self.ghost.len();
+        self.ghost.retain(|key| !f(key));
+        len - self.ghost.len()
+    }
+
+    /// Pins the entry for `key`, so it will never be evicted from the cache. It can still be
+    /// removed explicitly, with methods like [`remove`].
+    ///
+    /// Pinned entries don't count against the sizes of the recent and frequent lists, but do
+    /// count against the size of the cache as a whole, leaving less room for unpinned entries.
+    ///
+    /// The key may be any borrowed form of the cache's key type, but
+    /// Eq on the borrowed form *must* match those for
+    /// the key type.
+    ///
+    /// # Errors
+    /// Returns [`PinError::NotFound`] if the key is not in the cache, or [`PinError::Full`] if
+    /// pinning the entry would leave no room in the cache for unpinned entries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::{Cache, PinError};
     ///
-    /// let mut a = Cache::new(8);
-    /// assert!(a.is_empty());
-    /// a.insert(1, "a");
-    /// assert!(!a.is_empty());
+    /// let mut cache = Cache::new(4);
+    /// cache.insert(0, "config");
+    /// assert_eq!(cache.pin(&0), Ok(()));
+    /// for i in 1..100 {
+    ///     cache.insert(i, "scan");
+    /// }
+    /// assert_eq!(cache.get(&0), Some(&"config"));
+    /// assert_eq!(cache.pin(&1000), Err(PinError::NotFound));
     /// ```
-    pub fn is_empty(&self) -> bool {
-        self.recent.is_empty() && self.frequent.is_empty()
+    ///
+    /// [`remove`]: struct.Cache.html#method.remove
+    /// [`PinError::NotFound`]: enum.PinError.html#variant.NotFound
+    /// [`PinError::Full`]: enum.PinError.html#variant.Full
+    pub fn pin<Q>(&mut self, key: &Q) -> Result<(), PinError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let full = self.pinned_len() + 1 >= self.max_recent + self.max_frequent;
+        let (live_recent, live_frequent) = (self.live_recent().len(), self.live_frequent().len());
+        if let Some(entry) = self.recent
+            .iter_mut()
+            .take(live_recent)
+            .find(|entry| entry.key.borrow() == key)
+        {
+            if !entry.pinned {
+                if full {
+                    return Err(PinError::Full);
+                }
+                entry.pinned = true;
+                self.pinned_recent += 1;
+            }
+            Ok(())
+        } else if let Some(entry) = self.frequent
+            .iter_mut()
+            .take(live_frequent)
+            .find(|entry| entry.key.borrow() == key)
+        {
+            if !entry.pinned {
+                if full {
+                    return Err(PinError::Full);
+                }
+                entry.pinned = true;
+                self.pinned_frequent += 1;
+            }
+            Ok(())
+        } else {
+            Err(PinError::NotFound)
+        }
     }
 
-    /// Removes a key from the cache, returning the value associated with the key if the key
-    /// was previously in the cache.
+    /// Unpins the entry for `key`, allowing it to be evicted again.
     ///
-    /// The key may be any borrowed form of the cache's key type, but
-    /// Eq on the borrowed form *must* match those for
-    /// the key type.
+    /// Pinned entries don't count against the sizes of the recent and frequent lists, so if the
+    /// list of the entry is full, its oldest unpinned entry is evicted, which may be the entry
+    /// itself.
+    ///
+    /// Returns true if the entry was pinned.
     ///
     /// # Examples
     ///
     /// ```
     /// use cache_2q::Cache;
     ///
-    /// let mut cache = Cache::new(8);
-    /// cache.insert(1, "a");
-    /// assert_eq!(cache.remove(&1), Some("a"));
-    /// assert_eq!(cache.remove(&1), None);
+    /// let mut cache = Cache::new(4);
+    /// cache.insert_pinned(1, "a").unwrap();
+    /// assert!(cache.unpin(&1));
+    /// assert!(!cache.unpin(&1));
+    /// assert!(!cache.is_pinned(&1));
     /// ```
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
     where
         K: Borrow<Q>,
-        Q: Eq,
+        Q: ?Sized + Eq,
     {
-        if let Some(i) = self.recent
-            .iter()
-            .position(|entry| entry.key.borrow() == key)
+        let (live_recent, live_frequent) = (self.live_recent().len(), self.live_frequent().len());
+        if let Some(entry) = self.recent
+            .iter_mut()
+            .take(live_recent)
+            .find(|entry| entry.key.borrow() == key)
         {
-            Some(self.recent.remove(i).unwrap().value)
-        } else if let Some(i) = self.frequent.iter().position(|entry| entry.key.borrow() == key) {
-            Some(self.frequent.remove(i).unwrap().value)
-        } else {
-            None
+            if entry.pinned {
+                entry.pinned = false;
+                self.pinned_recent -= 1;
+                if live_recent - self.pinned_recent > self.max_recent {
+                    if let Some(victim) = self.oldest_recent() {
+                        self.evict(victim, &mut |_, _, _| {});
+                    }
+                }
+                return true;
+            }
+        } else if let Some(entry) = self.frequent
+            .iter_mut()
+            .take(live_frequent)
+            .find(|entry| entry.key.borrow() == key)
+        {
+            if entry.pinned {
+                entry.pinned = false;
+                self.pinned_frequent -= 1;
+                if live_frequent - self.pinned_frequent > self.max_frequent {
+                    if let Some(victim) = self.oldest_frequent() {
+                        self.evict(victim, &mut |_, _, _| {});
+                    }
+                }
+                return true;
+            }
         }
+        false
+    }
+
+    /// Returns true if the cache contains a pinned entry for the specified key.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.live_recent()
+            .chain(self.live_frequent())
+            .any(|entry| entry.pinned && entry.key.borrow() == key)
+    }
+
+    /// Returns the number of pinned entries in the cache.
+    pub fn pinned_len(&self) -> usize {
+        self.pinned_recent + self.pinned_frequent
     }
 
-    /// Clears the cache, removing all key-value pairs. Keeps the allocated memory for reuse.
+    /// Inserts a key-value pair into the cache, and pins it so it will never be evicted.
+    ///
+    /// If the cache did not have this key present, `Ok(None)` is returned.
+    ///
+    /// If the cache did have this key present, the value is updated, and the old
+    /// value is returned.
+    ///
+    /// # Errors
+    /// Returns [`PinError::Full`], without inserting anything, if pinning the entry would leave
+    /// no room in the cache for unpinned entries.
     ///
     /// # Examples
     ///
     /// ```
-    /// use cache_2q::Cache;
-    ///
-    /// let mut a = Cache::new(32);
-    /// a.insert(1, "a");
-    /// a.clear();
-    /// assert!(a.is_empty());
+    /// use cache_2q::{Cache, PinError};
+    ///
+    /// let mut cache = Cache::new(4);
+    /// assert_eq!(cache.insert_pinned("auth", "key"), Ok(None));
+    /// assert_eq!(cache.insert_pinned("config", "value"), Ok(None));
+    /// assert_eq!(cache.insert_pinned("auth", "new key"), Ok(Some("key")));
+    /// assert_eq!(cache.insert_pinned("other", "value"), Ok(None));
+    /// // pinning a fourth entry would leave no room for anything else
+    /// assert_eq!(cache.insert_pinned("another", "value"), Err(PinError::Full));
+    /// assert!(!cache.contains_key("another"));
     /// ```
-    pub fn clear(&mut self) {
-        self.recent.clear();
-        self.ghost.clear();
-        self.frequent.clear();
+    ///
+    /// [`PinError::Full`]: enum.PinError.html#variant.Full
+    pub fn insert_pinned(&mut self, key: K, value: V) -> Result<Option<V>, PinError> {
+        if !self.is_pinned(&key) && self.pinned_len() + 1 >= self.max_recent + self.max_frequent {
+            return Err(PinError::Full);
+        }
+        let (old, entry) = match self.touch_entry(key) {
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(value);
+                (Some(old), entry.kind)
+            }
+            Entry::Vacant(entry) => (None, entry.insert_entry(value).kind),
+        };
+        let (pinned, count) = match entry {
+            OccupiedKind::Recent(i) => (&mut self.recent[i].pinned, &mut self.pinned_recent),
+            OccupiedKind::Frequent(i) => (&mut self.frequent[i].pinned, &mut self.pinned_frequent),
+        };
+        if !*pinned {
+            *pinned = true;
+            *count += 1;
+        }
+        Ok(old)
     }
 
     /// Gets the given key's corresponding entry in the cache for in-place manipulation.
@@ -462,12 +1582,13 @@ impl<K: Eq, V> Cache<K, V> {
     /// }
     /// ```
     pub fn peek_entry(&mut self, key: K) -> Entry<K, V> {
-        if let Some(i) = self.frequent.iter().position(|entry| &entry.key == &key) {
+        self.reclaim_stale_key(&key);
+        if let Some(i) = self.live_frequent().position(|entry| entry.key == key) {
             Entry::Occupied(OccupiedEntry {
                 cache: self,
                 kind: OccupiedKind::Frequent(i),
             })
-        } else if let Some(i) = self.recent.iter().position(|entry| &entry.key == &key) {
+        } else if let Some(i) = self.live_recent().position(|entry| entry.key == key) {
             Entry::Occupied(OccupiedEntry {
                 cache: self,
                 kind: OccupiedKind::Recent(i),
@@ -487,6 +1608,22 @@ impl<K: Eq, V> Cache<K, V> {
         }
     }
 
+    /// Drops the invalidated entry for `key`, if there is one, so it can't be confused with a
+    /// live entry for the same key.
+    fn reclaim_stale_key(&mut self, key: &K) {
+        let live = self.recent.len() - self.stale_recent;
+        if let Some(i) = self.recent.iter().skip(live).position(|entry| &entry.key == key) {
+            self.recent.remove(live + i);
+            self.stale_recent -= 1;
+            return;
+        }
+        let live = self.frequent.len() - self.stale_frequent;
+        if let Some(i) = self.frequent.iter().skip(live).position(|entry| &entry.key == key) {
+            self.frequent.remove(live + i);
+            self.stale_frequent -= 1;
+        }
+    }
+
     /// An iterator visiting all key-value pairs in arbitrary order.
     /// The iterator element type is `(&'a K, &'a V)`.
     ///
@@ -506,11 +1643,324 @@ impl<K: Eq, V> Cache<K, V> {
     /// ```
     pub fn iter(&self) -> Iter<K, V> {
         Iter {
-            inner: self.recent.iter().chain(self.frequent.iter()).map(Into::into),
+            inner: self.live_recent().chain(self.live_frequent()).map(Into::into),
+        }
+    }
+
+    /// Checks the internal invariants of the cache, for use in tests and fuzzing of code built on
+    /// top of it:
+    ///
+    /// * no key has more than one entry, or is remembered in ghost more than once
+    /// * no key remembered in ghost has an entry
+    /// * the cache holds no more entries than its size, and ghost remembers no more keys than its
+    ///   size
+    /// * recent and frequent hold no more unpinned entries than their sizes
+    /// * the counts of pinned and invalidated entries match the entries
+    ///
+    /// Use a [`ModelCache`] to check which entries are kept.
+    ///
+    /// This takes time quadratic in the size of the cache, and is only available with the
+    /// `testing` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let mut cache = Cache::new(8);
+    /// for i in 0..100 {
+    ///     cache.insert(i % 13, i);
+    ///     assert_eq!(cache.check_invariants(), Ok(()));
+    /// }
+    /// ```
+    ///
+    /// [`ModelCache`]: model/struct.ModelCache.html
+    #[cfg(feature = "testing")]
+    pub fn check_invariants(&self) -> Result<(), InvariantError> {
+        if self.stale_recent > self.recent.len() || self.stale_frequent > self.frequent.len() {
+            return Err(InvariantError::StaleCount);
+        }
+        let live: Vec<&CacheEntry<K, V>> = self.live_recent().chain(self.live_frequent()).collect();
+        for (i, entry) in live.iter().enumerate() {
+            if live[i + 1..].iter().any(|other| other.key == entry.key) {
+                return Err(InvariantError::DuplicateKey);
+            }
+        }
+        for (i, key) in self.ghost.iter().enumerate() {
+            if self.ghost.iter().skip(i + 1).any(|other| other == key) {
+                return Err(InvariantError::DuplicateGhostKey);
+            }
+            if live.iter().any(|entry| &entry.key == key) {
+                return Err(InvariantError::GhostKeyCached);
+            }
+        }
+        if self.recent.len() + self.frequent.len() > self.max_recent + self.max_frequent {
+            return Err(InvariantError::TooManyEntries);
+        }
+        if self.ghost.len() > cmp::max(1, self.max_ghost) {
+            return Err(InvariantError::GhostTooLong);
+        }
+        let pinned_recent = self.live_recent().filter(|entry| entry.pinned).count();
+        let pinned_frequent = self.live_frequent().filter(|entry| entry.pinned).count();
+        if pinned_recent != self.pinned_recent || pinned_frequent != self.pinned_frequent {
+            return Err(InvariantError::PinnedCount);
+        }
+        if self.live_recent().len() - pinned_recent > self.max_recent {
+            return Err(InvariantError::RecentTooLong);
+        }
+        // like ghost, frequent holds an entry even if its size is 0, in a cache of size 1
+        if self.live_frequent().len() - pinned_frequent > cmp::max(1, self.max_frequent) {
+            return Err(InvariantError::FrequentTooLong);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<K, V> Cache<K, V> {
+    /// Returns the entry which would be evicted to make room for a new entry in `segment`, or
+    /// None if there is already room.
+    ///
+    /// Pinned entries are never chosen. They don't count against the sizes of the recent and
+    /// frequent lists, but do count against the size of the cache as a whole.
+    ///
+    /// Invalidated entries are always at the back of their lists, and are chosen before any live
+    /// entry of the same list, or of the whole cache once it is full.
+    fn victim(&self, segment: Segment) -> Option<OccupiedKind> {
+        let full = self.recent.len() + self.frequent.len() >= self.max_recent + self.max_frequent;
+        if full && self.stale_frequent > 0 {
+            return Some(OccupiedKind::Frequent(self.frequent.len() - 1));
+        }
+        if full && self.stale_recent > 0 {
+            return Some(OccupiedKind::Recent(self.recent.len() - 1));
+        }
+        let victim = match segment {
+            Segment::Recent if self.recent.len() - self.pinned_recent >= self.max_recent => {
+                self.oldest_recent()
+            }
+            Segment::Frequent if self.frequent.len() - self.pinned_frequent >= self.max_frequent => {
+                self.oldest_frequent()
+            }
+            _ => None,
+        };
+        victim.or_else(|| if full { self.oldest() } else { None })
+    }
+
+    /// Returns the entry which is evicted first from a full cache: the oldest entry of frequent
+    /// which can be evicted, or of recent if there is none.
+    fn oldest(&self) -> Option<OccupiedKind> {
+        self.oldest_frequent().or_else(|| self.oldest_recent())
+    }
+
+    /// Returns the number of bytes allocated for the lists of the cache, whether or not they
+    /// are used.
+    fn list_capacity_bytes(&self) -> usize {
+        (self.recent.capacity() + self.frequent.capacity()) * size_of::<CacheEntry<K, V>>()
+            + self.ghost.capacity() * size_of::<K>()
+    }
+
+    /// Returns the oldest entry of recent which can be evicted.
+    fn oldest_recent(&self) -> Option<OccupiedKind> {
+        match self.stale_recent {
+            0 => self.recent.iter().rposition(|entry| !entry.pinned).map(OccupiedKind::Recent),
+            _ => Some(OccupiedKind::Recent(self.recent.len() - 1)),
+        }
+    }
+
+    /// Returns the oldest entry of frequent which can be evicted.
+    fn oldest_frequent(&self) -> Option<OccupiedKind> {
+        match self.stale_frequent {
+            0 => self.frequent.iter().rposition(|entry| !entry.pinned).map(OccupiedKind::Frequent),
+            _ => Some(OccupiedKind::Frequent(self.frequent.len() - 1)),
+        }
+    }
+
+    /// Returns an iterator over the entries of recent which haven't been invalidated.
+    fn live_recent(&self) -> iter::Take<vec_deque::Iter<'_, CacheEntry<K, V>>> {
+        self.recent.iter().take(self.recent.len() - self.stale_recent)
+    }
+
+    /// Returns an iterator over the entries of frequent which haven't been invalidated.
+    fn live_frequent(&self) -> iter::Take<vec_deque::Iter<'_, CacheEntry<K, V>>> {
+        self.frequent.iter().take(self.frequent.len() - self.stale_frequent)
+    }
+
+    /// Drops every invalidated entry.
+    fn reclaim_stale(&mut self) {
+        let live = self.recent.len() - self.stale_recent;
+        self.recent.truncate(live);
+        let live = self.frequent.len() - self.stale_frequent;
+        self.frequent.truncate(live);
+        self.stale_recent = 0;
+        self.stale_frequent = 0;
+    }
+
+    /// Evicts an entry chosen by [`victim`], passing the segment it was evicted from, its key and
+    /// its value to `evicted`. The key of an entry evicted from recent is then remembered in ghost.
+    ///
+    /// [`victim`]: struct.Cache.html#method.victim
+    ///
+    /// The keys of invalidated entries aren't remembered.
+    fn evict<F: FnMut(Segment, &K, V)>(&mut self, victim: OccupiedKind, evicted: &mut F) {
+        match victim {
+            OccupiedKind::Recent(i) if i >= self.recent.len() - self.stale_recent => {
+                let CacheEntry { key, value, .. } = self.recent.remove(i).unwrap();
+                self.stale_recent -= 1;
+                evicted(Segment::Recent, &key, value);
+            }
+            OccupiedKind::Frequent(i) if i >= self.frequent.len() - self.stale_frequent => {
+                let CacheEntry { key, value, .. } = self.frequent.remove(i).unwrap();
+                self.stale_frequent -= 1;
+                evicted(Segment::Frequent, &key, value);
+            }
+            OccupiedKind::Recent(i) => {
+                let CacheEntry { key, value, .. } = self.recent.remove(i).unwrap();
+                evicted(Segment::Recent, &key, value);
+                self.stats.recent_evictions += 1;
+                trace!(self, RecentEviction, Some(&key));
+                if self.ghost.len() + 1 > self.max_ghost {
+                    let dropped = self.ghost.pop_back();
+                    if dropped.is_some() {
+                        self.stats.ghost_evictions += 1;
+                    }
+                    trace!(self, GhostDrop, dropped.as_ref());
+                }
+                self.ghost.push_front(key);
+            }
+            OccupiedKind::Frequent(i) => {
+                let CacheEntry { key, value, .. } = self.frequent.remove(i).unwrap();
+                evicted(Segment::Frequent, &key, value);
+                self.stats.frequent_evictions += 1;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<K: Eq, V> Cache<K, V> {
+    /// Creates a cache with the specified size, and inserts every key-value pair from `iter`
+    /// into it in order, as if by [`insert`].
+    ///
+    /// If there are more pairs than fit in the cache, later pairs win: new keys are only
+    /// inserted into recent, so earlier pairs are evicted from recent to make room, and their keys
+    /// are remembered in ghost. A key which appears again after it was evicted is inserted into
+    /// frequent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// // recent holds 2 of the 8 entries
+    /// let mut cache = Cache::from_iter_with_capacity((0..10).map(|i| (i, i)), 8);
+    /// assert_eq!(cache.len(), 2);
+    /// assert!(cache.contains_key(&8) && cache.contains_key(&9));
+    ///
+    /// // the last 4 keys evicted are remembered in ghost, so 7 is inserted into frequent and
+    /// // survives a scan, but 0 isn't
+    /// cache.extend(vec![(0, 0), (7, 7)]);
+    /// cache.extend((100..200).map(|i| (i, i)));
+    /// assert!(cache.contains_key(&7));
+    /// assert!(!cache.contains_key(&0));
+    /// ```
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    ///
+    /// [`insert`]: struct.Cache.html#method.insert
+    pub fn from_iter_with_capacity<I>(iter: I, size: usize) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut cache = Cache::new(size);
+        cache.extend(iter);
+        cache
+    }
+}
+
+/// Caches are equal if they have the same size, hold the same entries in the same order, and
+/// remember the same keys in ghost. Their statistics, profiles, generations and invalidated
+/// entries aren't compared.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::Cache;
+///
+/// let mut a = Cache::new(8);
+/// let mut b = Cache::new(8);
+/// a.insert(1, "a");
+/// b.insert(1, "a");
+/// assert_eq!(a.get(&1), Some(&"a"));
+/// assert!(a == b);
+///
+/// a.invalidate_all(false);
+/// assert!(a == Cache::new(8));
+/// ```
+#[cfg(feature = "alloc")]
+impl<K: PartialEq, V: PartialEq> PartialEq for Cache<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_recent == other.max_recent
+            && self.max_frequent == other.max_frequent
+            && self.max_ghost == other.max_ghost
+            && self.live_recent().eq(other.live_recent())
+            && self.live_frequent().eq(other.live_frequent())
+            && self.ghost == other.ghost
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<K: Eq, V: Eq> Eq for Cache<K, V> {}
+
+#[cfg(feature = "alloc")]
+impl<K: Eq, V> Extend<(K, V)> for Cache<K, V> {
+    /// Inserts every key-value pair from `iter` in order, as if by [`insert`]. See
+    /// [`from_iter_with_capacity`] for how pairs which don't fit in the cache are evicted.
+    ///
+    /// [`insert`]: struct.Cache.html#method.insert
+    /// [`from_iter_with_capacity`]: struct.Cache.html#method.from_iter_with_capacity
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<K: Eq, V> iter::FromIterator<(K, V)> for Cache<K, V> {
+    /// Creates a cache which holds every key-value pair from `iter`.
+    ///
+    /// The cache is sized so its frequent list holds every pair, and they are loaded directly into
+    /// frequent as if by [`warm`], so a later pair replaces the value of an earlier one with the
+    /// same key. Use
+    /// [`from_iter_with_capacity`] to choose the size of the cache instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::Cache;
+    ///
+    /// let cache: Cache<_, _> = (0..10).map(|i| (i, i * 10)).collect();
+    /// assert_eq!(cache.len(), 10);
+    /// assert_eq!(cache.peek(&9), Some(&90));
+    /// ```
+    ///
+    /// [`warm`]: struct.Cache.html#method.warm
+    /// [`from_iter_with_capacity`]: struct.Cache.html#method.from_iter_with_capacity
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let entries: Vec<(K, V)> = iter.into_iter().collect();
+        // the smallest size whose frequent list holds every entry
+        let mut size = entries.len() + cmp::max(1, entries.len() / 3);
+        while size - cmp::max(1, size / 4) < entries.len() {
+            size += 1;
         }
+        let mut cache = Cache::new(size);
+        cache.warm(entries);
+        cache
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + Eq, V: 'a> IntoIterator for &'a Cache<K, V> {
     type Item = (&'a K, &'a V);
     type IntoIter = Iter<'a, K, V>;
@@ -519,6 +1969,7 @@ impl<'a, K: 'a + Eq, V: 'a> IntoIterator for &'a Cache<K, V> {
     }
 }
 
+#[cfg(feature = "alloc")]
 /// A view into a single entry in a cache, which may either be vacant or occupied.
 ///
 /// This enum is constructed from the entry method on Cache.
@@ -529,6 +1980,7 @@ pub enum Entry<'a, K: 'a, V: 'a> {
     Vacant(VacantEntry<'a, K, V>),
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for Entry<'a, K, V> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -538,6 +1990,7 @@ impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for Entry<'a, K, V>
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + Eq, V: 'a> Entry<'a, K, V> {
     /// Returns a reference to this entry's key.
     ///
@@ -600,6 +2053,7 @@ impl<'a, K: 'a + Eq, V: 'a> Entry<'a, K, V> {
     }
 }
 
+#[cfg(feature = "alloc")]
 /// A view into an occupied entry in a [`Cache`].
 /// It is part of the [`Entry`] enum.
 ///
@@ -610,6 +2064,7 @@ pub struct OccupiedEntry<'a, K: 'a, V: 'a> {
     kind: OccupiedKind,
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for OccupiedEntry<'a, K, V> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("OccupiedEntry")
@@ -627,6 +2082,7 @@ impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for OccupiedEntry<'a
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a, V: 'a> OccupiedEntry<'a, K, V> {
     fn entry(&self) -> &CacheEntry<K, V> {
         match self.kind {
@@ -778,10 +2234,16 @@ impl<'a, K: 'a, V: 'a> OccupiedEntry<'a, K, V> {
         match self.kind {
             OccupiedKind::Recent(idx) => {
                 let entry = self.cache.recent.remove(idx).unwrap();
+                if entry.pinned {
+                    self.cache.pinned_recent -= 1;
+                }
                 (entry.key, entry.value)
             }
             OccupiedKind::Frequent(idx) => {
                 let entry = self.cache.frequent.remove(idx).unwrap();
+                if entry.pinned {
+                    self.cache.pinned_frequent -= 1;
+                }
                 (entry.key, entry.value)
             }
         }
@@ -810,6 +2272,7 @@ impl<'a, K: 'a, V: 'a> OccupiedEntry<'a, K, V> {
     }
 }
 
+#[cfg(feature = "alloc")]
 /// A view into a vacant entry in a [`Cache`].
 /// It is part of the [`Entry`] enum.
 ///
@@ -821,6 +2284,7 @@ pub struct VacantEntry<'a, K: 'a, V: 'a> {
     kind: VacantKind,
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for VacantEntry<'a, K, V> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("VacantEntry")
@@ -837,6 +2301,7 @@ impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for VacantEntry<'a,
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a, V: 'a> VacantEntry<'a, K, V> {
     /// Gets a reference to the key that would be used when inserting a value
     /// through the `VacantEntry`.
@@ -876,8 +2341,15 @@ impl<'a, K: 'a, V: 'a> VacantEntry<'a, K, V> {
     pub fn into_key(self) -> K {
         self.key
     }
+
+    /// Emits a tracing event for a value about to be loaded into this entry, by a wrapper such
+    /// as `Memoize`.
+    fn trace_load(&self) {
+        trace!(self.cache, Load, Some(&self.key));
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + Eq, V: 'a> VacantEntry<'a, K, V> {
     /// Sets the value of the entry with the VacantEntry's key,
     /// and returns a mutable reference to it.
@@ -897,38 +2369,52 @@ impl<'a, K: 'a + Eq, V: 'a> VacantEntry<'a, K, V> {
     /// assert_eq!(*cache.get("poneyland").unwrap(), 37);
     /// ```
     pub fn insert(self, value: V) -> &'a mut V {
+        self.insert_entry(value).into_mut()
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key, and returns the now occupied entry.
+    fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V> {
+        self.insert_entry_with(value, |_, _, _| {})
+    }
+
+    /// Like [`insert_entry`], but passes the segment, key and value of every entry evicted to
+    /// make room to `evicted`.
+    ///
+    /// [`insert_entry`]: struct.VacantEntry.html#method.insert_entry
+    fn insert_entry_with<F>(self, value: V, mut evicted: F) -> OccupiedEntry<'a, K, V>
+    where
+        F: FnMut(Segment, &K, V),
+    {
         let VacantEntry { cache, key, kind } = self;
-        match kind {
-            VacantKind::Ghost(idx) => {
-                cache.ghost.remove(idx);
-                if cache.frequent.len() + 1 > cache.max_frequent {
-                    cache.frequent.pop_back();
-                }
-                cache.frequent.push_front(CacheEntry {
-                    key: key,
-                    value: value,
-                });
-                &mut cache.frequent[0].value
-            }
-            VacantKind::Unknown => {
-                if cache.recent.len() + 1 > cache.max_recent {
-                    if let Some(CacheEntry {key: old_key, ..}) = cache.recent.pop_back() {
-                        if cache.ghost.len() + 1 > cache.max_ghost {
-                            cache.ghost.pop_back();
-                        }
-                        cache.ghost.push_front(old_key);
-                    }
-                }
-                cache.recent.push_front(CacheEntry {
-                    key: key,
-                    value: value,
-                });
-                &mut cache.recent[0].value
-            }
+        let entry = CacheEntry {
+            key,
+            value,
+            pinned: false,
+        };
+        if let VacantKind::Ghost(idx) = kind {
+            cache.ghost.remove(idx);
+            cache.stats.ghost_hits += 1;
+            trace!(cache, GhostHit, Some(&entry.key));
         }
+        let segment = kind.segment();
+        while let Some(victim) = cache.victim(segment) {
+            cache.evict(victim, &mut evicted);
+        }
+        let kind = match segment {
+            Segment::Recent => {
+                cache.recent.push_front(entry);
+                OccupiedKind::Recent(0)
+            }
+            Segment::Frequent => {
+                cache.frequent.push_front(entry);
+                OccupiedKind::Frequent(0)
+            }
+        };
+        OccupiedEntry { cache, kind }
     }
 }
 
+#[cfg(feature = "alloc")]
 /// An iterator over the entries of a `Cache`.
 ///
 /// This `struct` is created by the [`iter`] method on [`Cache`]. See its
@@ -938,11 +2424,15 @@ impl<'a, K: 'a + Eq, V: 'a> VacantEntry<'a, K, V> {
 /// [`Cache`]: struct.Cache.html
 pub struct Iter<'a, K: 'a, V: 'a> {
     inner: iter::Map<
-        iter::Chain<vec_deque::Iter<'a, CacheEntry<K, V>>, vec_deque::Iter<'a, CacheEntry<K, V>>>,
+        iter::Chain<
+            iter::Take<vec_deque::Iter<'a, CacheEntry<K, V>>>,
+            iter::Take<vec_deque::Iter<'a, CacheEntry<K, V>>>,
+        >,
         fn(&'a CacheEntry<K, V>) -> (&K, &V),
     >,
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a, V: 'a> Clone for Iter<'a, K, V> {
     fn clone(&self) -> Self {
         Iter {
@@ -951,12 +2441,14 @@ impl<'a, K: 'a, V: 'a> Clone for Iter<'a, K, V> {
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a + fmt::Debug, V: 'a + fmt::Debug> fmt::Debug for Iter<'a, K, V> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_list().entries(self.clone()).finish()
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
     type Item = (&'a K, &'a V);
 
@@ -969,21 +2461,41 @@ impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
     }
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum VacantKind {
     Ghost(usize),
     Unknown,
 }
 
+#[cfg(feature = "alloc")]
+impl VacantKind {
+    /// Returns the segment a new entry of this kind is inserted into.
+    fn segment(self) -> Segment {
+        match self {
+            VacantKind::Ghost(_) => Segment::Frequent,
+            VacantKind::Unknown => Segment::Recent,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Segment {
+    Recent,
+    Frequent,
+}
+
+#[cfg(feature = "alloc")]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum OccupiedKind {
     Recent(usize),
     Frequent(usize),
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "alloc"))]
 mod tests {
-    use super::Cache;
+    use super::{Cache, DuplicateKeyError, PinError};
 
     #[test]
     fn cache_zero_size() {
@@ -1016,4 +2528,190 @@ mod tests {
         assert_eq!(cache.get(&200), Some(&"other"));
         assert_eq!(cache.get(&100), None);
     }
+
+    #[test]
+    fn pinned_frequent_entries_survive() {
+        let mut cache = Cache::new(8);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        cache.insert(3, "c");
+        // 1 is evicted from recent, and promoted to frequent when inserted again
+        cache.insert(1, "a");
+        assert_eq!(cache.pin(&1), Ok(()));
+        // fill frequent with other entries, by inserting them again after they're evicted
+        let fill_frequent = |cache: &mut Cache<u32, &str>, keys: ::std::ops::Range<u32>| {
+            for i in keys {
+                cache.insert(i, "scan");
+                cache.insert(i + 1000, "scan");
+                cache.insert(i + 2000, "scan");
+                cache.insert(i, "scan");
+            }
+        };
+        fill_frequent(&mut cache, 10..30);
+        assert_eq!(cache.peek(&1), Some(&"a"));
+        assert!(cache.unpin(&1));
+        fill_frequent(&mut cache, 30..50);
+        assert_eq!(cache.peek(&1), None);
+    }
+
+    #[test]
+    fn unpinning_keeps_lists_within_their_sizes() {
+        // recent holds 2 unpinned entries, besides the pinned ones
+        let mut cache = Cache::new(8);
+        cache.insert_pinned(1, "a").unwrap();
+        cache.insert_pinned(2, "b").unwrap();
+        cache.insert(3, "c");
+        cache.insert(4, "d");
+        assert_eq!(cache.len(), 4);
+        assert!(cache.unpin(&2));
+        // 1 is still pinned, so 2 is the oldest unpinned entry, and is evicted to ghost
+        assert_eq!(cache.peek(&2), None);
+        assert_eq!(cache.peek(&1), Some(&"a"));
+        assert_eq!(cache.len(), 3);
+        #[cfg(feature = "testing")]
+        assert_eq!(cache.check_invariants(), Ok(()));
+        cache.insert(2, "b");
+        assert_eq!(cache.frequent[0].key, 2);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn check_invariants_checks_list_sizes() {
+        let entry = |key| ::CacheEntry {
+            key,
+            value: (),
+            pinned: false,
+        };
+        // recent holds 2 entries, and frequent 6
+        let mut cache = Cache::new(8);
+        cache.recent.extend((0..3).map(entry));
+        assert_eq!(cache.check_invariants(), Err(::InvariantError::RecentTooLong));
+        cache.recent.clear();
+        cache.frequent.extend((0..7).map(entry));
+        assert_eq!(cache.check_invariants(), Err(::InvariantError::FrequentTooLong));
+        cache.frequent[0].pinned = true;
+        cache.pinned_frequent = 1;
+        assert_eq!(cache.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn pinned_entries_count_against_size() {
+        let mut cache = Cache::new(4);
+        for i in 0..3 {
+            cache.insert_pinned(i, i).unwrap();
+        }
+        assert_eq!(cache.insert_pinned(3, 3), Err(PinError::Full));
+        for i in 10..20 {
+            cache.insert(i, i);
+            assert!(cache.len() <= 4);
+        }
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.pinned_len(), 3);
+        assert_eq!(cache.pin(&19), Err(PinError::Full));
+        assert_eq!(cache.remove(&0), Some(0));
+        assert_eq!(cache.pinned_len(), 2);
+        assert_eq!(cache.pin(&19), Ok(()));
+    }
+
+    #[test]
+    fn invalidation_counts_pinned_entries() {
+        let mut cache = Cache::new(8);
+        cache.insert_pinned(1, "a").unwrap();
+        cache.insert(2, "b");
+        assert_eq!(cache.invalidate_where(|_, _| true), 2);
+        assert_eq!(cache.pinned_len(), 0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn invalidated_entries_are_reclaimed() {
+        let mut cache = Cache::new(8);
+        for i in 0..8 {
+            cache.insert(i, i);
+        }
+        cache.invalidate_all(true);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.iter().count(), 0);
+        assert!(!cache.contains_key(&7));
+        for i in 100..108 {
+            cache.insert(i, i);
+            assert!(cache.recent.len() + cache.frequent.len() <= 8);
+        }
+        assert_eq!(cache.stale_recent + cache.stale_frequent, 0);
+
+        // re-inserting an invalidated key doesn't leave a duplicate behind
+        cache.insert(200, 200);
+        cache.invalidate_all(true);
+        cache.insert(200, 201);
+        assert_eq!(cache.remove(&200), Some(201));
+        assert_eq!(cache.remove(&200), None);
+    }
+
+    #[test]
+    fn invalidation_keeps_ghost() {
+        let mut cache = Cache::new(4);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.invalidate_all(true);
+        // 1 was evicted into ghost before the invalidation, so it goes to frequent
+        cache.insert(1, 1);
+        assert!(cache.frequent.iter().take(1).any(|entry| entry.key == 1));
+
+        cache.invalidate_all(false);
+        assert!(cache.ghost.is_empty());
+        cache.insert(1, 1);
+        assert_eq!(cache.recent[0].key, 1);
+    }
+
+    #[test]
+    fn get_many_mut_spans_segments() {
+        let mut cache = Cache::new(8);
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        cache.insert(3, 30);
+        // 1 was evicted to ghost, so it returns in frequent
+        cache.insert(1, 10);
+        assert!(cache.frequent.iter().any(|entry| entry.key == 1));
+
+        let [a, b, c] = cache.get_many_mut([&3, &4, &1]).unwrap();
+        assert_eq!(b, None);
+        *a.unwrap() += 1;
+        *c.unwrap() += 1;
+        assert_eq!(cache.get_many(&[1, 3]), vec![Some(&11), Some(&31)]);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits(), stats.misses), (4, 1));
+        assert_eq!(
+            cache.get_many_mut([&1, &3, &1]).unwrap_err(),
+            DuplicateKeyError { first: 0, second: 2 }
+        );
+        assert_eq!(cache.stats(), stats);
+    }
+
+    #[test]
+    fn collect_holds_everything() {
+        for n in 0..50 {
+            let cache: Cache<_, _> = (0..n).map(|i| (i, i)).collect();
+            assert_eq!(cache.len(), n);
+            assert_eq!(cache.frequent.len(), n);
+        }
+        let cache: Cache<_, _> = vec![(1, "a"), (2, "b"), (1, "c")].into_iter().collect();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.peek(&1), Some(&"c"));
+    }
+
+    #[test]
+    fn warm_replaces_and_forgets_ghost() {
+        let mut cache = Cache::new(8);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        cache.insert(3, "c");
+        assert_eq!(cache.ghost.len(), 1);
+        assert_eq!(cache.warm(vec![(1, "x"), (3, "y")]), 2);
+        assert!(cache.ghost.is_empty());
+        assert_eq!(cache.frequent[0].key, 1);
+        assert_eq!(cache.peek(&3), Some(&"y"));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.stats().lookups(), 0);
+    }
 }
@@ -0,0 +1,111 @@
+//! Memoization of functions, backed by a 2Q cache
+
+use core::fmt;
+
+use {Cache, Entry, Stats};
+
+/// A function whose results are memoized in a 2Q [`Cache`], keyed by its arguments.
+///
+/// Functions taking several arguments can be memoized by taking them as a tuple.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::Memoize;
+///
+/// let mut calls = 0;
+/// let mut square = Memoize::new(32, |&x: &u64| {
+///     calls += 1;
+///     x * x
+/// });
+/// assert_eq!(*square.call(4), 16);
+/// assert_eq!(*square.call(4), 16);
+/// assert_eq!(square.stats().hits(), 1);
+/// square.clear();
+/// assert_eq!(*square.call(4), 16);
+/// drop(square);
+/// assert_eq!(calls, 2);
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+pub struct Memoize<A, R, F> {
+    cache: Cache<A, R>,
+    f: F,
+}
+
+impl<A: Eq, R, F: FnMut(&A) -> R> Memoize<A, R, F> {
+    /// Wraps `f`, memoizing the results of up to `size` different arguments.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize, f: F) -> Self {
+        Memoize {
+            cache: Cache::new(size),
+            f,
+        }
+    }
+
+    /// Returns the result of calling the function with `args`, calling it only if the result
+    /// isn't already memoized.
+    pub fn call(&mut self, args: A) -> &R {
+        match self.cache.entry(args) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.trace_load();
+                let result = (self.f)(entry.key());
+                entry.insert(result)
+            }
+        }
+    }
+
+    /// Returns the result memoized for `args`, without calling the function.
+    pub fn peek(&self, args: &A) -> Option<&R> {
+        self.cache.peek(args)
+    }
+
+    /// Forgets every memoized result.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Returns the statistics of the memoized results: a hit is a call which didn't need to call
+    /// the function, and a miss is one which did.
+    pub fn stats(&self) -> Stats {
+        self.cache.stats()
+    }
+
+    /// Returns a reference to the cache of memoized results.
+    pub fn cache(&self) -> &Cache<A, R> {
+        &self.cache
+    }
+}
+
+impl<A: fmt::Debug, R: fmt::Debug, F> fmt::Debug for Memoize<A, R, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Memoize").field("cache", &self.cache).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Memoize;
+
+    #[test]
+    fn tuple_arguments() {
+        let mut calls = 0;
+        {
+            let mut add = Memoize::new(8, |&(a, b): &(u32, u32)| {
+                calls += 1;
+                a + b
+            });
+            for _ in 0..3 {
+                assert_eq!(*add.call((1, 2)), 3);
+                assert_eq!(*add.call((2, 1)), 3);
+            }
+            assert_eq!(add.peek(&(1, 2)), Some(&3));
+            assert_eq!(add.peek(&(3, 3)), None);
+            assert_eq!(add.stats().misses, 2);
+        }
+        assert_eq!(calls, 2);
+    }
+}
@@ -0,0 +1,244 @@
+//! A reference model of the 2Q policy, for testing
+//!
+//! [`ModelCache`] implements the replacement policy of [`Cache`] as plainly as possible, so
+//! property tests can run it side by side with a `Cache`, or a type built on one, and compare
+//! their results. It is only available with the `testing` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use cache_2q::Cache;
+//! use cache_2q::model::ModelCache;
+//!
+//! let mut cache = Cache::new(8);
+//! let mut model = ModelCache::new(8);
+//! for i in 0..1000u32 {
+//!     let key = i * 7 % 13;
+//!     if i % 3 == 0 {
+//!         assert_eq!(cache.get(&key), model.get(&key));
+//!     } else {
+//!         assert_eq!(cache.insert(key, i), model.insert(key, i));
+//!     }
+//!     assert!(cache.iter().eq(model.iter()));
+//! }
+//! ```
+//!
+//! [`ModelCache`]: struct.ModelCache.html
+//! [`Cache`]: ../struct.Cache.html
+
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::{cmp, iter, mem, slice};
+
+/// A reference model of a [`Cache`], which keeps the same entries given the same operations.
+///
+/// Entries are kept in plain vectors, newest first, and every operation is a linear scan.
+/// Pinning and invalidation aren't modelled.
+///
+/// [`Cache`]: ../struct.Cache.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelCache<K, V> {
+    recent: Vec<(K, V)>,
+    frequent: Vec<(K, V)>,
+    ghost: Vec<K>,
+    max_recent: usize,
+    max_frequent: usize,
+    max_ghost: usize,
+}
+
+impl<K: Eq, V> ModelCache<K, V> {
+    /// Creates an empty model of a cache with the specified size.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0);
+        let max_recent = cmp::max(1, size / 4);
+        ModelCache {
+            recent: Vec::new(),
+            frequent: Vec::new(),
+            ghost: Vec::new(),
+            max_recent,
+            max_frequent: size - max_recent,
+            max_ghost: size / 2,
+        }
+    }
+
+    /// Returns true if the model contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.peek(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key, without moving it.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.iter().find(|&(k, _)| k.borrow() == key).map(|(_, v)| v)
+    }
+
+    /// Returns a reference to the value corresponding to the key, moving an entry found in
+    /// frequent to its front.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        if let Some(i) = position(&self.frequent, key) {
+            let entry = self.frequent.remove(i);
+            self.frequent.insert(0, entry);
+            return Some(&self.frequent[0].1);
+        }
+        position(&self.recent, key).map(move |i| &self.recent[i].1)
+    }
+
+    /// Inserts a key-value pair.
+    ///
+    /// An existing entry has its value replaced, and is moved to the front of frequent if it is
+    /// there. A new entry is inserted at the front of frequent if its key is remembered in
+    /// ghost, and of recent otherwise, after evicting entries to make room.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(i) = position(&self.frequent, &key) {
+            let mut entry = self.frequent.remove(i);
+            let old = mem::replace(&mut entry.1, value);
+            self.frequent.insert(0, entry);
+            return Some(old);
+        }
+        if let Some(i) = position(&self.recent, &key) {
+            return Some(mem::replace(&mut self.recent[i].1, value));
+        }
+        match self.ghost.iter().position(|k| *k == key) {
+            Some(i) => {
+                self.ghost.remove(i);
+                while self.frequent.len() >= self.max_frequent && !self.frequent.is_empty() {
+                    self.frequent.pop();
+                }
+                self.make_room();
+                self.frequent.insert(0, (key, value));
+            }
+            None => {
+                while self.recent.len() >= self.max_recent && !self.recent.is_empty() {
+                    self.evict_recent();
+                }
+                self.make_room();
+                self.recent.insert(0, (key, value));
+            }
+        }
+        None
+    }
+
+    /// Removes a key, returning its value if it had one. The key isn't remembered in ghost.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        if let Some(i) = position(&self.recent, key) {
+            return Some(self.recent.remove(i).1);
+        }
+        position(&self.frequent, key).map(|i| self.frequent.remove(i).1)
+    }
+
+    /// Returns the number of entries in the model.
+    pub fn len(&self) -> usize {
+        self.recent.len() + self.frequent.len()
+    }
+
+    /// Returns true if the model contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every entry, and forgets every key in ghost.
+    pub fn clear(&mut self) {
+        self.recent.clear();
+        self.frequent.clear();
+        self.ghost.clear();
+    }
+
+    /// Returns true if the key is remembered in ghost.
+    pub fn ghost_contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.ghost.iter().any(|k| k.borrow() == key)
+    }
+
+    /// Returns an iterator over the entries of recent, newest first, followed by the entries of
+    /// frequent, most recently used first: the same order as [`Cache::iter`].
+    ///
+    /// [`Cache::iter`]: ../struct.Cache.html#method.iter
+    #[allow(clippy::type_complexity)]
+    pub fn iter(
+        &self,
+    ) -> iter::Map<
+        iter::Chain<slice::Iter<'_, (K, V)>, slice::Iter<'_, (K, V)>>,
+        fn(&(K, V)) -> (&K, &V),
+    > {
+        fn split<K, V>(entry: &(K, V)) -> (&K, &V) {
+            (&entry.0, &entry.1)
+        }
+        self.recent.iter().chain(self.frequent.iter()).map(split)
+    }
+
+    /// Evicts entries while the model is full: the oldest of frequent, or of recent if frequent
+    /// is empty.
+    fn make_room(&mut self) {
+        while self.len() >= self.max_recent + self.max_frequent {
+            if self.frequent.pop().is_none() {
+                self.evict_recent();
+            }
+        }
+    }
+
+    /// Evicts the oldest entry of recent, and remembers its key in ghost.
+    fn evict_recent(&mut self) {
+        if let Some((key, _)) = self.recent.pop() {
+            if self.ghost.len() + 1 > self.max_ghost {
+                self.ghost.pop();
+            }
+            self.ghost.insert(0, key);
+        }
+    }
+}
+
+/// Returns the position of the entry for `key`.
+fn position<K, V, Q>(entries: &[(K, V)], key: &Q) -> Option<usize>
+where
+    K: Borrow<Q>,
+    Q: ?Sized + Eq,
+{
+    entries.iter().position(|entry| entry.0.borrow() == key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModelCache;
+    use Cache;
+
+    #[test]
+    fn matches_cache() {
+        for &size in &[1, 2, 5, 8, 16] {
+            let mut cache = Cache::new(size);
+            let mut model = ModelCache::new(size);
+            let mut state = 7u32;
+            for _ in 0..3000 {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = (state >> 16) % (size as u32 * 3);
+                match state % 7 {
+                    0 => assert_eq!(cache.remove(&key), model.remove(&key)),
+                    1 | 2 => assert_eq!(cache.get(&key), model.get(&key)),
+                    _ => assert_eq!(cache.insert(key, state), model.insert(key, state)),
+                }
+                assert_eq!(cache.check_invariants(), Ok(()));
+                assert!(cache.iter().eq(model.iter()));
+            }
+        }
+    }
+}
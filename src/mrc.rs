@@ -0,0 +1,248 @@
+//! Estimation of the miss ratio curve of a 2Q cache
+//!
+//! Because 2Q is not a stack algorithm, the miss ratio of every cache size can't be derived from
+//! a single reuse distance histogram. Instead, a miniature cache is simulated for every size,
+//! each fed the same spatially hashed sample of the keys, and scaled down by the sample rate,
+//! as described in
+//! **[Cache Modeling and Optimization using Miniature Simulations](https://www.usenix.org/conference/atc17/technical-sessions/presentation/waldspurger)**.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "std")] {
+//! use cache_2q::mrc::MissRatioCurve;
+//!
+//! let mut curve = MissRatioCurve::new(&[10, 100, 1000], 1.0);
+//! for i in 0..10_000u32 {
+//!     curve.access(i % 500);
+//! }
+//! let points = curve.points();
+//! assert_eq!(points.len(), 3);
+//! assert!(points[0].miss_ratio() > points[2].miss_ratio());
+//! # }
+//! ```
+
+use alloc::vec::Vec;
+use core::cmp;
+use core::hash::{BuildHasher, Hash};
+#[cfg(feature = "std")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "std")]
+use std::hash::BuildHasherDefault;
+
+use {Cache, Entry};
+
+/// The resolution of the spatial sampling threshold
+const SAMPLE_MODULUS: u64 = 1 << 24;
+
+/// A single point on a miss ratio curve.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Point {
+    /// The cache size this point estimates the miss ratio for
+    pub size: usize,
+    /// The size of the miniature cache which was simulated for this point
+    pub scaled_size: usize,
+    /// The number of sampled accesses the miniature cache saw
+    pub accesses: u64,
+    /// The number of sampled accesses which missed in the miniature cache
+    pub misses: u64,
+}
+
+impl Point {
+    /// Returns the estimated fraction of accesses which would miss in a cache of `size`, or 0 if
+    /// no accesses were sampled.
+    pub fn miss_ratio(&self) -> f64 {
+        match self.accesses {
+            0 => 0.0,
+            accesses => self.misses as f64 / accesses as f64,
+        }
+    }
+}
+
+/// Estimates the miss ratio of a 2Q cache at several sizes in a single pass over a trace.
+///
+/// Only keys whose hash falls under the sample rate are simulated, so a lower sample rate
+/// trades accuracy for speed. A sample rate of 1 simulates every size exactly.
+///
+/// Keys are hashed with `DefaultHasher` by default, which requires the `std` feature. Without
+/// it, a hash builder has to be given to [`with_hasher`].
+///
+/// [`with_hasher`]: struct.MissRatioCurve.html#method.with_hasher
+#[derive(Debug, Clone)]
+pub struct MissRatioCurve<
+    K,
+    #[cfg(feature = "std")] S = BuildHasherDefault<DefaultHasher>,
+    #[cfg(not(feature = "std"))] S,
+> {
+    caches: Vec<(usize, Cache<K, ()>)>,
+    threshold: u64,
+    hash_builder: S,
+}
+
+#[cfg(feature = "std")]
+impl<K: Hash + Eq + Clone> MissRatioCurve<K> {
+    /// Creates an estimator for the miss ratio at each of `sizes`, sampling `sample_rate` of the
+    /// keys it is given.
+    ///
+    /// # Panics
+    /// panics if any size is zero, or if `sample_rate` is not within `(0, 1]`
+    pub fn new(sizes: &[usize], sample_rate: f64) -> Self {
+        MissRatioCurve::with_hasher(sizes, sample_rate, BuildHasherDefault::default())
+    }
+}
+
+impl<K: Hash + Eq + Clone, S: BuildHasher> MissRatioCurve<K, S> {
+    /// Creates an estimator like [`new`], which will use the given hash builder to sample keys.
+    ///
+    /// # Panics
+    /// panics if any size is zero, or if `sample_rate` is not within `(0, 1]`
+    ///
+    /// [`new`]: struct.MissRatioCurve.html#method.new
+    pub fn with_hasher(sizes: &[usize], sample_rate: f64, hash_builder: S) -> Self {
+        assert!(sample_rate > 0.0 && sample_rate <= 1.0);
+        let caches = sizes
+            .iter()
+            .map(|&size| {
+                assert!(size > 0);
+                let scaled = cmp::max(1, round(size as f64 * sample_rate));
+                (size, Cache::new(scaled))
+            })
+            .collect();
+        MissRatioCurve {
+            caches,
+            threshold: ceil(sample_rate * SAMPLE_MODULUS as f64),
+            hash_builder,
+        }
+    }
+
+    /// Records an access to `key`, if it is sampled.
+    pub fn access(&mut self, key: K) {
+        if self.hash_builder.hash_one(&key) % SAMPLE_MODULUS >= self.threshold {
+            return;
+        }
+        for &mut (_, ref mut cache) in &mut self.caches {
+            if let Entry::Vacant(entry) = cache.entry(key.clone()) {
+                entry.insert(());
+            }
+        }
+    }
+
+    /// Returns the estimated point on the curve for each size, in the order the sizes were given.
+    pub fn points(&self) -> Vec<Point> {
+        self.caches
+            .iter()
+            .map(|&(size, ref cache)| {
+                let stats = cache.stats();
+                Point {
+                    size,
+                    scaled_size: cache.max_recent + cache.max_frequent,
+                    accesses: stats.lookups(),
+                    misses: stats.misses,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Rounds a non-negative number to the nearest integer, without `std`'s float functions.
+fn round(x: f64) -> usize {
+    let whole = x as usize;
+    if x - whole as f64 >= 0.5 {
+        whole + 1
+    } else {
+        whole
+    }
+}
+
+/// Rounds a non-negative number up to an integer, without `std`'s float functions.
+fn ceil(x: f64) -> u64 {
+    let whole = x as u64;
+    if (whole as f64) < x {
+        whole + 1
+    } else {
+        whole
+    }
+}
+
+/// Estimates the miss ratio curve of `keys` at each of `sizes`.
+///
+/// This is a shorthand for feeding every key to a [`MissRatioCurve`].
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::mrc;
+///
+/// let keys = (0..1000).map(|i| i % 10);
+/// let points = mrc::miss_ratio_curve(keys, &[5, 20], 1.0);
+/// assert!(points[0].miss_ratio() > points[1].miss_ratio());
+/// ```
+///
+/// [`MissRatioCurve`]: struct.MissRatioCurve.html
+#[cfg(feature = "std")]
+pub fn miss_ratio_curve<K, I>(keys: I, sizes: &[usize], sample_rate: f64) -> Vec<Point>
+where
+    K: Hash + Eq + Clone,
+    I: IntoIterator<Item = K>,
+{
+    let mut curve = MissRatioCurve::new(sizes, sample_rate);
+    for key in keys {
+        curve.access(key);
+    }
+    curve.points()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::RandomState;
+
+    #[cfg(feature = "std")]
+    use super::miss_ratio_curve;
+    use super::MissRatioCurve;
+    use Cache;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn exact_when_not_sampled() {
+        let keys: Vec<u32> = (0..5000).map(|i| (i * 7919) % 300).collect();
+        let sizes = [50, 200, 400];
+        let points = miss_ratio_curve(keys.iter().cloned(), &sizes, 1.0);
+        for (&size, point) in sizes.iter().zip(points) {
+            let mut cache = Cache::new(size);
+            for &key in &keys {
+                cache.entry(key).or_insert(());
+            }
+            assert_eq!(point.misses, cache.stats().misses);
+            assert_eq!(point.scaled_size, size);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn sampling_scales_caches() {
+        let keys = (0..100_000u32).map(|i| i % 2000);
+        let points = miss_ratio_curve(keys, &[1000, 4000], 0.1);
+        assert_eq!(points[0].scaled_size, 100);
+        assert_eq!(points[1].scaled_size, 400);
+        assert!(points[0].accesses > 0 && points[0].accesses < 100_000);
+        // a cache larger than the working set only takes compulsory misses
+        assert!(points[1].miss_ratio() < 0.05);
+    }
+
+    #[test]
+    fn any_hasher_is_exact_when_not_sampled() {
+        let keys: Vec<u32> = (0..5000).map(|i| (i * 7919) % 300).collect();
+        let sizes = [50, 400];
+        let mut curve = MissRatioCurve::with_hasher(&sizes, 1.0, RandomState::new());
+        for &key in &keys {
+            curve.access(key);
+        }
+        for (&size, point) in sizes.iter().zip(curve.points()) {
+            let mut cache = Cache::new(size);
+            for &key in &keys {
+                cache.entry(key).or_insert(());
+            }
+            assert_eq!(point.misses, cache.stats().misses);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! A cache which also remembers keys known to be absent
+
+use core::borrow::Borrow;
+
+use {Cache, Entry, OccupiedKind, VacantEntry};
+
+/// The result of looking up a key in a [`NegativeCache`].
+///
+/// [`NegativeCache`]: struct.NegativeCache.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Lookup<T> {
+    /// The key is cached with a value
+    Hit(T),
+    /// The key is cached as known to be absent
+    KnownAbsent,
+    /// Nothing is cached for the key
+    Miss,
+}
+
+impl<T> Lookup<T> {
+    /// Returns the value of a hit, or `None` otherwise.
+    pub fn hit(self) -> Option<T> {
+        match self {
+            Lookup::Hit(value) => Some(value),
+            Lookup::KnownAbsent | Lookup::Miss => None,
+        }
+    }
+
+    /// Returns true if nothing was cached for the key, so the backing source must be consulted.
+    pub fn is_miss(&self) -> bool {
+        match *self {
+            Lookup::Miss => true,
+            Lookup::Hit(_) | Lookup::KnownAbsent => false,
+        }
+    }
+}
+
+impl<T> From<Option<T>> for Lookup<T> {
+    /// Converts the result of a lookup in a backing source: `None` means the key is known to be
+    /// absent.
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => Lookup::Hit(value),
+            None => Lookup::KnownAbsent,
+        }
+    }
+}
+
+/// A 2Q cache which can also remember that keys are absent from a backing source.
+///
+/// Negative entries, inserted with [`insert_absent`], move through the recent, ghost and frequent
+/// lists just like entries with values, so a key which is repeatedly looked up but never found
+/// stays cached as absent. Negative entries count against the size of the cache, and at most
+/// `max_absent` of them are kept at once: past that, the oldest negative entry is evicted.
+///
+/// Lookups which find a negative entry are counted as hits in the [`stats`] of the cache.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::{Lookup, NegativeCache};
+///
+/// let mut cache = NegativeCache::new(64, 16);
+/// cache.insert("apple", 3);
+/// cache.insert_absent("durian");
+///
+/// assert_eq!(cache.get("apple"), Lookup::Hit(&3));
+/// assert_eq!(cache.get("durian"), Lookup::KnownAbsent);
+/// assert_eq!(cache.get("banana"), Lookup::Miss);
+/// ```
+///
+/// [`insert_absent`]: struct.NegativeCache.html#method.insert_absent
+/// [`stats`]: struct.Cache.html#method.stats
+#[derive(Debug, Clone)]
+pub struct NegativeCache<K, V> {
+    cache: Cache<K, Option<V>>,
+    absent: usize,
+    max_absent: usize,
+}
+
+impl<K: Eq, V> NegativeCache<K, V> {
+    /// Creates an empty cache with the specified size, which keeps at most `max_absent` negative
+    /// entries.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize, max_absent: usize) -> Self {
+        NegativeCache {
+            cache: Cache::new(size),
+            absent: 0,
+            max_absent,
+        }
+    }
+
+    /// Returns a reference to the underlying cache, in which negative entries have no value.
+    pub fn cache(&self) -> &Cache<K, Option<V>> {
+        &self.cache
+    }
+
+    /// Returns the maximum number of negative entries kept in the cache.
+    pub fn max_absent(&self) -> usize {
+        self.max_absent
+    }
+
+    /// Returns the number of negative entries currently in the cache.
+    pub fn absent_len(&self) -> usize {
+        self.absent
+    }
+
+    /// Returns the number of entries currently in the cache, including negative entries.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns true if the cache contains no entries, including negative entries.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Looks up the specified key, without moving it in the cache.
+    pub fn peek<Q>(&self, key: &Q) -> Lookup<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        lookup(self.cache.peek(key).map(Option::as_ref))
+    }
+
+    /// Looks up the specified key.
+    ///
+    /// Like [`Cache::get`], a key found in the frequent list is moved to its front.
+    ///
+    /// [`Cache::get`]: struct.Cache.html#method.get
+    pub fn get<Q>(&mut self, key: &Q) -> Lookup<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        lookup(self.cache.get(key).map(Option::as_ref))
+    }
+
+    /// Looks up the specified key, returning a mutable reference to its value on a hit.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Lookup<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        lookup(self.cache.get_mut(key).map(Option::as_mut))
+    }
+
+    /// Inserts a key-value pair into the cache, replacing a negative entry for the key if there
+    /// is one.
+    ///
+    /// If the cache had a value for this key, it is returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.cache.touch_entry(key) {
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(Some(value));
+                if old.is_none() {
+                    self.absent -= 1;
+                }
+                old
+            }
+            Entry::Vacant(entry) => {
+                insert_vacant(entry, Some(value), &mut self.absent);
+                None
+            }
+        }
+    }
+
+    /// Remembers that the key is absent from the backing source, replacing any value cached for
+    /// it.
+    ///
+    /// If the cache had a value for this key, it is returned. If this makes the cache hold more
+    /// than `max_absent` negative entries, the oldest negative entry is evicted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::{Lookup, NegativeCache};
+    ///
+    /// let mut cache = NegativeCache::new(64, 2);
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.insert_absent(1), Some("a"));
+    /// cache.insert_absent(2);
+    /// cache.insert_absent(3);
+    /// assert_eq!(cache.absent_len(), 2);
+    /// assert_eq!(cache.peek(&1), Lookup::Miss);
+    /// assert_eq!(cache.peek(&3), Lookup::KnownAbsent);
+    /// ```
+    pub fn insert_absent(&mut self, key: K) -> Option<V> {
+        let old = match self.cache.touch_entry(key) {
+            Entry::Occupied(mut entry) => match entry.insert(None) {
+                None => return None,
+                old => old,
+            },
+            Entry::Vacant(entry) => {
+                insert_vacant(entry, None, &mut self.absent);
+                None
+            }
+        };
+        self.absent += 1;
+        while self.absent > self.max_absent {
+            let cache = &self.cache;
+            let victim = cache
+                .recent
+                .iter()
+                .rposition(|entry| entry.value.is_none())
+                .map(OccupiedKind::Recent)
+                .or_else(|| {
+                    cache
+                        .frequent
+                        .iter()
+                        .rposition(|entry| entry.value.is_none())
+                        .map(OccupiedKind::Frequent)
+                });
+            match victim {
+                Some(victim) => self.cache.evict(victim, &mut |_, _, _| {}),
+                None => break,
+            }
+            self.absent -= 1;
+        }
+        old
+    }
+
+    /// Removes a key from the cache, returning its value on a hit.
+    pub fn remove<Q>(&mut self, key: &Q) -> Lookup<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let result = lookup(self.cache.remove(key));
+        if let Lookup::KnownAbsent = result {
+            self.absent -= 1;
+        }
+        result
+    }
+
+    /// Removes all entries from the cache, including negative entries.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.absent = 0;
+    }
+}
+
+/// Converts the result of a lookup in the underlying cache.
+fn lookup<T>(value: Option<Option<T>>) -> Lookup<T> {
+    value.map_or(Lookup::Miss, Lookup::from)
+}
+
+/// Inserts an entry into the underlying cache, accounting for negative entries it evicts.
+fn insert_vacant<K: Eq, V>(
+    entry: VacantEntry<'_, K, Option<V>>,
+    value: Option<V>,
+    absent: &mut usize,
+) {
+    entry.insert_entry_with(value, |_, _, value| {
+        if value.is_none() {
+            *absent -= 1;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lookup, NegativeCache};
+
+    #[test]
+    fn evicted_negative_entries_are_counted() {
+        let mut cache = NegativeCache::new(8, 8);
+        for i in 0..20 {
+            cache.insert_absent(i);
+        }
+        assert_eq!(cache.absent_len(), cache.len());
+        for i in 20..40 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.absent_len(), 0);
+        assert_eq!(cache.peek(&0), Lookup::Miss);
+    }
+
+    #[test]
+    fn values_replace_negative_entries() {
+        let mut cache = NegativeCache::new(8, 1);
+        assert_eq!(cache.insert_absent(1), None);
+        assert_eq!(cache.insert_absent(1), None);
+        assert_eq!(cache.absent_len(), 1);
+        assert_eq!(cache.insert(1, "a"), None);
+        assert_eq!(cache.absent_len(), 0);
+        assert_eq!(cache.get_mut(&1), Lookup::Hit(&mut "a"));
+        cache.insert_absent(1);
+        assert_eq!(cache.remove(&1), Lookup::KnownAbsent);
+        assert_eq!(cache.remove(&1), Lookup::Miss);
+        assert_eq!(cache.absent_len(), 0);
+    }
+
+    #[test]
+    fn negative_entries_are_promoted() {
+        let mut cache = NegativeCache::<u32, u32>::new(8, 4);
+        cache.insert_absent(1);
+        // push 1 out of recent and into ghost
+        cache.insert_absent(2);
+        cache.insert_absent(3);
+        assert_eq!(cache.peek(&1), Lookup::Miss);
+        cache.insert_absent(1);
+        assert!(cache.cache().frequent.iter().any(|entry| entry.key == 1));
+        for i in 10..20 {
+            cache.insert_absent(i);
+        }
+        assert_eq!(cache.peek(&1), Lookup::KnownAbsent);
+        assert_eq!(cache.absent_len(), cache.len());
+    }
+}
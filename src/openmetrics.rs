@@ -0,0 +1,348 @@
+//! Exporting cache statistics in the OpenMetrics text format
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use {Cache, HeapSize, MemoryBoundedCache, Stats};
+
+/// The statistics and sizes of caches, rendered in the [OpenMetrics] text format which
+/// Prometheus scrapes.
+///
+/// Every metric name starts with a configurable prefix, and each cache added is told apart by
+/// its own labels, so several named caches can be exported together. The metrics are:
+///
+/// * `<prefix>_hits_total`, the lookups which found their key, with a `segment` label of
+///   `recent` or `frequent`
+/// * `<prefix>_misses_total`, the lookups which did not find their key
+/// * `<prefix>_ghost_hits_total`, the entries inserted into frequent because their key was
+///   remembered in ghost
+/// * `<prefix>_evictions_total`, with a `segment` label of `recent`, `frequent` or `ghost`
+/// * `<prefix>_entries`, the number of entries in each segment, or of keys in ghost
+/// * `<prefix>_max_entries`, the number each segment holds when the cache is full
+/// * `<prefix>_memory_bytes` and `<prefix>_max_memory_bytes`, the memory used by each
+///   [`MemoryBoundedCache`] and its budget
+///
+/// The text ends with the `# EOF` marker, so it is a complete exposition: render every cache of
+/// a process with one `OpenMetrics`.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::{Cache, OpenMetrics};
+///
+/// let mut users = Cache::new(8);
+/// users.insert("alice", 1);
+/// assert_eq!(users.get("alice"), Some(&1));
+/// let sessions: Cache<u64, String> = Cache::new(64);
+///
+/// let mut metrics = OpenMetrics::new("app_cache");
+/// metrics.cache(&users, &[("cache", "users")]);
+/// metrics.cache(&sessions, &[("cache", "sessions")]);
+/// let text = metrics.to_string();
+/// assert!(text.contains("app_cache_hits_total{cache=\"users\",segment=\"recent\"} 1\n"));
+/// assert!(text.contains("app_cache_max_entries{cache=\"sessions\",segment=\"frequent\"} 48\n"));
+/// assert!(text.ends_with("# EOF\n"));
+/// ```
+///
+/// [OpenMetrics]: https://openmetrics.io
+/// [`MemoryBoundedCache`]: struct.MemoryBoundedCache.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenMetrics {
+    prefix: String,
+    samples: Vec<Sample>,
+}
+
+/// What is exported of one cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sample {
+    /// The labels of the cache, rendered and separated by commas
+    labels: String,
+    stats: Stats,
+    /// The length and maximum length of recent, frequent and ghost
+    segments: [(usize, usize); 3],
+    /// The memory usage and budget of a memory bounded cache
+    memory: Option<(usize, usize)>,
+}
+
+const SEGMENTS: [&str; 3] = ["recent", "frequent", "ghost"];
+
+impl OpenMetrics {
+    /// Creates an empty exposition, whose metric names start with `prefix`.
+    ///
+    /// The prefix should be a valid metric name, such as `myapp_cache`.
+    pub fn new(prefix: &str) -> Self {
+        OpenMetrics {
+            prefix: prefix.into(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds the statistics and segment sizes of `cache`, with the specified label names and
+    /// values. Label values are escaped as needed.
+    ///
+    /// Every cache added should have a distinct set of labels, and none of them may be named
+    /// `segment`.
+    pub fn cache<K: Eq, V>(&mut self, cache: &Cache<K, V>, labels: &[(&str, &str)]) -> &mut Self {
+        self.samples.push(Sample {
+            labels: render_labels(labels),
+            stats: cache.stats(),
+            segments: [
+                (cache.recent.len() - cache.stale_recent, cache.max_recent),
+                (
+                    cache.frequent.len() - cache.stale_frequent,
+                    cache.max_frequent,
+                ),
+                (cache.ghost.len(), cache.max_ghost),
+            ],
+            memory: None,
+        });
+        self
+    }
+
+    /// Like [`cache`], but also adds the memory usage and budget of `cache`.
+    ///
+    /// [`cache`]: struct.OpenMetrics.html#method.cache
+    pub fn memory_bounded<K, V>(
+        &mut self,
+        cache: &MemoryBoundedCache<K, V>,
+        labels: &[(&str, &str)],
+    ) -> &mut Self
+    where
+        K: Eq + HeapSize,
+        V: HeapSize,
+    {
+        self.cache(cache.cache(), labels);
+        if let Some(sample) = self.samples.last_mut() {
+            sample.memory = Some((cache.memory_usage(), cache.max_bytes()));
+        }
+        self
+    }
+
+    /// Writes one metric family: its metadata, then a value per cache, or per cache and
+    /// segment. Families without any value are left out.
+    fn family(&self, f: &mut fmt::Formatter<'_>, family: &Family) -> fmt::Result {
+        let count = if family.segments { SEGMENTS.len() } else { 1 };
+        let values =
+            |sample| (0..count).filter_map(move |i| (family.value)(sample, i).map(|v| (i, v)));
+        if self
+            .samples
+            .iter()
+            .all(|sample| values(sample).next().is_none())
+        {
+            return Ok(());
+        }
+        writeln!(f, "# TYPE {}_{} {}", self.prefix, family.name, family.kind)?;
+        if family.name.ends_with("_bytes") {
+            writeln!(f, "# UNIT {}_{} bytes", self.prefix, family.name)?;
+        }
+        writeln!(f, "# HELP {}_{} {}", self.prefix, family.name, family.help)?;
+        let suffix = if family.kind == "counter" {
+            "_total"
+        } else {
+            ""
+        };
+        for sample in &self.samples {
+            for (segment, value) in values(sample) {
+                let mut labels = sample.labels.clone();
+                if family.segments {
+                    if !labels.is_empty() {
+                        labels.push(',');
+                    }
+                    let _ = write!(labels, "segment=\"{}\"", SEGMENTS[segment]);
+                }
+                write!(f, "{}_{}{}", self.prefix, family.name, suffix)?;
+                if !labels.is_empty() {
+                    write!(f, "{{{}}}", labels)?;
+                }
+                writeln!(f, " {}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OpenMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for family in &FAMILIES {
+            self.family(f, family)?;
+        }
+        writeln!(f, "# EOF")
+    }
+}
+
+/// A metric family.
+struct Family {
+    /// `counter` or `gauge`
+    kind: &'static str,
+    /// The name of the family, after the prefix
+    name: &'static str,
+    help: &'static str,
+    /// Whether the family has a value per segment, labelled by the segment's name
+    segments: bool,
+    /// Returns the value of a cache, for the segment at the specified index of `SEGMENTS`
+    value: fn(&Sample, usize) -> Option<u64>,
+}
+
+const FAMILIES: [Family; 8] = [
+    Family {
+        kind: "counter",
+        name: "hits",
+        help: "Lookups which found their key",
+        segments: true,
+        value: |s, i| [Some(s.stats.recent_hits), Some(s.stats.frequent_hits), None][i],
+    },
+    Family {
+        kind: "counter",
+        name: "misses",
+        help: "Lookups which did not find their key",
+        segments: false,
+        value: |s, _| Some(s.stats.misses),
+    },
+    Family {
+        kind: "counter",
+        name: "ghost_hits",
+        help: "Entries inserted into frequent because their key was remembered in ghost",
+        segments: false,
+        value: |s, _| Some(s.stats.ghost_hits),
+    },
+    Family {
+        kind: "counter",
+        name: "evictions",
+        help: "Entries evicted, or keys forgotten by ghost",
+        segments: true,
+        value: |s, i| {
+            let stats = &s.stats;
+            Some(
+                [
+                    stats.recent_evictions,
+                    stats.frequent_evictions,
+                    stats.ghost_evictions,
+                ][i],
+            )
+        },
+    },
+    Family {
+        kind: "gauge",
+        name: "entries",
+        help: "Entries in each segment, or keys in ghost",
+        segments: true,
+        value: |s, i| Some(s.segments[i].0 as u64),
+    },
+    Family {
+        kind: "gauge",
+        name: "max_entries",
+        help: "Entries each segment holds when full",
+        segments: true,
+        value: |s, i| Some(s.segments[i].1 as u64),
+    },
+    Family {
+        kind: "gauge",
+        name: "memory_bytes",
+        help: "Memory used by the cache",
+        segments: false,
+        value: |s, _| s.memory.map(|(usage, _)| usage as u64),
+    },
+    Family {
+        kind: "gauge",
+        name: "max_memory_bytes",
+        help: "Memory the cache may use",
+        segments: false,
+        value: |s, _| s.memory.map(|(_, max)| max as u64),
+    },
+];
+
+/// Renders label names and values as they appear between the braces of a sample.
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+    for (i, &(name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            rendered.push(',');
+        }
+        rendered.push_str(name);
+        rendered.push_str("=\"");
+        for c in value.chars() {
+            match c {
+                '\\' => rendered.push_str("\\\\"),
+                '"' => rendered.push_str("\\\""),
+                '\n' => rendered.push_str("\\n"),
+                c => rendered.push(c),
+            }
+        }
+        rendered.push('"');
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpenMetrics;
+    use {Cache, MemoryBoundedCache};
+
+    #[test]
+    fn exposition() {
+        let mut cache = Cache::new(4);
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        cache.insert(2, 2);
+        assert_eq!(cache.get(&2), Some(&2));
+        assert_eq!(cache.get(&3), Some(&3));
+        assert_eq!(cache.get(&9), None);
+
+        let mut metrics = OpenMetrics::new("test_cache");
+        metrics.cache(&cache, &[("cache", "numbers")]);
+        let expected = "\
+# TYPE test_cache_hits counter
+# HELP test_cache_hits Lookups which found their key
+test_cache_hits_total{cache=\"numbers\",segment=\"recent\"} 1
+test_cache_hits_total{cache=\"numbers\",segment=\"frequent\"} 1
+# TYPE test_cache_misses counter
+# HELP test_cache_misses Lookups which did not find their key
+test_cache_misses_total{cache=\"numbers\"} 1
+# TYPE test_cache_ghost_hits counter
+# HELP test_cache_ghost_hits Entries inserted into frequent because their key was remembered in ghost
+test_cache_ghost_hits_total{cache=\"numbers\"} 1
+# TYPE test_cache_evictions counter
+# HELP test_cache_evictions Entries evicted, or keys forgotten by ghost
+test_cache_evictions_total{cache=\"numbers\",segment=\"recent\"} 3
+test_cache_evictions_total{cache=\"numbers\",segment=\"frequent\"} 0
+test_cache_evictions_total{cache=\"numbers\",segment=\"ghost\"} 1
+# TYPE test_cache_entries gauge
+# HELP test_cache_entries Entries in each segment, or keys in ghost
+test_cache_entries{cache=\"numbers\",segment=\"recent\"} 1
+test_cache_entries{cache=\"numbers\",segment=\"frequent\"} 1
+test_cache_entries{cache=\"numbers\",segment=\"ghost\"} 1
+# TYPE test_cache_max_entries gauge
+# HELP test_cache_max_entries Entries each segment holds when full
+test_cache_max_entries{cache=\"numbers\",segment=\"recent\"} 1
+test_cache_max_entries{cache=\"numbers\",segment=\"frequent\"} 3
+test_cache_max_entries{cache=\"numbers\",segment=\"ghost\"} 2
+# EOF
+";
+        assert_eq!(metrics.to_string(), expected);
+    }
+
+    #[test]
+    fn several_caches() {
+        let plain: Cache<u32, u32> = Cache::new(8);
+        let mut bounded = MemoryBoundedCache::new(8, 4096);
+        bounded.insert(1u32, vec![0u8; 100]);
+
+        let mut metrics = OpenMetrics::new("app");
+        metrics
+            .cache(&plain, &[])
+            .memory_bounded(&bounded, &[("cache", "blobs \"big\"\n"), ("tier", "1")]);
+        let text = metrics.to_string();
+        // each family appears once, with a sample per cache
+        assert_eq!(text.matches("# TYPE app_misses counter").count(), 1);
+        assert!(text.contains("\napp_misses_total 0\n"));
+        assert!(text.contains("\napp_misses_total{cache=\"blobs \\\"big\\\"\\n\",tier=\"1\"} 0\n"));
+        // only the memory bounded cache has a memory usage
+        assert!(text.contains("# UNIT app_memory_bytes bytes\n"));
+        assert_eq!(text.matches("\napp_memory_bytes").count(), 1);
+        let max = "\napp_max_memory_bytes{cache=\"blobs \\\"big\\\"\\n\",tier=\"1\"} 4096\n";
+        assert!(text.contains(max));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
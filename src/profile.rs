@@ -0,0 +1,241 @@
+//! Profiling the positions at which lookups find their keys
+
+use alloc::vec::Vec;
+use core::cmp;
+
+use OccupiedKind;
+
+/// Histograms of the positions at which a [`Cache`] found the keys it was asked for, from which
+/// its hit ratio at other sizes can be estimated without replaying the workload.
+///
+/// Positions count from the front of each list, so a hit at position `i` of recent or frequent
+/// would still have been a hit with room for `i + 1` entries in that list. A lookup which missed
+/// but found its key at position `i` of ghost means `i` other keys were evicted from recent
+/// after this one, so with room for `i + 1` more entries in recent it would have been a hit.
+///
+/// Profiling is started by [`Cache::start_profiling`]. Each histogram grows to the furthest
+/// position a hit was found at, so it uses up to 8 bytes per entry of its list.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::Cache;
+///
+/// let mut cache = Cache::new(16);
+/// cache.start_profiling();
+/// for _ in 0..10 {
+///     for i in 0..12 {
+///         cache.entry(i).or_insert(i);
+///     }
+/// }
+/// let profile = cache.profile().unwrap();
+/// assert_eq!(profile.lookups(), 120);
+/// // the estimate at the size of the cache itself is exact
+/// assert_eq!(profile.estimated_hits(16), cache.stats().hits());
+/// // the 12 keys don't fit in half the size, and a larger recent would have kept the keys
+/// // which were promoted from ghost
+/// assert!(profile.estimated_hit_ratio(8) < 0.2);
+/// assert!(profile.estimated_hit_ratio(32) > profile.estimated_hit_ratio(16));
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+/// [`Cache::start_profiling`]: struct.Cache.html#method.start_profiling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    max_recent: usize,
+    lookups: u64,
+    recent_hits: Vec<u64>,
+    frequent_hits: Vec<u64>,
+    ghost_hits: Vec<u64>,
+}
+
+impl Profile {
+    /// Creates an empty profile of a cache whose recent list holds `max_recent` entries.
+    pub(crate) fn new(max_recent: usize) -> Self {
+        Profile {
+            max_recent,
+            lookups: 0,
+            recent_hits: Vec::new(),
+            frequent_hits: Vec::new(),
+            ghost_hits: Vec::new(),
+        }
+    }
+
+    /// Records a lookup, and where it found its key, before the key was moved.
+    pub(crate) fn lookup(&mut self, found: Option<OccupiedKind>) {
+        self.lookups += 1;
+        match found {
+            Some(OccupiedKind::Recent(i)) => record(&mut self.recent_hits, i),
+            Some(OccupiedKind::Frequent(i)) => record(&mut self.frequent_hits, i),
+            None => {}
+        }
+    }
+
+    /// Records that the lookup which just missed found its key at position `i` of ghost.
+    pub(crate) fn ghost_miss(&mut self, i: usize) {
+        record(&mut self.ghost_hits, i);
+    }
+
+    /// Returns the number of lookups profiled.
+    pub fn lookups(&self) -> u64 {
+        self.lookups
+    }
+
+    /// Returns the number of lookups which found their key at each position of recent.
+    pub fn recent_hits(&self) -> &[u64] {
+        &self.recent_hits
+    }
+
+    /// Returns the number of lookups which found their key at each position of frequent.
+    pub fn frequent_hits(&self) -> &[u64] {
+        &self.frequent_hits
+    }
+
+    /// Returns the number of lookups which missed, but found their key remembered at each
+    /// position of ghost.
+    ///
+    /// Insertions of keys remembered in ghost which don't follow a lookup, such as by
+    /// [`Cache::insert`], aren't counted.
+    ///
+    /// [`Cache::insert`]: struct.Cache.html#method.insert
+    pub fn ghost_hits(&self) -> &[u64] {
+        &self.ghost_hits
+    }
+
+    /// Estimates the number of profiled lookups which would have found their key in a cache of
+    /// `size` entries.
+    ///
+    /// Hits beyond the end of a smaller cache's lists are taken away, and the ghost hits a
+    /// larger recent list would have kept are added. Keys the profiled cache no longer
+    /// remembered at all can't be accounted for, so the estimate for a larger cache is a lower
+    /// bound. It is never more than the number of lookups profiled.
+    pub fn estimated_hits(&self, size: usize) -> u64 {
+        let max_recent = cmp::max(1, size / 4);
+        let max_frequent = size.saturating_sub(max_recent);
+        let sum = |hits: &[u64], len: usize| hits.iter().take(len).sum::<u64>();
+        let hits = sum(&self.recent_hits, max_recent)
+            + sum(&self.frequent_hits, max_frequent)
+            + sum(&self.ghost_hits, max_recent.saturating_sub(self.max_recent));
+        cmp::min(hits, self.lookups)
+    }
+
+    /// Estimates the fraction of profiled lookups which would have found their key in a cache of
+    /// `size` entries, as [`estimated_hits`] does, or returns 0 if no lookups were profiled.
+    ///
+    /// [`estimated_hits`]: struct.Profile.html#method.estimated_hits
+    pub fn estimated_hit_ratio(&self, size: usize) -> f64 {
+        match self.lookups {
+            0 => 0.0,
+            lookups => self.estimated_hits(size) as f64 / lookups as f64,
+        }
+    }
+}
+
+/// Counts a hit at position `i` of a histogram, growing it as needed.
+fn record(hits: &mut Vec<u64>, i: usize) {
+    if hits.len() <= i {
+        hits.resize(i + 1, 0);
+    }
+    hits[i] += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use Cache;
+
+    #[test]
+    fn positions() {
+        // recent holds 2 entries, frequent 6, and ghost 4 keys
+        let mut cache = Cache::new(8);
+        cache.start_profiling();
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        // ghost holds 1 then 0
+        assert_eq!(cache.get(&2), Some(&2));
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&1), None);
+        // insertions aren't lookups, so their ghost hits aren't recorded
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.get(&7), None);
+        {
+            let profile = cache.profile().unwrap();
+            assert_eq!(profile.lookups(), 5);
+            assert_eq!(profile.recent_hits(), [0, 1]);
+            assert_eq!(profile.frequent_hits(), [0, 1]);
+            assert_eq!(profile.ghost_hits(), [1, 1]);
+            assert_eq!(profile.estimated_hits(100), 4);
+        }
+        // entry counts lookups too, and restarting clears the profile
+        cache.start_profiling();
+        cache.entry(0).or_insert(0);
+        cache.entry(9).or_insert(9);
+        let profile = cache.stop_profiling().unwrap();
+        assert_eq!(profile.lookups(), 2);
+        assert_eq!(profile.frequent_hits(), [1]);
+        assert!(profile.ghost_hits().is_empty());
+        assert_eq!(cache.profile(), None);
+    }
+
+    #[test]
+    fn estimates() {
+        let mut cache = Cache::new(8);
+        cache.start_profiling();
+        for _ in 0..100 {
+            for i in 0..4 {
+                cache.entry(i).or_insert(i);
+            }
+        }
+        let profile = cache.profile().unwrap();
+        let hits = cache.stats().hits();
+        assert_eq!(profile.estimated_hits(8), hits);
+        // 0 and 1 were promoted by ghost hits, and alternate in frequent, while 2 and 3 stay in
+        // recent, where 2 is found behind 3
+        assert_eq!(profile.ghost_hits(), [1, 1]);
+        assert_eq!(profile.frequent_hits(), [0, 196]);
+        assert_eq!(profile.recent_hits(), [99, 99]);
+        assert_eq!(profile.estimated_hits(4), hits - 99);
+        // with a larger recent, 0 and 1 would have been found there
+        assert_eq!(profile.estimated_hits(100), hits + 2);
+        assert_eq!(profile.estimated_hit_ratio(8), hits as f64 / 400.0);
+    }
+
+    #[test]
+    fn estimates_are_bounded_by_lookups() {
+        let mut cache = Cache::new(8);
+        cache.start_profiling();
+        // ghost hits of keys which are inserted without being looked up aren't hits
+        for _ in 0..10 {
+            for i in 0..6 {
+                cache.insert(i, i);
+            }
+        }
+        for i in 0..6 {
+            cache.entry(i).or_insert(i);
+        }
+        let profile = cache.profile().unwrap();
+        assert_eq!(profile.lookups(), 6);
+        for size in 1..64 {
+            assert!(profile.estimated_hits(size) <= 6);
+            assert!(profile.estimated_hit_ratio(size) <= 1.0);
+        }
+    }
+
+    #[test]
+    fn larger_recent() {
+        // a scan which recent is one entry too small for
+        let mut cache = Cache::new(4);
+        cache.start_profiling();
+        for _ in 0..10 {
+            cache.entry(0).or_insert(0);
+            cache.entry(1).or_insert(1);
+            cache.entry(2).or_insert(2);
+        }
+        let profile = cache.profile().unwrap();
+        // a cache with a recent list of 2 entries would have kept the ghost hits
+        assert!(profile.ghost_hits()[0] > 0);
+        assert!(profile.estimated_hits(8) >= profile.estimated_hits(4) + profile.ghost_hits()[0]);
+    }
+}
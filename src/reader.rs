@@ -0,0 +1,254 @@
+//! A block cache for `Read + Seek` sources
+
+use std::cmp;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+use {Cache, Entry};
+
+/// A reader which caches fixed-size blocks of an underlying `Read + Seek` source in a 2Q
+/// [`Cache`], keyed by block index.
+///
+/// Reads and seeks are served from cached blocks, and missing blocks are read from the
+/// underlying source on demand. Because new blocks only enter the recent list, a sequential scan
+/// through the source won't evict blocks which are read frequently.
+///
+/// The underlying source is assumed not to change while it is being read.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::{Cursor, Read, Seek, SeekFrom};
+/// use cache_2q::CachedReader;
+///
+/// let data: Vec<u8> = (0..=255).collect();
+/// let mut reader = CachedReader::new(Cursor::new(data), 16, 8);
+///
+/// let mut buf = [0; 4];
+/// reader.seek(SeekFrom::Start(30)).unwrap();
+/// reader.read_exact(&mut buf).unwrap();
+/// assert_eq!(buf, [30, 31, 32, 33]);
+///
+/// // the block containing bytes 16..32 is now cached
+/// reader.seek(SeekFrom::Start(20)).unwrap();
+/// reader.read_exact(&mut buf).unwrap();
+/// assert_eq!(buf, [20, 21, 22, 23]);
+/// assert_eq!(reader.cache().stats().hits(), 1);
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+#[derive(Debug)]
+pub struct CachedReader<R> {
+    inner: R,
+    cache: Cache<u64, Box<[u8]>>,
+    block_size: usize,
+    pos: u64,
+}
+
+impl<R: Read + Seek> CachedReader<R> {
+    /// Creates a reader over `inner`, which caches up to `blocks` blocks of `block_size` bytes.
+    ///
+    /// # Panics
+    /// panics if `block_size` or `blocks` is zero
+    pub fn new(inner: R, block_size: usize, blocks: usize) -> Self {
+        assert!(block_size > 0);
+        CachedReader {
+            inner,
+            cache: Cache::new(blocks),
+            block_size,
+            pos: 0,
+        }
+    }
+
+    /// Returns the size of the blocks the source is split into.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns a reference to the cache of blocks.
+    pub fn cache(&self) -> &Cache<u64, Box<[u8]>> {
+        &self.cache
+    }
+
+    /// Returns a reference to the underlying source.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwraps this reader, returning the underlying source.
+    ///
+    /// The position of the underlying source is unspecified.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the block at `index`, reading it from the source if it isn't cached.
+    ///
+    /// The last block of the source may be shorter than the block size, and blocks past the end
+    /// of the source are empty. Empty blocks aren't cached, so reading past the end doesn't
+    /// evict blocks holding data.
+    fn block(&mut self, index: u64) -> io::Result<&[u8]> {
+        match self.cache.entry(index) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                entry.trace_load();
+                let block = read_block(&mut self.inner, index, self.block_size)?;
+                if block.is_empty() {
+                    return Ok(&[]);
+                }
+                Ok(entry.insert(block))
+            }
+        }
+    }
+}
+
+/// Reads a single block from `inner`, stopping early at the end of the source.
+fn read_block<R>(inner: &mut R, index: u64, block_size: usize) -> io::Result<Box<[u8]>>
+where
+    R: Read + Seek,
+{
+    inner.seek(SeekFrom::Start(index * block_size as u64))?;
+    let mut block = Vec::with_capacity(block_size);
+    inner.take(block_size as u64).read_to_end(&mut block)?;
+    Ok(block.into_boxed_slice())
+}
+
+impl<R: Read + Seek> Read for CachedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let available = self.fill_buf()?;
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> BufRead for CachedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let block_size = self.block_size as u64;
+        let (index, offset) = (self.pos / block_size, (self.pos % block_size) as usize);
+        let block = self.block(index)?;
+        Ok(block.get(offset..).unwrap_or(&[]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl<R: Read + Seek> Seek for CachedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.inner.seek(SeekFrom::End(0))?, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+    use super::CachedReader;
+
+    /// Counts the reads made against the underlying source
+    #[derive(Debug)]
+    struct Counting {
+        inner: Cursor<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn reader(len: usize) -> CachedReader<Counting> {
+        let data = (0..len).map(|i| i as u8).collect();
+        let counting = Counting {
+            inner: Cursor::new(data),
+            reads: 0,
+        };
+        CachedReader::new(counting, 10, 4)
+    }
+
+    #[test]
+    fn reads_everything() {
+        let mut reader = reader(95);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, (0..95).map(|i| i as u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seeks() {
+        let mut reader = reader(95);
+        let mut buf = [0; 3];
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 92);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [92, 93, 94]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(reader.seek(SeekFrom::Current(-10)).unwrap(), 85);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [85, 86, 87]);
+        assert!(reader.seek(SeekFrom::Current(-100)).is_err());
+    }
+
+    #[test]
+    fn cached_blocks_are_not_reread() {
+        let mut reader = reader(95);
+        let mut buf = [0; 5];
+        for _ in 0..10 {
+            reader.seek(SeekFrom::Start(12)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [12, 13, 14, 15, 16]);
+        }
+        let reads = reader.get_ref().reads;
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert!(reader.get_ref().reads > reads);
+        assert_eq!(reader.cache().stats().misses, 2);
+    }
+
+    #[test]
+    fn empty_blocks_are_not_cached() {
+        let mut reader = reader(95);
+        let mut buf = [0; 5];
+        reader.seek(SeekFrom::Start(12)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        for pos in (100..200).step_by(10) {
+            reader.seek(SeekFrom::Start(pos)).unwrap();
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        }
+        assert_eq!(reader.cache().len(), 1);
+        let reads = reader.get_ref().reads;
+        reader.seek(SeekFrom::Start(12)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.get_ref().reads, reads);
+    }
+}
@@ -0,0 +1,686 @@
+//! Caching middleware for tower services
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+use core::fmt;
+
+use tower_layer::Layer;
+use tower_service::Service;
+
+use {Cache, Stats};
+
+/// The response to a request in flight, shared with the duplicate requests waiting for it.
+#[derive(Debug)]
+enum Flight<V> {
+    /// The request hasn't completed yet, and these are the wakers of the requests waiting for it
+    Pending(Vec<Waker>),
+    /// The request completed with this response, or `None` if it failed or was cancelled
+    Done(Option<V>),
+}
+
+type SharedFlight<V> = Arc<Mutex<Flight<V>>>;
+
+/// The cache shared by every clone of a [`CacheService`], and the requests in flight.
+///
+/// [`CacheService`]: struct.CacheService.html
+#[derive(Debug)]
+struct State<K, V> {
+    cache: Cache<K, V>,
+    in_flight: Vec<(K, SharedFlight<V>)>,
+}
+
+type SharedState<K, V> = Arc<Mutex<State<K, V>>>;
+
+impl<K: Eq, V> State<K, V> {
+    /// Returns the request in flight for `key`, if there is one.
+    fn flight(&self, key: &K) -> Option<SharedFlight<V>> {
+        self.in_flight
+            .iter()
+            .find(|(other, _)| other == key)
+            .map(|(_, flight)| flight.clone())
+    }
+
+    /// Records that a request for `key` is in flight.
+    fn take_off(&mut self, key: K) -> SharedFlight<V> {
+        let flight = Arc::new(Mutex::new(Flight::Pending(Vec::new())));
+        self.in_flight.push((key, flight.clone()));
+        flight
+    }
+}
+
+/// Locks a mutex. Nothing panics while the cache is locked, so a poisoned lock is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Completes a request in flight, waking every request waiting for it.
+fn land<K, V>(state: &Mutex<State<K, V>>, flight: &SharedFlight<V>, response: Option<V>) {
+    lock(state)
+        .in_flight
+        .retain(|(_, other)| !Arc::ptr_eq(other, flight));
+    let done = mem::replace(&mut *lock(flight), Flight::Done(response));
+    if let Flight::Pending(wakers) = done {
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// A [`Layer`] which wraps services in a [`CacheService`].
+///
+/// Every service made by the same layer shares its cache.
+///
+/// # Examples
+///
+/// ```
+/// extern crate tower_layer;
+/// extern crate tower_service;
+/// # extern crate cache_2q;
+///
+/// use std::future::{self, Future, Ready};
+/// use std::task::{Context, Poll, Waker};
+/// use cache_2q::CacheLayer;
+/// use tower_layer::Layer;
+/// use tower_service::Service;
+///
+/// #[derive(Debug, Clone)]
+/// struct Square;
+///
+/// impl Service<u64> for Square {
+///     type Response = u64;
+///     type Error = ();
+///     type Future = Ready<Result<u64, ()>>;
+///
+///     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+///         Poll::Ready(Ok(()))
+///     }
+///     fn call(&mut self, x: u64) -> Self::Future {
+///         future::ready(Ok(x * x))
+///     }
+/// }
+///
+/// let layer = CacheLayer::new(32, |&x: &u64| Some(x));
+/// let mut service = layer.layer(Square);
+/// let mut cx = Context::from_waker(Waker::noop());
+/// for _ in 0..2 {
+///     assert_eq!(service.poll_ready(&mut cx), Poll::Ready(Ok(())));
+///     let mut response = service.call(4);
+///     assert_eq!(Future::poll(std::pin::Pin::new(&mut response), &mut cx), Poll::Ready(Ok(16)));
+/// }
+/// assert_eq!(service.stats().hits(), 1);
+/// ```
+///
+/// [`Layer`]: https://docs.rs/tower-layer/0.3/tower_layer/trait.Layer.html
+/// [`CacheService`]: struct.CacheService.html
+pub struct CacheLayer<K, V, F> {
+    state: SharedState<K, V>,
+    key: F,
+}
+
+impl<K: Eq, V, F> CacheLayer<K, V, F> {
+    /// Creates a layer caching up to `size` responses, keyed by `key`, which derives the key
+    /// of a request, or returns `None` if its response mustn't be cached.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize, key: F) -> Self {
+        CacheLayer {
+            state: Arc::new(Mutex::new(State {
+                cache: Cache::new(size),
+                in_flight: Vec::new(),
+            })),
+            key,
+        }
+    }
+}
+
+impl<S, K, V, F: Clone> Layer<S> for CacheLayer<K, V, F> {
+    type Service = CacheService<S, K, V, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheService {
+            inner,
+            state: self.state.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<K, V, F: Clone> Clone for CacheLayer<K, V, F> {
+    fn clone(&self) -> Self {
+        CacheLayer {
+            state: self.state.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, F> fmt::Debug for CacheLayer<K, V, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheLayer")
+            .field("cache", &lock(&self.state).cache)
+            .finish()
+    }
+}
+
+/// A [`Service`] which caches the successful responses of an inner service in a 2Q [`Cache`].
+///
+/// The key of each request is derived by a closure, and requests without a key are always sent
+/// to the inner service. A request whose response is cached is answered from the cache. A
+/// request with the same key as one in flight waits for its response instead of being sent too.
+/// If that request fails or is cancelled, one of the requests waiting for it is sent in its
+/// place, and the others wait for that one.
+///
+/// Clones of the service share the cache, which is locked while looking up and inserting
+/// responses, but not while requests are in flight. The inner service is cloned to send
+/// requests which were waiting for another to complete, after polling it for readiness. A
+/// request answered without the inner service leaves the readiness reserved by
+/// [`poll_ready`] for the next request.
+///
+/// [`Service`]: https://docs.rs/tower-service/0.3/tower_service/trait.Service.html
+/// [`Cache`]: struct.Cache.html
+/// [`poll_ready`]: https://docs.rs/tower-service/0.3/tower_service/trait.Service.html#tymethod.poll_ready
+pub struct CacheService<S, K, V, F> {
+    inner: S,
+    state: SharedState<K, V>,
+    key: F,
+}
+
+impl<S, K: Eq, V, F> CacheService<S, K, V, F> {
+    /// Wraps `inner`, caching up to `size` responses, keyed by `key`, which derives the key of a
+    /// request, or returns `None` if its response mustn't be cached.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(inner: S, size: usize, key: F) -> Self {
+        let CacheLayer { state, key } = CacheLayer::new(size, key);
+        CacheService { inner, state, key }
+    }
+
+    /// Returns a reference to the inner service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner service.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the number of cached responses.
+    pub fn len(&self) -> usize {
+        lock(&self.state).cache.len()
+    }
+
+    /// Returns true if no responses are cached.
+    pub fn is_empty(&self) -> bool {
+        lock(&self.state).cache.is_empty()
+    }
+
+    /// Forgets every cached response. Requests in flight are still shared, and their responses
+    /// are cached when they complete.
+    pub fn clear(&self) {
+        lock(&self.state).cache.clear();
+    }
+
+    /// Returns the lookup statistics of the cache: a hit is a request answered from the cache,
+    /// and a miss is one which wasn't. Requests without a key aren't counted.
+    pub fn stats(&self) -> Stats {
+        lock(&self.state).cache.stats()
+    }
+}
+
+impl<S, Req, K, F> Service<Req> for CacheService<S, K, S::Response, F>
+where
+    S: Service<Req> + Clone,
+    S::Response: Clone,
+    K: Eq + Clone,
+    F: Fn(&Req) -> Option<K>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S, Req, K>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let key = match (self.key)(&request) {
+            Some(key) => key,
+            None => return ResponseFuture::sending(self.inner.call(request), None),
+        };
+        let mut state = lock(&self.state);
+        if let Some(response) = state.cache.get(&key) {
+            return ResponseFuture {
+                kind: Kind::Cached(Some(response.clone())),
+            };
+        }
+        if let Some(flight) = state.flight(&key) {
+            drop(state);
+            return ResponseFuture {
+                kind: Kind::Waiting {
+                    flight,
+                    inner: self.inner.clone(),
+                    request: Some(request),
+                    completion: Some(Completion {
+                        state: self.state.clone(),
+                        key: Some(key),
+                        flight: None,
+                    }),
+                },
+            };
+        }
+        let flight = state.take_off(key.clone());
+        drop(state);
+        let completion = Completion {
+            state: self.state.clone(),
+            key: Some(key),
+            flight: Some(flight),
+        };
+        ResponseFuture::sending(self.inner.call(request), Some(completion))
+    }
+}
+
+impl<S: Clone, K, V, F: Clone> Clone for CacheService<S, K, V, F> {
+    fn clone(&self) -> Self {
+        CacheService {
+            inner: self.inner.clone(),
+            state: self.state.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug, K: fmt::Debug, V: fmt::Debug, F> fmt::Debug for CacheService<S, K, V, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheService")
+            .field("inner", &self.inner)
+            .field("cache", &lock(&self.state).cache)
+            .finish()
+    }
+}
+
+/// Caches the response to a request when it completes, and shares it with the requests waiting
+/// for it. Dropping it before completion cancels the request in flight.
+struct Completion<K, V> {
+    state: SharedState<K, V>,
+    key: Option<K>,
+    /// The request in flight, if this request is the one the others wait for
+    flight: Option<SharedFlight<V>>,
+}
+
+impl<K: Eq, V: Clone> Completion<K, V> {
+    /// Caches the response if the request succeeded.
+    fn complete(mut self, response: Option<&V>) {
+        if let (Some(key), Some(response)) = (self.key.take(), response) {
+            lock(&self.state).cache.insert(key, response.clone());
+        }
+        if let Some(flight) = self.flight.take() {
+            land(&self.state, &flight, response.cloned());
+        }
+    }
+}
+
+impl<K, V> Drop for Completion<K, V> {
+    fn drop(&mut self) {
+        if let Some(flight) = self.flight.take() {
+            land(&self.state, &flight, None);
+        }
+    }
+}
+
+/// The states of a [`ResponseFuture`].
+///
+/// [`ResponseFuture`]: struct.ResponseFuture.html
+enum Kind<S: Service<Req>, Req, K> {
+    /// Answered from the cache
+    Cached(Option<S::Response>),
+    /// Sent to the inner service
+    Sending {
+        future: Pin<Box<S::Future>>,
+        completion: Option<Completion<K, S::Response>>,
+    },
+    /// Waiting for a request in flight with the same key
+    Waiting {
+        flight: SharedFlight<S::Response>,
+        inner: S,
+        request: Option<Req>,
+        completion: Option<Completion<K, S::Response>>,
+    },
+}
+
+/// The future returned by [`CacheService`], which resolves to the response to a request.
+///
+/// [`CacheService`]: struct.CacheService.html
+pub struct ResponseFuture<S: Service<Req>, Req, K> {
+    kind: Kind<S, Req, K>,
+}
+
+impl<S: Service<Req>, Req, K> ResponseFuture<S, Req, K> {
+    fn sending(future: S::Future, completion: Option<Completion<K, S::Response>>) -> Self {
+        ResponseFuture {
+            kind: Kind::Sending {
+                future: Box::pin(future),
+                completion,
+            },
+        }
+    }
+}
+
+// the inner future is boxed, and no other field is pinned
+impl<S: Service<Req>, Req, K> Unpin for ResponseFuture<S, Req, K> {}
+
+impl<S, Req, K> Future for ResponseFuture<S, Req, K>
+where
+    S: Service<Req>,
+    S::Response: Clone,
+    K: Eq + Clone,
+{
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            let next = match self.kind {
+                Kind::Cached(ref mut response) => {
+                    let response = response.take().expect("polled after completion");
+                    return Poll::Ready(Ok(response));
+                }
+                Kind::Sending {
+                    ref mut future,
+                    ref mut completion,
+                } => {
+                    let result = match future.as_mut().poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    if let Some(completion) = completion.take() {
+                        completion.complete(result.as_ref().ok());
+                    }
+                    return Poll::Ready(result);
+                }
+                Kind::Waiting {
+                    ref mut flight,
+                    ref mut inner,
+                    ref mut request,
+                    ref mut completion,
+                } => {
+                    match *lock(flight) {
+                        Flight::Pending(ref mut wakers) => {
+                            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                                wakers.push(cx.waker().clone());
+                            }
+                            return Poll::Pending;
+                        }
+                        Flight::Done(Some(ref response)) => {
+                            return Poll::Ready(Ok(response.clone()))
+                        }
+                        Flight::Done(None) => {}
+                    }
+                    // the request waited for failed, so this one is sent in its place, unless
+                    // another already was, or already succeeded
+                    match inner.poll_ready(cx) {
+                        Poll::Ready(Ok(())) => {}
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                    let mut sent = completion.take().expect("polled after completion");
+                    let shared = sent.state.clone();
+                    let mut state = lock(&shared);
+                    let key = sent
+                        .key
+                        .clone()
+                        .expect("the key is only taken on completion");
+                    if let Some(response) = state.cache.get(&key) {
+                        return Poll::Ready(Ok(response.clone()));
+                    }
+                    if let Some(other) = state.flight(&key) {
+                        *flight = other;
+                        *completion = Some(sent);
+                        continue;
+                    }
+                    sent.flight = Some(state.take_off(key));
+                    drop(state);
+                    let request = request.take().expect("polled after completion");
+                    ResponseFuture::sending(inner.call(request), Some(sent)).kind
+                }
+            };
+            self.kind = next;
+        }
+    }
+}
+
+impl<S: Service<Req>, Req, K> fmt::Debug for ResponseFuture<S, Req, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.kind {
+            Kind::Cached(_) => "Cached",
+            Kind::Sending { .. } => "Sending",
+            Kind::Waiting { .. } => "Waiting",
+        };
+        f.debug_struct("ResponseFuture")
+            .field("state", &state)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+
+    use tower_service::Service;
+
+    use super::CacheService;
+
+    /// Whether the responses of an [`Origin`] are released, and the wakers waiting for them.
+    #[derive(Debug, Default)]
+    struct Gate {
+        open: bool,
+        wakers: Vec<Waker>,
+    }
+
+    /// A stand-in service, which answers even numbers with themselves as a string, and fails
+    /// odd numbers, once its gate is open.
+    #[derive(Debug, Clone, Default)]
+    struct Origin {
+        calls: Arc<AtomicUsize>,
+        gate: Arc<Mutex<Gate>>,
+    }
+
+    impl Origin {
+        fn open(&self) {
+            let mut gate = self.gate.lock().unwrap();
+            gate.open = true;
+            gate.wakers.drain(..).for_each(Waker::wake);
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    struct Response {
+        gate: Arc<Mutex<Gate>>,
+        request: u32,
+    }
+
+    impl Future for Response {
+        type Output = Result<String, &'static str>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut gate = self.gate.lock().unwrap();
+            if !gate.open {
+                gate.wakers.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            Poll::Ready(if self.request.is_multiple_of(2) {
+                Ok(self.request.to_string())
+            } else {
+                Err("odd")
+            })
+        }
+    }
+
+    impl Service<u32> for Origin {
+        type Response = String;
+        type Error = &'static str;
+        type Future = Response;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), &'static str>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: u32) -> Response {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Response {
+                gate: self.gate.clone(),
+                request,
+            }
+        }
+    }
+
+    /// A waker which records whether it was woken.
+    #[derive(Debug, Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F, waker: &Waker) -> Poll<F::Output> {
+        Pin::new(future).poll(&mut Context::from_waker(waker))
+    }
+
+    type Cached = CacheService<Origin, u32, String, fn(&u32) -> Option<u32>>;
+
+    /// Requests below 100 are cached.
+    fn service(origin: &Origin) -> Cached {
+        fn key(&request: &u32) -> Option<u32> {
+            Some(request).filter(|&request| request < 100)
+        }
+        CacheService::new(origin.clone(), 8, key)
+    }
+
+    #[test]
+    fn successes_are_cached() {
+        let origin = Origin::default();
+        origin.open();
+        let mut service = service(&origin);
+        let waker = Waker::noop();
+        for _ in 0..3 {
+            assert_eq!(
+                poll(&mut service.call(2), waker),
+                Poll::Ready(Ok("2".to_string()))
+            );
+            assert_eq!(poll(&mut service.call(3), waker), Poll::Ready(Err("odd")));
+            assert_eq!(
+                poll(&mut service.call(200), waker),
+                Poll::Ready(Ok("200".to_string()))
+            );
+        }
+        assert_eq!(origin.calls(), 7);
+        assert_eq!(service.len(), 1);
+        assert_eq!(service.stats().hits(), 2);
+        assert_eq!(service.stats().misses, 4);
+    }
+
+    #[test]
+    fn duplicate_requests_are_coalesced() {
+        let origin = Origin::default();
+        let mut service = service(&origin);
+        let mut clone = service.clone();
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+
+        let mut first = service.call(4);
+        let mut second = clone.call(4);
+        assert_eq!(poll(&mut first, &waker), Poll::Pending);
+        assert_eq!(poll(&mut second, &waker), Poll::Pending);
+        assert_eq!(origin.calls(), 1);
+
+        origin.open();
+        flag.0.store(false, Ordering::SeqCst);
+        assert_eq!(
+            poll(&mut first, Waker::noop()),
+            Poll::Ready(Ok("4".to_string()))
+        );
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert_eq!(
+            poll(&mut second, Waker::noop()),
+            Poll::Ready(Ok("4".to_string()))
+        );
+        assert_eq!(
+            poll(&mut clone.call(4), Waker::noop()),
+            Poll::Ready(Ok("4".to_string()))
+        );
+        assert_eq!(origin.calls(), 1);
+    }
+
+    #[test]
+    fn waiting_requests_are_sent_when_the_first_fails() {
+        let origin = Origin::default();
+        let mut service = service(&origin);
+        let waker = Waker::noop();
+
+        let mut first = service.call(5);
+        let mut second = service.call(5);
+        assert_eq!(poll(&mut second, waker), Poll::Pending);
+        origin.open();
+        assert_eq!(poll(&mut first, waker), Poll::Ready(Err("odd")));
+        assert_eq!(poll(&mut second, waker), Poll::Ready(Err("odd")));
+        assert_eq!(origin.calls(), 2);
+        assert_eq!(poll(&mut service.call(5), waker), Poll::Ready(Err("odd")));
+        assert_eq!(origin.calls(), 3);
+        assert!(service.is_empty());
+    }
+
+    #[test]
+    fn waiting_requests_are_sent_when_the_first_is_cancelled() {
+        let origin = Origin::default();
+        let mut service = service(&origin);
+        let waker = Waker::noop();
+
+        let first = service.call(6);
+        let mut second = service.call(6);
+        assert_eq!(poll(&mut second, waker), Poll::Pending);
+        drop(first);
+        assert_eq!(poll(&mut second, waker), Poll::Pending);
+        assert_eq!(origin.calls(), 2);
+
+        // a new request waits for the one sent in place of the cancelled one
+        let mut third = service.call(6);
+        assert_eq!(poll(&mut third, waker), Poll::Pending);
+        origin.open();
+        assert_eq!(poll(&mut second, waker), Poll::Ready(Ok("6".to_string())));
+        assert_eq!(poll(&mut third, waker), Poll::Ready(Ok("6".to_string())));
+        assert_eq!(origin.calls(), 2);
+        assert_eq!(service.len(), 1);
+    }
+
+    #[test]
+    fn waiting_requests_share_the_response_sent_in_place_of_the_first() {
+        let origin = Origin::default();
+        let mut service = service(&origin);
+        let waker = Waker::noop();
+
+        let first = service.call(8);
+        let mut second = service.call(8);
+        let mut third = service.call(8);
+        assert_eq!(poll(&mut second, waker), Poll::Pending);
+        assert_eq!(poll(&mut third, waker), Poll::Pending);
+        drop(first);
+        // the second is sent in place of the first, and completes before the third is polled
+        assert_eq!(poll(&mut second, waker), Poll::Pending);
+        origin.open();
+        assert_eq!(poll(&mut second, waker), Poll::Ready(Ok("8".to_string())));
+        assert_eq!(poll(&mut third, waker), Poll::Ready(Ok("8".to_string())));
+        assert_eq!(origin.calls(), 2);
+    }
+}
@@ -0,0 +1,299 @@
+//! A cache whose entries can be invalidated by tag
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::cmp;
+
+use {Cache, Entry, Segment, Stats};
+
+#[derive(Debug, Clone)]
+struct Tagged<V, T> {
+    value: V,
+    tags: Vec<T>,
+}
+
+/// A 2Q cache whose entries can be labelled with tags when they are inserted, so every entry
+/// with a tag can be invalidated at once.
+///
+/// Tags are kept alongside each entry, and invalidating a tag scans the whole cache, so this is
+/// suited to entries with a few tags each, such as the tenant or table they were derived from.
+/// The tags of entries evicted into ghost are kept too, so invalidating a tag can also forget
+/// the keys in ghost which had it.
+///
+/// # Examples
+///
+/// ```
+/// use cache_2q::TaggedCache;
+///
+/// let mut cache = TaggedCache::new(32);
+/// cache.insert_tagged("alice/profile", 1, vec!["tenant:alice"]);
+/// cache.insert_tagged("alice/settings", 2, vec!["tenant:alice", "settings"]);
+/// cache.insert_tagged("bob/settings", 3, vec!["tenant:bob", "settings"]);
+///
+/// assert_eq!(cache.invalidate_tag(&"tenant:alice", true), 2);
+/// assert_eq!(cache.peek(&"alice/profile"), None);
+/// assert_eq!(cache.peek(&"bob/settings"), Some(&3));
+/// ```
+#[derive(Debug, Clone)]
+pub struct TaggedCache<K, V, T> {
+    cache: Cache<K, Tagged<V, T>>,
+    /// The tags of keys evicted into ghost, newest first. Keys which have since left ghost are
+    /// only dropped now and then, so a key may appear more than once.
+    ghost_tags: VecDeque<(K, Vec<T>)>,
+}
+
+impl<K: Eq, V, T> TaggedCache<K, V, T> {
+    /// Creates an empty cache with the specified size.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize) -> Self {
+        TaggedCache {
+            cache: Cache::new(size),
+            ghost_tags: VecDeque::new(),
+        }
+    }
+
+    /// Returns true if the cache contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.contains_key(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key, without moving it in the
+    /// cache.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.peek(key).map(|entry| &entry.value)
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.get(key).map(|entry| &entry.value)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    /// Returns the tags of the entry for the specified key.
+    pub fn tags<Q>(&self, key: &Q) -> Option<&[T]>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.peek(key).map(|entry| &entry.tags[..])
+    }
+
+    /// Inserts a key-value pair into the cache, without any tags.
+    ///
+    /// If the cache had this key present, its value and tags are replaced, and the old value is
+    /// returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V>
+    where
+        K: Clone,
+    {
+        self.insert_tagged(key, value, Vec::new())
+    }
+
+    /// Inserts a key-value pair into the cache, labelled with `tags`.
+    ///
+    /// If the cache had this key present, its value and tags are replaced, and the old value is
+    /// returned.
+    pub fn insert_tagged<I>(&mut self, key: K, value: V, tags: I) -> Option<V>
+    where
+        K: Clone,
+        I: IntoIterator<Item = T>,
+    {
+        let entry = Tagged {
+            value,
+            tags: tags.into_iter().collect(),
+        };
+        let ghost_tags = &mut self.ghost_tags;
+        match self.cache.touch_entry(key) {
+            Entry::Occupied(mut occupied) => return Some(occupied.insert(entry).value),
+            Entry::Vacant(vacant) => {
+                vacant.insert_entry_with(entry, |segment, key, evicted| {
+                    if segment == Segment::Recent {
+                        ghost_tags.push_front((key.clone(), evicted.tags));
+                    }
+                });
+            }
+        }
+        if self.ghost_tags.len() > 2 * cmp::max(1, self.cache.max_ghost) {
+            self.forget_ghost_tags();
+        }
+        None
+    }
+
+    /// Removes every entry labelled with `tag`, and returns how many were removed.
+    ///
+    /// If `purge_ghost` is true, the keys in ghost whose entries had the tag when they were
+    /// evicted are forgotten too, so they are inserted into recent like new keys when they come
+    /// back, rather than straight into frequent.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cache_2q::TaggedCache;
+    ///
+    /// let mut cache = TaggedCache::new(4);
+    /// cache.insert_tagged(1, "a", vec!["x"]);
+    /// // recent only holds a single entry in a cache of size 4, so 1 is evicted into ghost
+    /// cache.insert_tagged(2, "b", vec!["y"]);
+    /// assert_eq!(cache.invalidate_tag(&"x", true), 0);
+    ///
+    /// cache.insert_tagged(1, "c", vec!["x"]);
+    /// assert_eq!(cache.stats().ghost_hits, 0);
+    /// ```
+    pub fn invalidate_tag<Q>(&mut self, tag: &Q, purge_ghost: bool) -> usize
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        if purge_ghost {
+            let ghost_tags = &self.ghost_tags;
+            self.cache.purge_ghost_where(|key| {
+                // the newest tags of a key are the ones it was last evicted with
+                ghost_tags
+                    .iter()
+                    .find(|(other, _)| other == key)
+                    .is_some_and(|(_, tags)| tags.iter().any(|t| t.borrow() == tag))
+            });
+            self.forget_ghost_tags();
+        }
+        self.cache
+            .invalidate_where(|_, entry| entry.tags.iter().any(|t| t.borrow() == tag))
+    }
+
+    /// Drops the tags of keys which are no longer in ghost, and older tags of keys which are.
+    fn forget_ghost_tags(&mut self) {
+        let mut i = 0;
+        while i < self.ghost_tags.len() {
+            let key = &self.ghost_tags[i].0;
+            let newer = self.ghost_tags.iter().take(i).any(|(other, _)| other == key);
+            if newer || !self.cache.ghost.contains(key) {
+                self.ghost_tags.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Removes every entry for which `f` returns true, and returns how many were removed.
+    ///
+    /// See [`Cache::invalidate_where`].
+    ///
+    /// [`Cache::invalidate_where`]: struct.Cache.html#method.invalidate_where
+    pub fn invalidate_where<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.cache
+            .invalidate_where(|key, entry| f(key, &entry.value))
+    }
+
+    /// Forgets every key in ghost for which `f` returns true, and returns how many were
+    /// forgotten.
+    ///
+    /// Ghost keys are matched by key here, see [`invalidate_tag`] to forget the keys whose
+    /// entries had a tag. See also [`Cache::purge_ghost_where`].
+    ///
+    /// [`invalidate_tag`]: struct.TaggedCache.html#method.invalidate_tag
+    /// [`Cache::purge_ghost_where`]: struct.Cache.html#method.purge_ghost_where
+    pub fn purge_ghost_where<F>(&mut self, f: F) -> usize
+    where
+        F: FnMut(&K) -> bool,
+    {
+        self.cache.purge_ghost_where(f)
+    }
+
+    /// Removes a key from the cache, returning the value associated with the key if the key
+    /// was previously in the cache.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.remove(key).map(|entry| entry.value)
+    }
+
+    /// Returns the number of entries currenly in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Clears the cache, removing all key-value pairs.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.ghost_tags.clear();
+    }
+
+    /// Returns the lookup statistics of the cache.
+    pub fn stats(&self) -> Stats {
+        self.cache.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TaggedCache;
+
+    #[test]
+    fn retagging_replaces_tags() {
+        let mut cache = TaggedCache::new(8);
+        cache.insert_tagged(1, "a", vec!["x", "y"]);
+        assert_eq!(cache.insert_tagged(1, "b", vec!["y"]), Some("a"));
+        assert_eq!(cache.tags(&1), Some(&["y"][..]));
+        assert_eq!(cache.invalidate_tag("x", false), 0);
+        assert_eq!(cache.invalidate_tag("y", false), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn invalidating_a_tag_purges_ghost() {
+        // recent holds 2 entries, so 1, 2 and 3 are evicted into ghost, with their tags
+        let mut cache = TaggedCache::new(8);
+        cache.insert_tagged(1, "a", vec!["x"]);
+        cache.insert_tagged(2, "b", vec!["y"]);
+        cache.insert_tagged(3, "c", vec!["x", "y"]);
+        cache.insert_tagged(4, "d", vec!["x"]);
+        cache.insert_tagged(5, "e", vec!["y"]);
+        assert_eq!(cache.invalidate_tag("x", true), 1);
+        assert!(!cache.contains_key(&4));
+
+        // keys which had the tag come back as new keys, others as ghost hits
+        cache.insert_tagged(1, "a", vec!["x"]);
+        cache.insert_tagged(3, "c", vec!["x"]);
+        assert_eq!(cache.stats().ghost_hits, 0);
+        cache.insert_tagged(2, "b", vec!["y"]);
+        assert_eq!(cache.stats().ghost_hits, 1);
+
+        // without purging ghost, 1 is evicted again and comes back as a ghost hit
+        cache.insert_tagged(6, "f", vec![]);
+        assert_eq!(cache.invalidate_tag("x", false), 1);
+        cache.insert_tagged(1, "a", vec![]);
+        assert_eq!(cache.stats().ghost_hits, 2);
+    }
+}
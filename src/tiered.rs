@@ -0,0 +1,446 @@
+//! A two-tier cache, which spills entries evicted from memory to a bounded store on local disk
+
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use {Cache, Entry, Segment, VacantEntry};
+
+/// Converts values to and from the bytes stored on disk by a [`TieredCache`].
+///
+/// [`TieredCache`]: struct.TieredCache.html
+pub trait Codec<V> {
+    /// Serializes `value` to bytes.
+    fn encode(&self, value: &V) -> io::Result<Vec<u8>>;
+
+    /// Deserializes a value from bytes produced by [`encode`].
+    ///
+    /// [`encode`]: trait.Codec.html#tymethod.encode
+    fn decode(&self, bytes: Vec<u8>) -> io::Result<V>;
+}
+
+/// The location and size of a value stored on disk
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct DiskEntry {
+    id: u64,
+    len: u64,
+}
+
+/// A bounded store of values in files in a local directory, which is the second tier of a
+/// [`TieredCache`].
+///
+/// Keys are kept in memory, and the store is bounded both by the number of values, and by the
+/// total size of the values in bytes. When it is full, the values which were spilled to disk
+/// longest ago are evicted first. Because reading a value moves it back into memory, this is also
+/// the least recently used value.
+///
+/// Every store must have a directory of its own. Files the store creates are deleted when they are
+/// evicted, and when the store is dropped.
+///
+/// [`TieredCache`]: struct.TieredCache.html
+#[derive(Debug)]
+pub struct DiskTier<K> {
+    dir: PathBuf,
+    entries: VecDeque<(K, DiskEntry)>,
+    max_entries: usize,
+    bytes: u64,
+    max_bytes: u64,
+    next_id: u64,
+}
+
+impl<K: Eq> DiskTier<K> {
+    /// Creates an empty store in `dir`, holding at most `entries` values totalling at most
+    /// `max_bytes` bytes. `dir` is created if it doesn't exist.
+    ///
+    /// # Errors
+    /// Returns an error if `dir` could not be created.
+    ///
+    /// # Panics
+    /// panics if `entries` is zero
+    pub fn new<P: Into<PathBuf>>(dir: P, entries: usize, max_bytes: u64) -> io::Result<Self> {
+        assert!(entries > 0);
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(DiskTier {
+            dir,
+            entries: VecDeque::new(),
+            max_entries: entries,
+            bytes: 0,
+            max_bytes,
+            next_id: 0,
+        })
+    }
+
+    /// Returns the directory values are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the number of values currently on disk.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if there are no values on disk.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the total size in bytes of the values currently on disk.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns true if there is a value on disk for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.entries.iter().any(|(k, _)| k.borrow() == key)
+    }
+
+    /// Stores `bytes` for `key`, evicting other values as needed to stay within bounds.
+    ///
+    /// Values larger than the whole store are not stored.
+    fn insert(&mut self, key: K, bytes: &[u8]) -> io::Result<()> {
+        self.take(&key)?;
+        let len = bytes.len() as u64;
+        if len > self.max_bytes {
+            return Ok(());
+        }
+        while self.entries.len() >= self.max_entries || self.bytes + len > self.max_bytes {
+            let (_, old) = self.entries.pop_back().unwrap();
+            self.delete(old)?;
+        }
+
+        let entry = DiskEntry {
+            id: self.next_id,
+            len,
+        };
+        self.next_id += 1;
+        fs::write(self.path(entry.id), bytes)?;
+        self.bytes += len;
+        self.entries.push_front((key, entry));
+        Ok(())
+    }
+
+    /// Removes the value for `key` from disk, returning its bytes.
+    fn take<Q>(&mut self, key: &Q) -> io::Result<Option<Vec<u8>>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        match self.entries.iter().position(|(k, _)| k.borrow() == key) {
+            Some(i) => {
+                let (_, entry) = self.entries.remove(i).unwrap();
+                let bytes = fs::read(self.path(entry.id));
+                self.delete(entry)?;
+                bytes.map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<K> DiskTier<K> {
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.2q", id))
+    }
+
+    fn delete(&mut self, entry: DiskEntry) -> io::Result<()> {
+        self.bytes -= entry.len;
+        fs::remove_file(self.path(entry.id))
+    }
+}
+
+impl<K> Drop for DiskTier<K> {
+    fn drop(&mut self) {
+        while let Some((_, entry)) = self.entries.pop_front() {
+            let _ = self.delete(entry);
+        }
+    }
+}
+
+/// A 2Q [`Cache`] in memory, backed by a larger [`DiskTier`].
+///
+/// Entries evicted from the frequent list in memory (and optionally from the recent list too) are
+/// serialized with a [`Codec`] and spilled to disk. When a key isn't found in memory, the disk is
+/// checked before declaring a miss, and an entry found on disk is moved back into memory.
+///
+/// A key is only ever stored in one of the tiers.
+///
+/// # Examples
+///
+/// ```
+/// use std::io;
+/// use cache_2q::{Codec, DiskTier, TieredCache};
+///
+/// #[derive(Debug)]
+/// struct Utf8;
+///
+/// impl Codec<String> for Utf8 {
+///     fn encode(&self, value: &String) -> io::Result<Vec<u8>> {
+///         Ok(value.clone().into_bytes())
+///     }
+///     fn decode(&self, bytes: Vec<u8>) -> io::Result<String> {
+///         String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+///     }
+/// }
+///
+/// let dir = std::env::temp_dir().join(format!("cache_2q_doc_{}", std::process::id()));
+/// let disk = DiskTier::new(&dir, 1024, 1 << 20)?;
+/// let mut cache = TieredCache::new(4, disk, Utf8);
+/// cache.set_spill_recent(true);
+///
+/// cache.insert(1, "one".to_string())?;
+/// // recent only holds one entry in a cache of size 4, so 1 is spilled to disk
+/// cache.insert(2, "two".to_string())?;
+/// assert_eq!(cache.memory().peek(&1), None);
+/// assert!(cache.disk().contains_key(&1));
+///
+/// assert_eq!(cache.get(&1)?, Some(&"one".to_string()));
+/// assert!(!cache.disk().contains_key(&1));
+/// # drop(cache);
+/// # std::fs::remove_dir(&dir)?;
+/// # Ok::<(), io::Error>(())
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+/// [`DiskTier`]: struct.DiskTier.html
+/// [`Codec`]: trait.Codec.html
+#[derive(Debug)]
+pub struct TieredCache<K, V, C> {
+    memory: Cache<K, V>,
+    disk: DiskTier<K>,
+    codec: C,
+    spill_recent: bool,
+}
+
+impl<K: Eq + Clone, V, C: Codec<V>> TieredCache<K, V, C> {
+    /// Creates an empty cache which holds up to `size` entries in memory, and spills entries
+    /// evicted from memory to `disk`.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize, disk: DiskTier<K>, codec: C) -> Self {
+        TieredCache {
+            memory: Cache::new(size),
+            disk,
+            codec,
+            spill_recent: false,
+        }
+    }
+
+    /// Sets whether entries evicted from the recent list in memory are spilled to disk, as well
+    /// as entries evicted from the frequent list. Defaults to false.
+    pub fn set_spill_recent(&mut self, spill_recent: bool) {
+        self.spill_recent = spill_recent;
+    }
+
+    /// Returns a reference to the in-memory tier.
+    pub fn memory(&self) -> &Cache<K, V> {
+        &self.memory
+    }
+
+    /// Returns a reference to the on-disk tier.
+    pub fn disk(&self) -> &DiskTier<K> {
+        &self.disk
+    }
+
+    /// Returns true if either tier contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.memory.contains_key(key) || self.disk.contains_key(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key, moving it from disk into
+    /// memory if it is only found on disk.
+    ///
+    /// # Errors
+    /// Returns an error if reading or decoding the value from disk fails, or if spilling the
+    /// entries evicted from memory to make room for it fails.
+    pub fn get(&mut self, key: &K) -> io::Result<Option<&V>> {
+        self.get_mut(key).map(|value| value.map(|value| &*value))
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key, moving it from disk
+    /// into memory if it is only found on disk.
+    ///
+    /// # Errors
+    /// Returns an error if reading or decoding the value from disk fails, or if spilling the
+    /// entries evicted from memory to make room for it fails.
+    pub fn get_mut(&mut self, key: &K) -> io::Result<Option<&mut V>> {
+        match self.memory.entry(key.clone()) {
+            Entry::Occupied(entry) => Ok(Some(entry.into_mut())),
+            Entry::Vacant(entry) => {
+                entry.trace_load();
+                match self.disk.take(key)? {
+                    Some(bytes) => {
+                        let value = self.codec.decode(bytes)?;
+                        let spill_recent = self.spill_recent;
+                        insert_spilling(entry, value, spill_recent, &mut self.disk, &self.codec)
+                            .map(Some)
+                    }
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Inserts a key-value pair into memory.
+    ///
+    /// If either tier had this key present, the old value is removed and returned.
+    ///
+    /// # Errors
+    /// Returns an error if reading or decoding the old value from disk fails, or if spilling the
+    /// entries evicted from memory to make room for the new value fails. In the latter case, the
+    /// new value has been inserted, but the evicted entries which could not be spilled are lost.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<V>> {
+        let old = match self.disk.take(&key)? {
+            Some(bytes) => Some(self.codec.decode(bytes)?),
+            None => None,
+        };
+        match self.memory.touch_entry(key) {
+            Entry::Occupied(mut entry) => Ok(Some(entry.insert(value))),
+            Entry::Vacant(entry) => {
+                let spill_recent = self.spill_recent;
+                insert_spilling(entry, value, spill_recent, &mut self.disk, &self.codec)?;
+                Ok(old)
+            }
+        }
+    }
+
+    /// Removes a key from both tiers, returning the value associated with the key if the key
+    /// was previously in either tier.
+    ///
+    /// # Errors
+    /// Returns an error if reading or decoding the value from disk fails.
+    pub fn remove(&mut self, key: &K) -> io::Result<Option<V>> {
+        if let Some(value) = self.memory.remove(key) {
+            return Ok(Some(value));
+        }
+        match self.disk.take(key)? {
+            Some(bytes) => self.codec.decode(bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Inserts `value` into a vacant entry in memory, spilling the entries evicted to make room to
+/// `disk`.
+fn insert_spilling<'a, K, V, C>(
+    entry: VacantEntry<'a, K, V>,
+    value: V,
+    spill_recent: bool,
+    disk: &mut DiskTier<K>,
+    codec: &C,
+) -> io::Result<&'a mut V>
+where
+    K: Eq + Clone,
+    C: Codec<V>,
+{
+    let mut spilled = Vec::new();
+    let entry = entry.insert_entry_with(value, |segment, key, value| {
+        if spill_recent || segment == Segment::Frequent {
+            spilled.push((key.clone(), value));
+        }
+    });
+    for (key, value) in spilled {
+        disk.insert(key, &codec.encode(&value)?)?;
+    }
+    Ok(entry.into_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{Codec, DiskTier, TieredCache};
+
+    #[derive(Debug)]
+    struct Le;
+
+    impl Codec<u32> for Le {
+        fn encode(&self, value: &u32) -> io::Result<Vec<u8>> {
+            Ok(value.to_le_bytes().to_vec())
+        }
+        fn decode(&self, bytes: Vec<u8>) -> io::Result<u32> {
+            let mut buf = [0; 4];
+            buf.copy_from_slice(&bytes);
+            Ok(u32::from_le_bytes(buf))
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        env::temp_dir().join(format!("cache_2q_tiered_{}_{}", process::id(), n))
+    }
+
+    /// Moves `key` into the frequent list of `cache`, by inserting it again once it's evicted
+    /// from recent
+    fn promote(cache: &mut TieredCache<u32, u32, Le>, key: u32) {
+        cache.insert(key, key).unwrap();
+        cache.insert(key + 1000, 0).unwrap();
+        cache.remove(&(key + 1000)).unwrap();
+        cache.insert(key, key).unwrap();
+    }
+
+    #[test]
+    fn frequent_evictions_spill() {
+        let dir = temp_dir();
+        let disk = DiskTier::new(&dir, 16, 1024).unwrap();
+        let mut cache = TieredCache::new(4, disk, Le);
+        for key in 0..5 {
+            promote(&mut cache, key);
+        }
+        // frequent holds 3 entries, so 0 and 1 have been spilled
+        assert!(cache.disk().contains_key(&0));
+        assert!(cache.disk().contains_key(&1));
+        assert_eq!(cache.disk().bytes(), 8);
+        // entries evicted from recent are not spilled
+        assert_eq!(cache.disk().len(), 2);
+
+        assert_eq!(cache.get(&0).unwrap(), Some(&0));
+        assert!(cache.memory().contains_key(&0));
+        assert!(!cache.disk().contains_key(&0));
+        assert!(cache.disk().contains_key(&1));
+
+        drop(cache);
+        assert_eq!(dir.read_dir().unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn disk_is_bounded() {
+        let dir = temp_dir();
+        let disk = DiskTier::new(&dir, 16, 12).unwrap();
+        let mut cache = TieredCache::new(4, disk, Le);
+        cache.set_spill_recent(true);
+        for key in 0..10 {
+            cache.insert(key, key).unwrap();
+        }
+        assert_eq!(cache.disk().len(), 3);
+        assert_eq!(cache.disk().bytes(), 12);
+        assert_eq!(dir.read_dir().unwrap().count(), 3);
+        assert_eq!(cache.get(&8).unwrap(), Some(&8));
+        assert_eq!(cache.get(&0).unwrap(), None);
+
+        assert_eq!(cache.insert(7, 70).unwrap(), Some(7));
+        assert_eq!(cache.remove(&6).unwrap(), Some(6));
+        assert!(!cache.contains_key(&6));
+
+        drop(cache);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,583 @@
+//! A TinyLFU admission filter in front of a 2Q cache
+//!
+//! Based on the paper entitled
+//! **[TinyLFU: A Highly Efficient Cache Admission Policy](https://arxiv.org/abs/1512.00727)**.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::fmt;
+use core::hash::{BuildHasher, Hash};
+#[cfg(feature = "std")]
+use std::collections::hash_map::RandomState;
+
+use {Cache, Entry, Iter, OccupiedKind, VacantEntry, VacantKind};
+
+/// The largest value a single counter in a [`CountMinSketch`] can reach.
+///
+/// [`CountMinSketch`]: struct.CountMinSketch.html
+const MAX_COUNT: u8 = 15;
+
+/// A count-min sketch of small saturating counters, which estimates how often keys have been seen.
+///
+/// Every `sample_size` increments, all counters are halved, so that the sketch reflects recent
+/// popularity rather than all-time popularity.
+///
+/// Keys are hashed with [`RandomState`] by default, which requires the `std` feature. Without
+/// it, a hash builder has to be given to [`with_hasher`].
+///
+/// # Examples
+///
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use cache_2q::CountMinSketch;
+///
+/// let mut sketch = CountMinSketch::new(64, 4, 640);
+/// sketch.increment(&"a");
+/// sketch.increment(&"a");
+/// sketch.increment(&"b");
+/// assert!(sketch.estimate(&"a") >= 2);
+/// assert!(sketch.estimate(&"b") >= 1);
+/// # }
+/// ```
+///
+/// [`RandomState`]: https://doc.rust-lang.org/std/collections/hash_map/struct.RandomState.html
+/// [`with_hasher`]: struct.CountMinSketch.html#method.with_hasher
+#[derive(Clone)]
+pub struct CountMinSketch<
+    #[cfg(feature = "std")] S = RandomState,
+    #[cfg(not(feature = "std"))] S,
+> {
+    counters: Vec<u8>,
+    mask: usize,
+    depth: usize,
+    additions: usize,
+    sample_size: usize,
+    hash_builder: S,
+}
+
+#[cfg(feature = "std")]
+impl CountMinSketch<RandomState> {
+    /// Creates a sketch with `width` counters per row and `depth` rows, which ages its counters
+    /// after every `sample_size` increments.
+    ///
+    /// # Notes
+    /// `width` is rounded up to the next power of two
+    ///
+    /// # Panics
+    /// panics if `width`, `depth` or `sample_size` is zero
+    pub fn new(width: usize, depth: usize, sample_size: usize) -> Self {
+        CountMinSketch::with_hasher(width, depth, sample_size, RandomState::new())
+    }
+}
+
+impl<S: BuildHasher> CountMinSketch<S> {
+    /// Creates a sketch like [`new`], which will use the given hash builder to hash keys.
+    ///
+    /// Passing a deterministic hash builder makes the sketch, and so admission decisions,
+    /// reproducible.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    /// use cache_2q::CountMinSketch;
+    ///
+    /// let hasher = BuildHasherDefault::<DefaultHasher>::default();
+    /// let mut sketch = CountMinSketch::with_hasher(16, 4, 160, hasher);
+    /// sketch.increment(&1);
+    /// assert_eq!(sketch.estimate(&1), 1);
+    /// ```
+    ///
+    /// # Panics
+    /// panics if `width`, `depth` or `sample_size` is zero
+    ///
+    /// [`new`]: struct.CountMinSketch.html#method.new
+    pub fn with_hasher(width: usize, depth: usize, sample_size: usize, hash_builder: S) -> Self {
+        assert!(width > 0);
+        assert!(depth > 0);
+        assert!(sample_size > 0);
+        let width = width.next_power_of_two();
+        CountMinSketch {
+            counters: vec![0; width * depth],
+            mask: width - 1,
+            depth,
+            additions: 0,
+            sample_size,
+            hash_builder,
+        }
+    }
+
+    /// Returns the number of counters in each row of the sketch.
+    pub fn width(&self) -> usize {
+        self.mask + 1
+    }
+
+    /// Returns the number of rows in the sketch.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Records an occurrence of `key`, aging the sketch if `sample_size` increments have been
+    /// recorded since it was last aged.
+    pub fn increment<Q: ?Sized + Hash>(&mut self, key: &Q) {
+        let hash = self.hash(key);
+        let mut added = false;
+        for row in 0..self.depth {
+            let idx = self.index(hash, row);
+            if self.counters[idx] < MAX_COUNT {
+                self.counters[idx] += 1;
+                added = true;
+            }
+        }
+        if added {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                self.age();
+            }
+        }
+    }
+
+    /// Returns the estimated number of recent occurrences of `key`.
+    ///
+    /// The estimate may be larger than the true count, but is never smaller (unless the sketch
+    /// has been aged since), and saturates at 15.
+    pub fn estimate<Q: ?Sized + Hash>(&self, key: &Q) -> u8 {
+        let hash = self.hash(key);
+        (0..self.depth)
+            .map(|row| self.counters[self.index(hash, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Halves every counter in the sketch.
+    pub fn age(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        self.additions /= 2;
+    }
+
+    /// Resets every counter in the sketch to zero.
+    pub fn clear(&mut self) {
+        for counter in &mut self.counters {
+            *counter = 0;
+        }
+        self.additions = 0;
+    }
+
+    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        // Double hashing: derive each row's index from two halves of a single hash
+        let lo = hash as u32 as usize;
+        let hi = (hash >> 32) as usize | 1;
+        let col = lo.wrapping_add(row.wrapping_mul(hi)) & self.mask;
+        row * self.width() + col
+    }
+}
+
+impl<S> fmt::Debug for CountMinSketch<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CountMinSketch")
+            .field("width", &(self.mask + 1))
+            .field("depth", &self.depth)
+            .field("additions", &self.additions)
+            .field("sample_size", &self.sample_size)
+            .finish()
+    }
+}
+
+/// A 2Q [`Cache`] guarded by a TinyLFU admission policy.
+///
+/// Every access to a key is recorded in a [`CountMinSketch`]. New keys are inserted into recent,
+/// which acts as the admission window of W-TinyLFU. When inserting a key would evict an entry
+/// from frequent, because the cache is full or because the key is remembered in the ghost list,
+/// the key is only admitted if it has been seen more often than the entry it would evict. A new
+/// key which isn't makes room by evicting the oldest entry of recent instead, or isn't inserted
+/// if every entry of recent is pinned, and a key remembered in ghost which isn't is inserted into
+/// recent as if it were new. This keeps keys which are only seen a few times from churning
+/// frequent.
+///
+/// [`new`] requires the `std` feature, without which the sketch is given to [`with_sketch`].
+///
+/// # Examples
+///
+/// ```
+/// # #[cfg(feature = "std")] {
+/// use cache_2q::TinyLfuCache;
+///
+/// let mut cache = TinyLfuCache::new(4);
+/// cache.insert("a", 1);
+/// // recent only holds a single entry in a cache of size 4, so "a" is evicted to make room for
+/// // "b", and remembered in ghost
+/// cache.insert("b", 2);
+/// assert_eq!(cache.get(&"a"), None);
+/// assert_eq!(cache.get(&"b"), Some(&2));
+/// // "a" is admitted into frequent, which has room for it
+/// cache.insert("a", 1);
+/// assert_eq!(cache.get(&"a"), Some(&1));
+/// # }
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+/// [`CountMinSketch`]: struct.CountMinSketch.html
+/// [`new`]: struct.TinyLfuCache.html#method.new
+/// [`with_sketch`]: struct.TinyLfuCache.html#method.with_sketch
+#[derive(Debug, Clone)]
+pub struct TinyLfuCache<
+    K,
+    V,
+    #[cfg(feature = "std")] S = RandomState,
+    #[cfg(not(feature = "std"))] S,
+> {
+    cache: Cache<K, V>,
+    sketch: CountMinSketch<S>,
+}
+
+#[cfg(feature = "std")]
+impl<K: Eq + Hash, V> TinyLfuCache<K, V, RandomState> {
+    /// Creates an empty cache with the specified size, and a sketch sized to match.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize) -> Self {
+        TinyLfuCache::with_sketch(size, CountMinSketch::new(size, 4, size * 10))
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> TinyLfuCache<K, V, S> {
+    /// Creates an empty cache with the specified size, which will make admission decisions
+    /// using `sketch`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    /// use cache_2q::{CountMinSketch, TinyLfuCache};
+    ///
+    /// let hasher = BuildHasherDefault::<DefaultHasher>::default();
+    /// let sketch = CountMinSketch::with_hasher(256, 4, 1280, hasher);
+    /// let mut cache = TinyLfuCache::with_sketch(128, sketch);
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.get(&1), Some(&"a"));
+    /// ```
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn with_sketch(size: usize, sketch: CountMinSketch<S>) -> Self {
+        TinyLfuCache {
+            cache: Cache::new(size),
+            sketch,
+        }
+    }
+
+    /// Returns a reference to the underlying cache.
+    pub fn cache(&self) -> &Cache<K, V> {
+        &self.cache
+    }
+
+    /// Returns a reference to the sketch used for admission decisions.
+    pub fn sketch(&self) -> &CountMinSketch<S> {
+        &self.sketch
+    }
+
+    /// Returns true if the cache contains a value for the specified key.
+    ///
+    /// This is not counted as an access to `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.contains_key(key)
+    }
+
+    /// Returns a reference to the value corresponding to the key, without recording an access.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.peek(key)
+    }
+
+    /// Records an access to `key`, and returns a reference to the corresponding value.
+    ///
+    /// The access is recorded even if `key` is not in the cache, which makes it more likely to be
+    /// admitted when it is next inserted.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.sketch.increment(key);
+        self.cache.get(key)
+    }
+
+    /// Records an access to `key`, and returns a mutable reference to the corresponding value.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.sketch.increment(key);
+        self.cache.get_mut(key)
+    }
+
+    /// Records an access to `key`, and inserts a key-value pair into the cache if it is admitted.
+    ///
+    /// If the cache already had this key present, the value is always updated, and the old value
+    /// is returned. Otherwise the key is inserted, into frequent if it is remembered in ghost and
+    /// has been accessed more often than the entry of frequent it would evict, or into recent
+    /// otherwise. A key inserted into recent only evicts an entry of frequent if it has been
+    /// accessed more often than that entry, and evicts the oldest entry of recent otherwise, or
+    /// isn't inserted at all if every entry of recent is pinned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[cfg(feature = "std")] {
+    /// use cache_2q::TinyLfuCache;
+    ///
+    /// let mut cache = TinyLfuCache::new(4);
+    /// cache.insert(1, "a");
+    /// assert_eq!(cache.insert(1, "b"), Some("a"));
+    /// assert_eq!(cache.get(&1), Some(&"b"));
+    /// # }
+    /// ```
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.sketch.increment(&key);
+        let sketch = &self.sketch;
+        match self.cache.touch_entry(key) {
+            Entry::Occupied(mut entry) => Some(entry.insert(value)),
+            Entry::Vacant(mut entry) => {
+                if let VacantKind::Ghost(i) = entry.kind {
+                    if !admitted(sketch, &entry) {
+                        entry.cache.ghost.remove(i);
+                        entry.kind = VacantKind::Unknown;
+                    }
+                }
+                if entry.kind == VacantKind::Unknown && !admitted(sketch, &entry) {
+                    match entry.cache.oldest_recent() {
+                        Some(victim) => entry.cache.evict(victim, &mut |_, _, _| {}),
+                        // every entry of recent is pinned, so the key could only be inserted by
+                        // evicting the entry of frequent it wasn't admitted over
+                        None => return None,
+                    }
+                }
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Removes a key from the cache, returning the value associated with the key if the key
+    /// was previously in the cache.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.remove(key)
+    }
+
+    /// Returns the number of entries currenly in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Clears the cache and the access history in the sketch.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.sketch.clear();
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.cache.iter()
+    }
+}
+
+/// Returns whether the key of `entry` may evict the entry of frequent which inserting it would
+/// evict, if any, because it has been seen more often.
+fn admitted<K, V, S>(sketch: &CountMinSketch<S>, entry: &VacantEntry<'_, K, V>) -> bool
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    let cache = &*entry.cache;
+    match cache.victim(entry.kind.segment()) {
+        Some(OccupiedKind::Frequent(i)) if i < cache.frequent.len() - cache.stale_frequent => {
+            sketch.estimate(&entry.key) > sketch.estimate(&cache.frequent[i].key)
+        }
+        _ => true,
+    }
+}
+
+impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher> IntoIterator for &'a TinyLfuCache<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    use super::{CountMinSketch, TinyLfuCache};
+
+    type Seeded = BuildHasherDefault<DefaultHasher>;
+
+    fn seeded_cache(size: usize) -> TinyLfuCache<u32, u32, Seeded> {
+        let sketch = CountMinSketch::with_hasher(64, 4, 1000, Seeded::default());
+        TinyLfuCache::with_sketch(size, sketch)
+    }
+
+    #[test]
+    fn sketch_ages() {
+        let mut sketch = CountMinSketch::with_hasher(64, 4, 8, Seeded::default());
+        for _ in 0..7 {
+            sketch.increment(&1);
+        }
+        assert_eq!(sketch.estimate(&1), 7);
+        sketch.increment(&1);
+        assert_eq!(sketch.estimate(&1), 4);
+    }
+
+    #[test]
+    fn sketch_saturates() {
+        let mut sketch = CountMinSketch::with_hasher(64, 4, 1000, Seeded::default());
+        for _ in 0..100 {
+            sketch.increment(&1);
+        }
+        assert_eq!(sketch.estimate(&1), 15);
+    }
+
+    /// Returns a cache of size 4 whose frequent list is full of "popular" keys, 1, 2 and 3, and
+    /// whose recent list holds 4.
+    fn popular_cache() -> TinyLfuCache<u32, u32, Seeded> {
+        let mut cache = seeded_cache(4);
+        for &key in &[1, 2, 1, 3, 2, 4, 3] {
+            cache.insert(key, key);
+        }
+        for _ in 0..3 {
+            for key in 1..4 {
+                assert_eq!(cache.get(&key), Some(&key));
+            }
+        }
+        assert_eq!(cache.cache().frequent.len(), 3);
+        cache
+    }
+
+    #[test]
+    fn new_keys_enter_recent() {
+        let mut cache = popular_cache();
+        for i in 100..200 {
+            cache.insert(i, i);
+            assert_eq!(cache.peek(&i), Some(&i));
+        }
+        for key in 1..4 {
+            assert_eq!(cache.peek(&key), Some(&key));
+        }
+    }
+
+    #[test]
+    fn ghost_hits_compete_for_frequent() {
+        let mut cache = popular_cache();
+        let ghost_hits = cache.cache().stats().ghost_hits;
+        // 100 is remembered in ghost, but seen less often than the entries of frequent, so it is
+        // inserted into recent again
+        cache.insert(100, 100);
+        cache.insert(101, 101);
+        cache.insert(100, 100);
+        assert_eq!(cache.cache().recent[0].key, 100);
+        assert_eq!(cache.cache().stats().ghost_hits, ghost_hits);
+        for key in 1..4 {
+            assert_eq!(cache.peek(&key), Some(&key));
+        }
+        // 102 has been seen more often, so it evicts the oldest entry of frequent
+        for _ in 0..10 {
+            cache.get(&102);
+        }
+        cache.insert(102, 102);
+        cache.insert(103, 103);
+        cache.insert(102, 102);
+        assert_eq!(cache.cache().frequent[0].key, 102);
+        assert_eq!(cache.cache().stats().ghost_hits, ghost_hits + 1);
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn full_cache_evicts_recent_for_unpopular_keys() {
+        // frequent holds its 6 entries, and recent 2, one of them pinned, so the cache is full
+        // but recent has room for an unpinned entry
+        let mut cache = seeded_cache(8);
+        assert_eq!(cache.cache.warm((1..7).map(|key| (key, key))), 6);
+        cache.insert(10, 10);
+        cache.insert(11, 11);
+        cache.cache.pin(&10).unwrap();
+        for _ in 0..3 {
+            for key in 1..7 {
+                assert_eq!(cache.get(&key), Some(&key));
+            }
+        }
+        // a new key seen less often than the oldest entry of frequent evicts from recent
+        cache.insert(60, 60);
+        assert_eq!(cache.peek(&60), Some(&60));
+        assert_eq!(cache.peek(&11), None);
+        assert!((1..7).all(|key| cache.contains_key(&key)));
+        // a new key seen more often evicts the oldest entry of frequent
+        cache.cache.unpin(&10);
+        cache.cache.pin(&60).unwrap();
+        for _ in 0..10 {
+            cache.get(&70);
+        }
+        let oldest = cache.cache.frequent.back().unwrap().key;
+        cache.insert(70, 70);
+        assert_eq!(cache.peek(&70), Some(&70));
+        assert_eq!(cache.peek(&oldest), None);
+        assert_eq!(cache.len(), 8);
+    }
+
+    #[test]
+    fn pinned_recent_still_guards_frequent() {
+        // frequent holds its 6 entries, and recent its 2, both pinned
+        let mut cache = seeded_cache(8);
+        assert_eq!(cache.cache.warm((1..7).map(|key| (key, key))), 6);
+        for key in 10..12 {
+            cache.insert(key, key);
+            cache.cache.pin(&key).unwrap();
+        }
+        for _ in 0..3 {
+            for key in 1..7 {
+                assert_eq!(cache.get(&key), Some(&key));
+            }
+        }
+        // a new key seen less often than the oldest entry of frequent isn't inserted
+        cache.insert(60, 60);
+        assert_eq!(cache.peek(&60), None);
+        assert!((1..7).chain(10..12).all(|key| cache.contains_key(&key)));
+        // a new key seen more often evicts the oldest entry of frequent
+        for _ in 0..10 {
+            cache.get(&70);
+        }
+        let oldest = cache.cache.frequent.back().unwrap().key;
+        cache.insert(70, 70);
+        assert_eq!(cache.peek(&70), Some(&70));
+        assert_eq!(cache.peek(&oldest), None);
+        assert_eq!(cache.len(), 8);
+    }
+}
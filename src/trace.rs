@@ -0,0 +1,220 @@
+//! Events emitted through `tracing`, behind the `tracing` feature
+
+use core::fmt;
+
+use tracing::field;
+use tracing::Level;
+
+/// Formats a key for events, if the key type implements `Debug`.
+type FormatKey<K> = fn(&K, &mut fmt::Formatter<'_>) -> fmt::Result;
+
+/// Something which happened in a cache, worth an event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// A lookup found no entry
+    Miss,
+    /// A key remembered in ghost was inserted into frequent
+    GhostHit,
+    /// An entry found in frequent was moved to its front
+    Promotion,
+    /// An entry was evicted from recent, and its key remembered in ghost
+    RecentEviction,
+    /// A key was forgotten to make room in ghost
+    GhostDrop,
+    /// A value missing from the cache is being loaded, by a wrapper such as `Memoize`
+    Load,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Miss => "miss",
+            Event::GhostHit => "ghost_hit",
+            Event::Promotion => "promotion",
+            Event::RecentEviction => "recent_eviction",
+            Event::GhostDrop => "ghost_drop",
+            Event::Load => "load",
+        }
+    }
+}
+
+/// How a cache emits events: at which level, if any, and how to format keys.
+pub(crate) struct Tracer<K> {
+    level: Option<Level>,
+    format_key: Option<FormatKey<K>>,
+}
+
+impl<K> Tracer<K> {
+    pub(crate) fn new() -> Self {
+        Tracer {
+            level: Some(Level::TRACE),
+            format_key: None,
+        }
+    }
+
+    pub(crate) fn set_level(&mut self, level: Option<Level>) {
+        self.level = level;
+    }
+
+    pub(crate) fn trace_keys(&mut self)
+    where
+        K: fmt::Debug,
+    {
+        self.format_key = Some(<K as fmt::Debug>::fmt);
+    }
+
+    /// Emits an event, with its key if there is one and keys are traced.
+    pub(crate) fn emit(&self, event: Event, key: Option<&K>) {
+        let key = match (key, self.format_key) {
+            (Some(key), Some(format_key)) => Some(field::debug(DebugKey { key, format_key })),
+            _ => None,
+        };
+        let name = event.name();
+        // the level of an event must be a constant
+        match self.level {
+            Some(Level::ERROR) => tracing::event!(Level::ERROR, event = name, key),
+            Some(Level::WARN) => tracing::event!(Level::WARN, event = name, key),
+            Some(Level::INFO) => tracing::event!(Level::INFO, event = name, key),
+            Some(Level::DEBUG) => tracing::event!(Level::DEBUG, event = name, key),
+            Some(_) => tracing::event!(Level::TRACE, event = name, key),
+            None => {}
+        }
+    }
+}
+
+impl<K> Clone for Tracer<K> {
+    fn clone(&self) -> Self {
+        Tracer {
+            level: self.level,
+            format_key: self.format_key,
+        }
+    }
+}
+
+impl<K> fmt::Debug for Tracer<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("level", &self.level)
+            .field("trace_keys", &self.format_key.is_some())
+            .finish()
+    }
+}
+
+/// A key, formatted by the tracer of its cache.
+struct DebugKey<'a, K> {
+    key: &'a K,
+    format_key: FormatKey<K>,
+}
+
+impl<'a, K> fmt::Debug for DebugKey<'a, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.format_key)(self.key, f)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Level, Metadata, Subscriber};
+
+    use {Cache, Memoize};
+
+    /// A subscriber which records every event as its level and fields.
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        /// Runs `f` with this subscriber, and returns the events it recorded.
+        fn record<F: FnOnce()>(f: F) -> Vec<String> {
+            let recorder = Recorder::default();
+            tracing::subscriber::with_default(recorder.clone(), f);
+            let events = recorder.0.lock().unwrap();
+            events.clone()
+        }
+    }
+
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push_str(&format!(" {}={}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(event.metadata().level().to_string());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+        fn enter(&self, _span: &Id) {}
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn events() {
+        let events = Recorder::record(|| {
+            let mut cache = Cache::new(4);
+            cache.trace_keys();
+            cache.insert(1, "a");
+            // recent only holds one entry, and ghost two keys
+            cache.insert(2, "b");
+            cache.insert(3, "c");
+            cache.insert(4, "d");
+            cache.entry(2).or_insert("b");
+            assert_eq!(cache.get(&2), Some(&"b"));
+            assert_eq!(cache.get(&5), None);
+        });
+        assert_eq!(
+            events,
+            [
+                "TRACE event=recent_eviction key=1",
+                "TRACE event=recent_eviction key=2",
+                "TRACE event=recent_eviction key=3",
+                "TRACE event=ghost_drop key=1",
+                "TRACE event=miss key=2",
+                "TRACE event=ghost_hit key=2",
+                "TRACE event=promotion key=2",
+                "TRACE event=miss",
+            ]
+        );
+    }
+
+    #[test]
+    fn verbosity() {
+        let events = Recorder::record(|| {
+            let mut cache = Cache::new(4);
+            cache.set_trace_level(Some(Level::DEBUG));
+            cache.insert(1, 1);
+            cache.insert(2, 2);
+            cache.set_trace_level(None);
+            cache.insert(3, 3);
+        });
+        assert_eq!(events, ["DEBUG event=recent_eviction"]);
+    }
+
+    #[test]
+    fn loader_calls() {
+        let events = Recorder::record(|| {
+            let mut square = Memoize::new(4, |&x: &u32| x * x);
+            square.call(3);
+            square.call(3);
+        });
+        assert_eq!(events, ["TRACE event=miss", "TRACE event=load"]);
+    }
+}
@@ -0,0 +1,407 @@
+//! A write-back cache in front of a slower backing store
+
+use core::borrow::Borrow;
+use core::cmp;
+
+use {Cache, Entry, OccupiedKind, VacantEntry, VacantKind};
+
+/// A slow store of key-value pairs, which a [`WriteBackCache`] loads values from and writes
+/// values back to.
+///
+/// [`WriteBackCache`]: struct.WriteBackCache.html
+pub trait BackingStore<K, V> {
+    /// The type of errors returned by the store
+    type Error;
+
+    /// Loads the value for `key`, returning `None` if the store has no value for it.
+    fn load(&mut self, key: &K) -> Result<Option<V>, Self::Error>;
+
+    /// Stores `value` for `key`, replacing any value the store already had for it.
+    fn store(&mut self, key: &K, value: &V) -> Result<(), Self::Error>;
+
+    /// Deletes the value for `key` from the store.
+    fn delete(&mut self, key: &K) -> Result<(), Self::Error>;
+}
+
+/// The type of values in a write-back cache: a value, and whether it still has to be written
+/// back to the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Slot<V> {
+    value: V,
+    dirty: bool,
+}
+
+/// A 2Q [`Cache`] in front of a [`BackingStore`], which defers writing values to the store
+/// until they are evicted.
+///
+/// Values inserted into the cache are marked dirty. When a dirty value is about to be evicted,
+/// it is written to the store first. If that fails, the error is returned, the new value isn't
+/// inserted, and the value which couldn't be written back stays in the cache, still dirty, but
+/// values evicted before it are gone, having been written back or clean. [`flush`] writes every
+/// dirty value to the store, which also happens when the cache is dropped, though errors can
+/// only be observed by calling [`flush`] explicitly.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use cache_2q::{BackingStore, WriteBackCache};
+///
+/// #[derive(Debug, Default)]
+/// struct Store(HashMap<u32, String>);
+///
+/// impl BackingStore<u32, String> for Store {
+///     type Error = ();
+///
+///     fn load(&mut self, key: &u32) -> Result<Option<String>, ()> {
+///         Ok(self.0.get(key).cloned())
+///     }
+///     fn store(&mut self, key: &u32, value: &String) -> Result<(), ()> {
+///         self.0.insert(*key, value.clone());
+///         Ok(())
+///     }
+///     fn delete(&mut self, key: &u32) -> Result<(), ()> {
+///         self.0.remove(key);
+///         Ok(())
+///     }
+/// }
+///
+/// let mut cache = WriteBackCache::new(8, Store::default());
+/// cache.insert(1, "a".to_string()).unwrap();
+/// assert!(cache.store().0.is_empty());
+///
+/// cache.flush().unwrap();
+/// assert_eq!(cache.store().0[&1], "a");
+/// ```
+///
+/// [`Cache`]: struct.Cache.html
+/// [`BackingStore`]: trait.BackingStore.html
+/// [`flush`]: struct.WriteBackCache.html#method.flush
+#[derive(Debug)]
+pub struct WriteBackCache<K, V, S: BackingStore<K, V>> {
+    cache: Cache<K, Slot<V>>,
+    store: S,
+}
+
+impl<K: Eq + Clone, V, S: BackingStore<K, V>> WriteBackCache<K, V, S> {
+    /// Creates an empty cache with the specified size, in front of `store`.
+    ///
+    /// # Panics
+    /// panics if `size` is zero
+    pub fn new(size: usize, store: S) -> Self {
+        WriteBackCache {
+            cache: Cache::new(size),
+            store,
+        }
+    }
+
+    /// Returns a reference to the backing store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns a mutable reference to the backing store.
+    ///
+    /// Values written to the store directly may be shadowed by values in the cache.
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Returns a reference to the underlying cache's values, without loading from the store.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.peek(key).map(|slot| &slot.value)
+    }
+
+    /// Returns a reference to the value corresponding to the key, loading it from the store if
+    /// it isn't in the cache.
+    ///
+    /// # Errors
+    /// Returns an error if loading the value fails, or if writing back a dirty value to make room
+    /// for the loaded value fails.
+    pub fn get(&mut self, key: &K) -> Result<Option<&V>, S::Error> {
+        Ok(self.load(key)?.map(|slot| &slot.value))
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key, loading it from the
+    /// store if it isn't in the cache. The value is marked dirty.
+    ///
+    /// # Errors
+    /// Returns an error if loading the value fails, or if writing back a dirty value to make room
+    /// for the loaded value fails.
+    pub fn get_mut(&mut self, key: &K) -> Result<Option<&mut V>, S::Error> {
+        Ok(self.load(key)?.map(|slot| {
+            slot.dirty = true;
+            &mut slot.value
+        }))
+    }
+
+    /// Inserts a key-value pair into the cache, marking it dirty.
+    ///
+    /// If the cache already had this key present, the value is updated, and the old value is
+    /// returned. The store is not consulted for an old value.
+    ///
+    /// # Errors
+    /// Returns an error without inserting the new value if writing back a dirty value to make
+    /// room for it fails. That value stays in the cache, still dirty, but values evicted before
+    /// it were written back or clean, and are gone.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, S::Error> {
+        let slot = Slot { value, dirty: true };
+        match self.cache.touch_entry(key) {
+            Entry::Occupied(mut entry) => Ok(Some(entry.insert(slot).value)),
+            Entry::Vacant(entry) => {
+                insert_vacant(entry, slot, &mut self.store)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes a key from both the cache and the store, returning the cached value if the key
+    /// was in the cache.
+    ///
+    /// # Errors
+    /// Returns an error, leaving the cache unchanged, if deleting the key from the store fails.
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, S::Error> {
+        self.store.delete(key)?;
+        Ok(self.cache.remove(key).map(|slot| slot.value))
+    }
+
+    /// Returns true if the cache contains a value for the key which hasn't been written back
+    /// to the store.
+    pub fn is_dirty<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.cache.peek(key).is_some_and(|slot| slot.dirty)
+    }
+
+    /// Returns the number of entries currenly in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns true if the cache contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    fn load(&mut self, key: &K) -> Result<Option<&mut Slot<V>>, S::Error> {
+        match self.cache.entry(key.clone()) {
+            Entry::Occupied(entry) => Ok(Some(entry.into_mut())),
+            Entry::Vacant(entry) => {
+                entry.trace_load();
+                match self.store.load(key)? {
+                    Some(value) => {
+                        let slot = Slot { value, dirty: false };
+                        Ok(Some(insert_vacant(entry, slot, &mut self.store)?))
+                    }
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, S: BackingStore<K, V>> WriteBackCache<K, V, S> {
+    /// Writes every dirty value in the cache to the store.
+    ///
+    /// # Errors
+    /// Returns the first error from the store. Values written before the error are no longer
+    /// dirty, the rest are left dirty.
+    pub fn flush(&mut self) -> Result<(), S::Error> {
+        let entries = self.cache.recent.iter_mut().chain(self.cache.frequent.iter_mut());
+        for entry in entries.filter(|entry| entry.value.dirty) {
+            self.store.store(&entry.key, &entry.value.value)?;
+            entry.value.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, S: BackingStore<K, V>> Drop for WriteBackCache<K, V, S> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Inserts `slot` into a vacant entry, writing back every dirty value it would evict first.
+///
+/// If the store fails, the entry isn't inserted, and the value which couldn't be written back is
+/// left in the cache, still dirty. Values evicted before it were written back or clean.
+fn insert_vacant<'a, K: Eq, V, S: BackingStore<K, V>>(
+    entry: VacantEntry<'a, K, Slot<V>>,
+    slot: Slot<V>,
+    store: &mut S,
+) -> Result<&'a mut Slot<V>, S::Error> {
+    let VacantEntry { cache, key, kind } = entry;
+    // a key found in ghost leaves it before anything is evicted, as it does on insertion, so
+    // evicting from recent can't push it out of ghost
+    let ghost = match kind {
+        VacantKind::Ghost(i) => cache.ghost.remove(i),
+        VacantKind::Unknown => None,
+    };
+    let segment = kind.segment();
+    while let Some(victim) = cache.victim(segment) {
+        let entry = match victim {
+            OccupiedKind::Recent(i) => &mut cache.recent[i],
+            OccupiedKind::Frequent(i) => &mut cache.frequent[i],
+        };
+        if entry.value.dirty {
+            if let Err(e) = store.store(&entry.key, &entry.value.value) {
+                if let (VacantKind::Ghost(i), Some(ghost)) = (kind, ghost) {
+                    let i = cmp::min(i, cache.ghost.len());
+                    cache.ghost.insert(i, ghost);
+                }
+                return Err(e);
+            }
+            entry.value.dirty = false;
+        }
+        cache.evict(victim, &mut |_, _, _| {});
+    }
+    let kind = match ghost {
+        Some(ghost) => {
+            cache.ghost.push_front(ghost);
+            VacantKind::Ghost(0)
+        }
+        None => VacantKind::Unknown,
+    };
+    Ok(VacantEntry { cache, key, kind }.insert(slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{BackingStore, WriteBackCache};
+
+    #[derive(Debug, Default)]
+    struct Store {
+        values: HashMap<u32, u32>,
+        fail: bool,
+        loads: usize,
+    }
+
+    impl BackingStore<u32, u32> for Store {
+        type Error = &'static str;
+
+        fn load(&mut self, key: &u32) -> Result<Option<u32>, Self::Error> {
+            self.loads += 1;
+            Ok(self.values.get(key).cloned())
+        }
+        fn store(&mut self, key: &u32, value: &u32) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err("store failed");
+            }
+            self.values.insert(*key, *value);
+            Ok(())
+        }
+        fn delete(&mut self, key: &u32) -> Result<(), Self::Error> {
+            self.values.remove(key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn evictions_write_back() {
+        let mut cache = WriteBackCache::new(4, Store::default());
+        cache.insert(1, 10).unwrap();
+        assert!(cache.is_dirty(&1));
+        // recent only holds one entry, so 2 evicts 1
+        cache.insert(2, 20).unwrap();
+        assert_eq!(cache.store().values.get(&1), Some(&10));
+        assert_eq!(cache.peek(&1), None);
+
+        assert_eq!(cache.get(&1), Ok(Some(&10)));
+        assert!(!cache.is_dirty(&1));
+        cache.insert(3, 30).unwrap();
+        assert_eq!(cache.store().values.get(&2), Some(&20));
+    }
+
+    #[test]
+    fn eviction_errors_are_returned() {
+        let mut cache = WriteBackCache::new(4, Store::default());
+        cache.insert(1, 10).unwrap();
+        cache.store_mut().fail = true;
+        assert_eq!(cache.insert(2, 20), Err("store failed"));
+        assert_eq!(cache.peek(&1), Some(&10));
+        assert!(cache.is_dirty(&1));
+        assert_eq!(cache.peek(&2), None);
+        assert_eq!(cache.flush(), Err("store failed"));
+
+        cache.store_mut().fail = false;
+        cache.flush().unwrap();
+        assert!(!cache.is_dirty(&1));
+        assert_eq!(cache.store().values.get(&1), Some(&10));
+    }
+
+    #[test]
+    fn every_eviction_writes_back() {
+        let mut cache = WriteBackCache::new(4, Store::default());
+        // pinned entries let recent outgrow its single entry, and once invalidated, they're all
+        // evicted by the next insertion
+        for key in 1..4 {
+            cache.insert(key, key * 10).unwrap();
+            cache.cache.pin(&key).unwrap();
+        }
+        cache.cache.invalidate_all(true);
+        cache.insert(4, 40).unwrap();
+        assert_eq!(cache.len(), 1);
+        for key in 1..4 {
+            assert_eq!(cache.store().values.get(&key), Some(&(key * 10)));
+        }
+    }
+
+    #[test]
+    fn get_loads_once() {
+        let mut store = Store::default();
+        store.values.insert(1, 10);
+        let mut cache = WriteBackCache::new(4, store);
+        assert_eq!(cache.get(&1), Ok(Some(&10)));
+        assert_eq!(cache.get(&1), Ok(Some(&10)));
+        assert_eq!(cache.get(&2), Ok(None));
+        assert_eq!(cache.store().loads, 2);
+
+        *cache.get_mut(&1).unwrap().unwrap() = 11;
+        assert!(cache.is_dirty(&1));
+        assert_eq!(cache.remove(&1), Ok(Some(11)));
+        assert_eq!(cache.store().values.get(&1), None);
+    }
+
+    #[test]
+    fn drop_flushes() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Debug)]
+        struct Shared(Rc<RefCell<HashMap<u32, u32>>>);
+
+        impl BackingStore<u32, u32> for Shared {
+            type Error = ();
+
+            fn load(&mut self, key: &u32) -> Result<Option<u32>, ()> {
+                Ok(self.0.borrow().get(key).cloned())
+            }
+            fn store(&mut self, key: &u32, value: &u32) -> Result<(), ()> {
+                self.0.borrow_mut().insert(*key, *value);
+                Ok(())
+            }
+            fn delete(&mut self, key: &u32) -> Result<(), ()> {
+                self.0.borrow_mut().remove(key);
+                Ok(())
+            }
+        }
+
+        let values = Rc::new(RefCell::new(HashMap::new()));
+        {
+            let mut cache = WriteBackCache::new(8, Shared(values.clone()));
+            cache.insert(1, 10).unwrap();
+            cache.insert(2, 20).unwrap();
+            assert!(values.borrow().is_empty());
+        }
+        assert_eq!(values.borrow().len(), 2);
+    }
+}